@@ -1,3 +1,5 @@
+#include <Wire.h>
+
 // EN are PWM output pins regulating motor power supply (via a MOSFET
 // transistors)
 #define MOTOR_L_EN 10
@@ -18,9 +20,109 @@
 #define MOTOR_R_IN1 7
 #define MOTOR_R_IN2 8
 
+// Analog inputs connected to the battery voltage divider and to the current
+// sensor between BMS and remaining electronics.
+#define BATTERY_VOLTAGE_PIN A0
+#define BATTERY_CURRENT_PIN A1
+
+// Quadrature wheel encoders, channel A and B of each track. Pins of each
+// encoder share a pin change interrupt (port C and port D).
+// See https://irro.cz/hw.html#encoders
+#define ENCODER_L_A A2
+#define ENCODER_L_B A3
+#define ENCODER_R_A 5
+#define ENCODER_R_B 6
+
+// MPU-6050 IMU connected to the I2C bus (A4 and A5).
+// See https://irro.cz/hw.html#imu
+#define IMU_ADDRESS 0x68
+#define IMU_PWR_MGMT_1 0x6b
+#define IMU_ACCEL_XOUT_H 0x3b
+// I2C timeout in microseconds so that a stuck bus doesn't block the serial
+// communication.
+#define IMU_TIMEOUT 3000
+
+// Front ultrasonic range sensor (HC-SR04). The echo pin has to be an external
+// interrupt pin so that the echo is timed without blocking the serial
+// communication. See https://irro.cz/hw.html#range
+#define RANGE_TRIGGER 12
+#define RANGE_ECHO 3
+// Measurement period and the longest echo (approximately 4 meters) in
+// microseconds.
+#define RANGE_PERIOD 60000
+#define RANGE_TIMEOUT 25000
+// Distance reported when no echo was received within the longest echo.
+#define NO_ECHO 0xffff
+// Distance reported when the sensor didn't respond to the trigger at all,
+// e.g. it is disconnected or broken.
+#define RANGE_ERROR 0xfffe
+
+#define START_MARKER 0xa5
+#define MAX_PAYLOAD_LEN 64
+// Start marker, sequence id, command (2 bytes), payload length (2 bytes) and
+// CRC (2 bytes).
+#define V2_OVERHEAD 8
+#define LATEST_PROTOCOL 3
+
+#define FIRMWARE_MAJOR 0
+#define FIRMWARE_MINOR 6
+#define FIRMWARE_PATCH 0
+
+// Header of protocol version 1 negotiation command (0x0200 with 1 byte long
+// payload).
+const byte NEGOTIATION_V1[4] = {0x02, 0x00, 0x00, 0x01};
+
+// Written once the firmware starts so that RPi knows that commands it sent
+// while the bootloader was running were lost and that state of the Arduino
+// was reset.
+const byte BOOT_BANNER[6] = {0xff, 0xff, 'I', 'R', 'R', 'O'};
+
 int currentLedMask = 0;
 
+// Motors are kept off while emergency stop is latched. The latch is released
+// only by RPi, it is not latched after reset.
+bool emergencyStop = false;
+
+// Encoder tick counters, positive when the track moves forward. They are
+// updated from the interrupt handler and wrap around on overflow.
+volatile long leftTicks = 0;
+volatile long rightTicks = 0;
+// The last state of encoder pins, A and B channel of each encoder as bits 1
+// and 0.
+volatile byte leftEncoderState = 0;
+volatile byte rightEncoderState = 0;
+
+// Start of the current range measurement, start and length of the echo pulse
+// in microseconds. The echo length is 0 until the echo ends. The sensor always
+// raises the echo pin after it is triggered, even when nothing is in range.
+unsigned long rangeTriggered = 0;
+volatile bool echoStarted = false;
+volatile unsigned long echoStart = 0;
+volatile unsigned long echoLength = 0;
+// The last measured distance in millimeters, there is no measurement before
+// the sensor is triggered for the first time.
+unsigned int rangeDistance = RANGE_ERROR;
+
+// Serial protocol version negotiated with RPi, see
+// https://irro.cz/serial_protocol.html
+int protocolVersion = 1;
+
+// Protocol version 2 frame which is being received.
+byte frame[MAX_PAYLOAD_LEN + V2_OVERHEAD];
+int frameLen = 0;
+
+// Number of bytes read from the serial port since the last protocol
+// negotiation, reported to RPi in protocol version 3 responses.
+unsigned int consumedBytes = 0;
+
+// Content of MCU status register at boot, id est cause of the last reset.
+// Note that some bootloaders clear the register in which case it is 0.
+byte resetCause = 0;
+
 void setup() {
+  resetCause = MCUSR;
+  MCUSR = 0;
+
   Serial.begin(115200);
 
   pinMode(LED_BUILTIN, OUTPUT);
@@ -31,44 +133,448 @@ void setup() {
   pinMode(MOTOR_L_IN2, OUTPUT);
   pinMode(MOTOR_R_IN1, OUTPUT);
   pinMode(MOTOR_R_IN2, OUTPUT);
+
+  setupEncoders();
+  setupRange();
+  setupImu();
+
+  Serial.write(BOOT_BANNER, sizeof(BOOT_BANNER));
 }
 
 void loop() {
+  // Read all commands first before continuing to do other work. The buffer
+  // is only 64 bytes, better to read everything as often as possible.
+  if (protocolVersion == 1) {
+    readFramesV1();
+  } else {
+    readFramesV2();
+  }
+
+  updateRange();
+}
+
+void readFramesV1() {
   int cmd;
 
-  byte payload[64];
+  byte payload[MAX_PAYLOAD_LEN];
   int payloadLen;
 
-  byte response[64];
+  byte response[MAX_PAYLOAD_LEN];
   int responseLen;
 
   while (Serial.available() >= 4) {
-    // Read all commands first before continuing to do other work. The buffer
-    // is only 64 bytes, better to read everything as often as possible.
     cmd = readInt();
     payloadLen = readInt();
+    if (payloadLen < 0 || payloadLen > MAX_PAYLOAD_LEN) {
+      // The payload doesn't fit to the buffer, it is skipped so that the next
+      // frame is read from its start and the command is responded with empty
+      // payload, like an unknown command.
+      skipBytesV1(payload, (unsigned int)payloadLen);
+      writeResponseV1(response, 0);
+      continue;
+    }
     Serial.readBytes(payload, payloadLen);
 
-    responseLen = 0;
+    // Protocol version is switched only after the response is sent.
+    int version = protocolVersion;
+    responseLen = handleCommand(cmd, payload, payloadLen, response, &version);
 
-    if (cmd == 0x0000) {
-      ledMask(payload, payloadLen);
-    } else if (cmd == 0x0001) {
-      responseLen = readLeds(response);
-    } else if (cmd == 0x0100) {
-      setMotorsPowerRatioCmd(payload, payloadLen);
+    writeResponseV1(response, responseLen);
+
+    if (version != protocolVersion) {
+      protocolVersion = version;
+      frameLen = 0;
+      consumedBytes = 0;
+      return;
     }
+  }
+}
 
-    Serial.write(0);
-    Serial.write(responseLen);
+// Drop the given number of received bytes, or fewer if they don't arrive
+// before the serial timeout. The bytes are read to the buffer of
+// MAX_PAYLOAD_LEN bytes.
+void skipBytesV1(byte *buffer, unsigned int len) {
+  while (len > 0) {
+    unsigned int chunk = len < MAX_PAYLOAD_LEN ? len : MAX_PAYLOAD_LEN;
+    size_t read = Serial.readBytes(buffer, chunk);
+    if (read == 0) {
+      return;
+    }
+    len -= read;
+  }
+}
 
-    int i;
-    for (i = 0; i < responseLen; i++) {
-      Serial.write(response[i]);
+void readFramesV2() {
+  while (Serial.available() > 0 && protocolVersion >= 2) {
+    frame[frameLen++] = Serial.read();
+    consumedBytes++;
+    // Skipping corrupted data may reveal a complete frame.
+    while (frameLen > 0 && processFrameV2()) {
     }
   }
+}
+
+// Process the receive buffer. Return true if some data were consumed or
+// skipped.
+bool processFrameV2() {
+  byte response[MAX_PAYLOAD_LEN];
+  int responseLen;
 
-  // Other logic will be placed in a [short] loop here.
+  if (frame[0] != START_MARKER) {
+    if (!isNegotiationV1()) {
+      skipFrameByte();
+      return true;
+    }
+    if (frameLen < 4) {
+      return false;
+    }
+
+    // RPi always negotiates with protocol version 1 frames, e.g. after it
+    // re-opened the serial port.
+    byte payload[1];
+    Serial.readBytes(payload, 1);
+    frameLen = 0;
+
+    int version;
+    responseLen = negotiateProtocol(payload, 1, response, &version);
+    writeResponseV1(response, responseLen);
+    protocolVersion = version;
+    consumedBytes = 0;
+    return false;
+  }
+
+  if (frameLen < 6) {
+    return false;
+  }
+
+  int payloadLen = frame[4] << 8 | frame[5];
+  if (payloadLen > MAX_PAYLOAD_LEN) {
+    skipFrameByte();
+    return true;
+  }
+
+  int len = payloadLen + V2_OVERHEAD;
+  if (frameLen < len) {
+    return false;
+  }
+
+  unsigned int crc = frame[len - 2] << 8 | frame[len - 1];
+  if (crc != crc16(frame + 1, len - 3)) {
+    skipFrameByte();
+    return true;
+  }
+
+  byte seq = frame[1];
+  int cmd = frame[2] << 8 | frame[3];
+  int version = protocolVersion;
+  responseLen = handleCommand(cmd, frame + 6, payloadLen, response, &version);
+  writeResponseV2(seq, response, responseLen);
+
+  frameLen = 0;
+  return false;
+}
+
+// Return true if the receive buffer is a beginning of protocol version 1
+// negotiation command header.
+bool isNegotiationV1() {
+  int i;
+  for (i = 0; i < frameLen && i < 4; i++) {
+    if (frame[i] != NEGOTIATION_V1[i]) {
+      return false;
+    }
+  }
+  return true;
+}
+
+// Drop first byte of the receive buffer, the frame is re-synchronized on the
+// next start marker.
+void skipFrameByte() {
+  int i;
+  for (i = 1; i < frameLen; i++) {
+    frame[i - 1] = frame[i];
+  }
+  frameLen--;
+}
+
+void writeResponseV1(byte *response, int responseLen) {
+  Serial.write(0);
+  Serial.write(responseLen);
+  Serial.write(response, responseLen);
+}
+
+// Write protocol version 2 response frame, protocol version 3 frames
+// additionally include number of consumed bytes.
+void writeResponseV2(byte seq, byte *response, int responseLen) {
+  byte header[5];
+  int headerLen = 0;
+
+  header[headerLen++] = seq;
+  if (protocolVersion >= 3) {
+    header[headerLen++] = consumedBytes >> 8;
+    header[headerLen++] = consumedBytes & 0xff;
+  }
+  header[headerLen++] = 0;
+  header[headerLen++] = responseLen;
+
+  unsigned int crc = crc16(header, headerLen);
+  crc = crc16Update(crc, response, responseLen);
+
+  Serial.write(START_MARKER);
+  Serial.write(header, headerLen);
+  Serial.write(response, responseLen);
+  Serial.write(crc >> 8);
+  Serial.write(crc & 0xff);
+}
+
+// Compute CRC-16/CCITT-FALSE of the data.
+unsigned int crc16(byte *data, int len) {
+  return crc16Update(0xffff, data, len);
+}
+
+unsigned int crc16Update(unsigned int crc, byte *data, int len) {
+  int i, j;
+  for (i = 0; i < len; i++) {
+    crc ^= (unsigned int)data[i] << 8;
+    for (j = 0; j < 8; j++) {
+      if (crc & 0x8000) {
+        crc = (crc << 1) ^ 0x1021;
+      } else {
+        crc <<= 1;
+      }
+    }
+  }
+  return crc;
+}
+
+// Execute a command and write its response to `response`. Return response
+// length.
+int handleCommand(int cmd, byte *payload, int payloadLen, byte *response,
+                  int *version) {
+  if (cmd == 0x0000) {
+    ledMask(payload, payloadLen);
+  } else if (cmd == 0x0001) {
+    return readLeds(response);
+  } else if (cmd == 0x0100) {
+    setMotorsPowerRatioCmd(payload, payloadLen);
+  } else if (cmd == 0x0101) {
+    return setEmergencyStop(payload, payloadLen, response);
+  } else if (cmd == 0x0200) {
+    return negotiateProtocol(payload, payloadLen, response, version);
+  } else if (cmd == 0x0300) {
+    return readSystemInfo(response);
+  } else if (cmd == 0x0400) {
+    return readBattery(response);
+  } else if (cmd == 0x0500) {
+    return readEncoders(response);
+  } else if (cmd == 0x0600) {
+    return readRanges(response);
+  } else if (cmd == 0x0700) {
+    return readImu(response);
+  }
+  return 0;
+}
+
+// Select the latest protocol version supported by both RPi and Arduino.
+int negotiateProtocol(byte *payload, int len, byte *response, int *version) {
+  *version = 1;
+  if (len > 0 && payload[0] > 1) {
+    *version = min(payload[0], LATEST_PROTOCOL);
+  }
+  response[0] = *version;
+  return 1;
+}
+
+// Write firmware version, protocol versions, uptime, free RAM and reset cause
+// to the response.
+int readSystemInfo(byte *response) {
+  unsigned long uptime = millis();
+  unsigned int ram = freeRam();
+
+  response[0] = FIRMWARE_MAJOR;
+  response[1] = FIRMWARE_MINOR;
+  response[2] = FIRMWARE_PATCH;
+  response[3] = protocolVersion;
+  response[4] = LATEST_PROTOCOL;
+  response[5] = uptime >> 24;
+  response[6] = uptime >> 16;
+  response[7] = uptime >> 8;
+  response[8] = uptime;
+  response[9] = ram >> 8;
+  response[10] = ram;
+  response[11] = resetCause;
+  return 12;
+}
+
+// Return number of bytes between the top of the heap and the stack.
+unsigned int freeRam() {
+  extern int __heap_start, *__brkval;
+  int top;
+  return (int)&top - (__brkval == 0 ? (int)&__heap_start : (int)__brkval);
+}
+
+// Write raw ADC readings of battery voltage and current to the response.
+int readBattery(byte *response) {
+  int voltage = analogRead(BATTERY_VOLTAGE_PIN);
+  int current = analogRead(BATTERY_CURRENT_PIN);
+
+  response[0] = voltage >> 8;
+  response[1] = voltage;
+  response[2] = current >> 8;
+  response[3] = current;
+  return 4;
+}
+
+void setupEncoders() {
+  pinMode(ENCODER_L_A, INPUT_PULLUP);
+  pinMode(ENCODER_L_B, INPUT_PULLUP);
+  pinMode(ENCODER_R_A, INPUT_PULLUP);
+  pinMode(ENCODER_R_B, INPUT_PULLUP);
+
+  leftEncoderState = readEncoderState(ENCODER_L_A, ENCODER_L_B);
+  rightEncoderState = readEncoderState(ENCODER_R_A, ENCODER_R_B);
+
+  // Enable pin change interrupts of A2, A3 (PCINT10, PCINT11) and D5, D6
+  // (PCINT21, PCINT22).
+  PCMSK1 |= bit(PCINT10) | bit(PCINT11);
+  PCMSK2 |= bit(PCINT21) | bit(PCINT22);
+  PCIFR |= bit(PCIF1) | bit(PCIF2);
+  PCICR |= bit(PCIE1) | bit(PCIE2);
+}
+
+byte readEncoderState(int pinA, int pinB) {
+  return (digitalRead(pinA) << 1) | digitalRead(pinB);
+}
+
+// Return tick change, -1, 0 or 1, between two quadrature states. Invalid
+// transitions (both channels changed) are ignored.
+int encoderStep(byte previous, byte current) {
+  // Indexed by previous state in bits 3 and 2 and current state in bits 1
+  // and 0.
+  static const int8_t steps[16] = {0, -1, 1, 0, 1, 0, 0, -1,
+                                   -1, 0, 0, 1, 0, 1, -1, 0};
+  return steps[(previous << 2) | current];
+}
+
+ISR(PCINT1_vect) {
+  byte left = readEncoderState(ENCODER_L_A, ENCODER_L_B);
+  leftTicks += encoderStep(leftEncoderState, left);
+  leftEncoderState = left;
+}
+
+ISR(PCINT2_vect) {
+  byte right = readEncoderState(ENCODER_R_A, ENCODER_R_B);
+  // The right motor is mounted mirrored.
+  rightTicks -= encoderStep(rightEncoderState, right);
+  rightEncoderState = right;
+}
+
+// Write left and right encoder tick counters to the response.
+int readEncoders(byte *response) {
+  noInterrupts();
+  long left = leftTicks;
+  long right = rightTicks;
+  interrupts();
+
+  writeLong(response, left);
+  writeLong(response + 4, right);
+  return 8;
+}
+
+void writeLong(byte *response, long value) {
+  response[0] = value >> 24;
+  response[1] = value >> 16;
+  response[2] = value >> 8;
+  response[3] = value;
+}
+
+void setupRange() {
+  pinMode(RANGE_TRIGGER, OUTPUT);
+  pinMode(RANGE_ECHO, INPUT);
+  attachInterrupt(digitalPinToInterrupt(RANGE_ECHO), rangeEcho, CHANGE);
+}
+
+void rangeEcho() {
+  if (digitalRead(RANGE_ECHO) == HIGH) {
+    echoStarted = true;
+    echoStart = micros();
+  } else {
+    echoLength = micros() - echoStart;
+  }
+}
+
+// Finish the current range measurement and start a new one once per period.
+void updateRange() {
+  unsigned long now = micros();
+  if (now - rangeTriggered < RANGE_PERIOD) {
+    return;
+  }
+
+  noInterrupts();
+  bool started = echoStarted;
+  unsigned long length = echoLength;
+  echoStarted = false;
+  echoLength = 0;
+  interrupts();
+
+  if (rangeTriggered == 0 || !started || length == 0) {
+    // The echo pin didn't go up or it is stuck up for the whole period.
+    rangeDistance = RANGE_ERROR;
+  } else if (length > RANGE_TIMEOUT) {
+    rangeDistance = NO_ECHO;
+  } else {
+    // Sound travels approximately 0.343 mm per microsecond, to the obstacle
+    // and back.
+    rangeDistance = length * 343 / 2000;
+  }
+
+  digitalWrite(RANGE_TRIGGER, HIGH);
+  delayMicroseconds(10);
+  digitalWrite(RANGE_TRIGGER, LOW);
+  rangeTriggered = now;
+}
+
+void setupImu() {
+  Wire.begin();
+  Wire.setWireTimeout(IMU_TIMEOUT, true);
+  // Wake the IMU up, it starts in sleep mode with +-2 g accelerometer and
+  // +-250 deg/s gyroscope ranges.
+  Wire.beginTransmission(IMU_ADDRESS);
+  Wire.write(IMU_PWR_MGMT_1);
+  Wire.write(0);
+  Wire.endTransmission();
+}
+
+// Write accelerometer and gyroscope X, Y and Z readings to the response. One
+// byte response means that the IMU didn't respond.
+int readImu(byte *response) {
+  Wire.beginTransmission(IMU_ADDRESS);
+  Wire.write(IMU_ACCEL_XOUT_H);
+  if (Wire.endTransmission(false) != 0 || Wire.requestFrom(IMU_ADDRESS, 14) != 14) {
+    // The IMU may have been reset (e.g. by a loose connector), wake it up
+    // for the next reading.
+    setupImu();
+    response[0] = 1;
+    return 1;
+  }
+
+  // Accelerometer registers are followed by temperature and gyroscope
+  // registers, the temperature is skipped.
+  for (int i = 0; i < 14; i++) {
+    byte value = Wire.read();
+    if (i < 6) {
+      response[i] = value;
+    } else if (i >= 8) {
+      response[i - 2] = value;
+    }
+  }
+  return 12;
+}
+
+// Write distances measured by range sensors to the response.
+int readRanges(byte *response) {
+  response[0] = rangeDistance >> 8;
+  response[1] = rangeDistance;
+  return 2;
 }
 
 // Read 2 byte int from serial port. Do not call this method if there is less
@@ -103,10 +609,36 @@ void setMotorsPowerRatioCmd(byte *payload, int len) {
   analogWrite(MOTOR_L_EN, 0);
   analogWrite(MOTOR_R_EN, 0);
 
+  if (emergencyStop) {
+    return;
+  }
+
   setMotorPowerRatio(payload, MOTOR_L_EN, MOTOR_L_IN1, MOTOR_L_IN2);
   setMotorPowerRatio((payload + 2), MOTOR_R_EN, MOTOR_R_IN1, MOTOR_R_IN2);
 }
 
+// Latch (payload 1) or release (payload 0) emergency stop and write the
+// resulting state to the response.
+int setEmergencyStop(byte *payload, int len, byte *response) {
+  if (len > 0) {
+    emergencyStop = payload[0] != 0;
+  }
+  if (emergencyStop) {
+    stopMotors();
+  }
+  response[0] = emergencyStop;
+  return 1;
+}
+
+void stopMotors() {
+  analogWrite(MOTOR_L_EN, 0);
+  analogWrite(MOTOR_R_EN, 0);
+  digitalWrite(MOTOR_L_IN1, LOW);
+  digitalWrite(MOTOR_L_IN2, LOW);
+  digitalWrite(MOTOR_R_IN1, LOW);
+  digitalWrite(MOTOR_R_IN2, LOW);
+}
+
 void setMotorPowerRatio(byte *payload, int enPin, int in1Pin, int in2Pin) {
   int value = payload[0] << 8 | payload[1];
 
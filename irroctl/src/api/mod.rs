@@ -2,7 +2,7 @@
 //! See API documentation at https://irro.cz/api.html
 
 use reqwest::{self, Error};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
@@ -19,6 +19,46 @@ pub struct Client {
 struct MotorPowerRatio {
     left: f32,
     right: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validity: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct Velocity {
+    linear: f32,
+    angular: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validity: Option<f32>,
+}
+
+/// Calibration of motors on one side of Irro.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SideCalibration {
+    /// Multiplier of the requested power ratio.
+    pub gain: f32,
+    /// Smallest power ratio which moves the track.
+    pub deadband: f32,
+    /// Reverse direction of the motors.
+    pub inverted: bool,
+    /// Largest power ratio sent to the motors.
+    pub max_power: f32,
+}
+
+/// Calibration of left and right motors.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MotorCalibration {
+    pub left: SideCalibration,
+    pub right: SideCalibration,
+}
+
+/// State of Irro's emergency stop.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct EstopStatus {
+    pub engaged: bool,
+    /// Seconds since the UNIX epoch when the emergency stop was engaged.
+    pub since: Option<f64>,
+    /// True if Arduino confirmed that its emergency stop is latched.
+    pub confirmed: bool,
 }
 
 impl Client {
@@ -90,14 +130,32 @@ impl Client {
     }
 
     /// Set power ratio to left and right motors.
-    pub fn set_motor_power_ratio(&self, left: f32, right: f32) -> Result<(), Error> {
+    ///
+    /// # Arguments
+    ///
+    /// * `left` - left motors power ratio between -1.0 and 1.0.
+    ///
+    /// * `right` - right motors power ratio between -1.0 and 1.0.
+    ///
+    /// * `validity` - seconds after which Irro stops the motors unless another
+    ///   motor command is sent. Irro's default is used if `None`.
+    pub fn set_motor_power_ratio(
+        &self,
+        left: f32,
+        right: f32,
+        validity: Option<f32>,
+    ) -> Result<(), Error> {
         if !left.is_finite() || !right.is_finite() || left.abs() > 1.0 || right.abs() > 1.0 {
             // Don't use is_infinite() as it doesn't include NaNs
             panic!("Motor power ratio must be a number between -1 and 1.");
         }
 
         let url = self.url("/low/motor/power/ratio");
-        let payload = MotorPowerRatio { left, right };
+        let payload = MotorPowerRatio {
+            left,
+            right,
+            validity,
+        };
         self.client
             .post(&url)
             .json(&payload)
@@ -106,6 +164,69 @@ impl Client {
             .map(|_| ())
     }
 
+    /// Drive Irro with a linear velocity and an angular rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `linear` - forward velocity in meters per second, negative for
+    ///   backward.
+    ///
+    /// * `angular` - yaw rate in radians per second, positive for turning
+    ///   left.
+    ///
+    /// * `validity` - seconds after which Irro stops the motors unless another
+    ///   motor command is sent. Irro's default is used if `None`.
+    pub fn drive_velocity(
+        &self,
+        linear: f32,
+        angular: f32,
+        validity: Option<f32>,
+    ) -> Result<(), Error> {
+        let url = self.url("/drive/velocity");
+        let payload = Velocity {
+            linear,
+            angular,
+            validity,
+        };
+        self.client
+            .post(&url)
+            .json(&payload)
+            .send()?
+            .error_for_status()
+            .map(|_| ())
+    }
+
+    /// Retrieve calibration applied to motor power ratios.
+    pub fn get_motor_calibration(&self) -> Result<MotorCalibration, Error> {
+        let url = self.url("/motor/calibration");
+        self.client.get(&url).send()?.error_for_status()?.json()
+    }
+
+    /// Replace calibration applied to motor power ratios. Irro stores the
+    /// calibration so that it survives restarts.
+    pub fn set_motor_calibration(&self, calibration: &MotorCalibration) -> Result<(), Error> {
+        let url = self.url("/motor/calibration");
+        self.client
+            .put(&url)
+            .json(calibration)
+            .send()?
+            .error_for_status()
+            .map(|_| ())
+    }
+
+    /// Stop the motors and latch Irro's emergency stop. Irro rejects all
+    /// motor commands until the emergency stop is released.
+    pub fn engage_estop(&self) -> Result<EstopStatus, Error> {
+        let url = self.url("/estop");
+        self.client.post(&url).send()?.error_for_status()?.json()
+    }
+
+    /// Release Irro's emergency stop.
+    pub fn release_estop(&self) -> Result<EstopStatus, Error> {
+        let url = self.url("/estop");
+        self.client.delete(&url).send()?.error_for_status()?.json()
+    }
+
     fn url(&self, endpoint: &str) -> String {
         format!("http://{}:{}{}", &self.host, self.port, endpoint)
     }
@@ -113,7 +234,7 @@ impl Client {
 
 #[cfg(test)]
 mod tests {
-    use super::Client;
+    use super::{Client, EstopStatus, MotorCalibration, SideCalibration};
     use mockito::{mock, server_address};
 
     #[test]
@@ -149,4 +270,106 @@ mod tests {
 
         mock.assert();
     }
+
+    #[test]
+    fn test_set_motor_power_ratio() {
+        let mock = mock("POST", "/low/motor/power/ratio")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body("null")
+            .match_body(r#"{"left":0.5,"right":-0.25,"validity":2.0}"#)
+            .create();
+
+        let address = server_address();
+        let client = Client::from_ip_and_port(address.ip(), address.port());
+        client.set_motor_power_ratio(0.5, -0.25, Some(2.0)).unwrap();
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_drive_velocity() {
+        let mock = mock("POST", "/drive/velocity")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"left":0.25,"right":0.75,"saturated":false}"#)
+            .match_body(r#"{"linear":0.25,"angular":1.0}"#)
+            .create();
+
+        let address = server_address();
+        let client = Client::from_ip_and_port(address.ip(), address.port());
+        client.drive_velocity(0.25, 1.0, None).unwrap();
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_motor_calibration() {
+        let body = r#"{"left":{"gain":0.5,"deadband":0.0,"inverted":false,"max_power":1.0},"right":{"gain":1.0,"deadband":0.25,"inverted":true,"max_power":0.75}}"#;
+        let get_mock = mock("GET", "/motor/calibration")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create();
+        let put_mock = mock("PUT", "/motor/calibration")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .match_body(body)
+            .create();
+
+        let address = server_address();
+        let client = Client::from_ip_and_port(address.ip(), address.port());
+        let calibration = client.get_motor_calibration().unwrap();
+        assert_eq!(
+            calibration,
+            MotorCalibration {
+                left: SideCalibration {
+                    gain: 0.5,
+                    deadband: 0.0,
+                    inverted: false,
+                    max_power: 1.0,
+                },
+                right: SideCalibration {
+                    gain: 1.0,
+                    deadband: 0.25,
+                    inverted: true,
+                    max_power: 0.75,
+                },
+            }
+        );
+        client.set_motor_calibration(&calibration).unwrap();
+
+        get_mock.assert();
+        put_mock.assert();
+    }
+
+    #[test]
+    fn test_estop() {
+        let engage_mock = mock("POST", "/estop")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"engaged":true,"since":1565000000.5,"confirmed":true}"#)
+            .create();
+        let release_mock = mock("DELETE", "/estop")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"engaged":false,"since":null,"confirmed":false}"#)
+            .create();
+
+        let address = server_address();
+        let client = Client::from_ip_and_port(address.ip(), address.port());
+        assert_eq!(
+            client.engage_estop().unwrap(),
+            EstopStatus {
+                engaged: true,
+                since: Some(1_565_000_000.5),
+                confirmed: true,
+            }
+        );
+        assert!(!client.release_estop().unwrap().engaged);
+
+        engage_mock.assert();
+        release_mock.assert();
+    }
 }
@@ -0,0 +1,211 @@
+//! This module implements guided calibration of Irro's motors.
+
+use crate::api::{Client, MotorCalibration, SideCalibration};
+use log::info;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+/// Power ratio used to find out direction of the motors.
+const DIRECTION_RATIO: f32 = 0.3;
+/// Deadband is searched for with ratios increasing by this step.
+const DEADBAND_STEP: f32 = 0.02;
+/// Largest ratio tried while searching for the deadband.
+const MAX_DEADBAND: f32 = 0.5;
+/// Power ratio of both sides while the gain trim is adjusted.
+const TRIM_RATIO: f32 = 0.5;
+/// Initial change of the gain trim, it is halved whenever Irro starts
+/// veering to the other side.
+const TRIM_STEP: f32 = 0.08;
+const MAX_TRIM_ROUNDS: u32 = 8;
+/// Duration of a single test drive.
+const DRIVE_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    fn name(self) -> &'static str {
+        match self {
+            Side::Left => "left",
+            Side::Right => "right",
+        }
+    }
+
+    fn get(self, calibration: &mut MotorCalibration) -> &mut SideCalibration {
+        match self {
+            Side::Left => &mut calibration.left,
+            Side::Right => &mut calibration.right,
+        }
+    }
+
+    fn ratios(self, ratio: f32) -> (f32, f32) {
+        match self {
+            Side::Left => (ratio, 0.0),
+            Side::Right => (0.0, ratio),
+        }
+    }
+}
+
+/// Walk the user through calibration of motor direction, deadband and gain
+/// trim. The resulting calibration is stored on Irro. This is a long running
+/// blocking function reading answers from standard input.
+///
+/// # Panics
+///
+/// This function panics if Irro's API responds with an error or doesn't
+/// respond at all.
+pub fn calibrate(client: &Client) {
+    let mut calibration = client.get_motor_calibration().unwrap();
+    info!("Current motor calibration: {:?}", calibration);
+    info!(
+        "Place Irro on the floor with at least two meters of free space in \
+         front of it. Max power of the motors is kept, everything else is \
+         calibrated again."
+    );
+
+    for &side in &[Side::Left, Side::Right] {
+        let side_calibration = side.get(&mut calibration);
+        side_calibration.gain = 1.0;
+        side_calibration.deadband = 0.0;
+        side_calibration.inverted = false;
+    }
+    client.set_motor_calibration(&calibration).unwrap();
+
+    for &side in &[Side::Left, Side::Right] {
+        wait_for_enter(&format!(
+            "Press enter to drive the {} track forward.",
+            side.name()
+        ));
+        drive(client, side.ratios(DIRECTION_RATIO));
+        let forward = ask(&format!("Did the {} track move forward?", side.name()));
+        side.get(&mut calibration).inverted = !forward;
+        client.set_motor_calibration(&calibration).unwrap();
+    }
+
+    for &side in &[Side::Left, Side::Right] {
+        let deadband = find_deadband(client, side);
+        info!("Deadband of {} motors is {:.2}.", side.name(), deadband);
+        side.get(&mut calibration).deadband = deadband;
+        client.set_motor_calibration(&calibration).unwrap();
+    }
+
+    trim(client, &mut calibration);
+
+    info!("Final motor calibration: {:?}", calibration);
+}
+
+/// Return the smallest power ratio which moves the track.
+fn find_deadband(client: &Client, side: Side) -> f32 {
+    wait_for_enter(&format!(
+        "Press enter to find the smallest power which moves the {} track.",
+        side.name()
+    ));
+
+    let mut ratio = DEADBAND_STEP;
+    while ratio < MAX_DEADBAND {
+        info!("Driving the {} track at {:.2}.", side.name(), ratio);
+        drive(client, side.ratios(ratio));
+        if ask(&format!("Did the {} track move?", side.name())) {
+            return ratio;
+        }
+        ratio += DEADBAND_STEP;
+    }
+
+    panic!(
+        "The {} track didn't move even at {:.2} power.",
+        side.name(),
+        MAX_DEADBAND
+    );
+}
+
+/// Adjust gain trim of the faster side until Irro goes straight. Gain of the
+/// slower side is raised back first so that no more power is lost than
+/// necessary.
+fn trim(client: &Client, calibration: &mut MotorCalibration) {
+    let mut step = TRIM_STEP;
+    let mut last_faster: Option<Side> = None;
+
+    for _ in 0..MAX_TRIM_ROUNDS {
+        wait_for_enter("Press enter to drive straight forward.");
+        drive(client, (TRIM_RATIO, TRIM_RATIO));
+
+        let faster = match choose("Did Irro go straight (s), veer left (l) or veer right (r)?") {
+            's' => return,
+            // Veering left means that the right track is faster.
+            'l' => Side::Right,
+            _ => Side::Left,
+        };
+        if last_faster.is_some() && last_faster != Some(faster) {
+            step /= 2.0;
+        }
+        last_faster = Some(faster);
+
+        let slower = match faster {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        };
+        if slower.get(calibration).gain < 1.0 {
+            let gain = &mut slower.get(calibration).gain;
+            *gain = (*gain + step).min(1.0);
+        } else {
+            let gain = &mut faster.get(calibration).gain;
+            *gain = (*gain - step).max(step);
+        }
+        info!(
+            "Gain trim is {:.3} on the left and {:.3} on the right.",
+            calibration.left.gain, calibration.right.gain
+        );
+        client.set_motor_calibration(calibration).unwrap();
+    }
+
+    info!("Gain trim wasn't settled, the last one is kept.");
+}
+
+/// Drive with the given power ratios and stop.
+fn drive(client: &Client, (left, right): (f32, f32)) {
+    let validity = DRIVE_TIME.as_secs() as f32 + 1.0;
+    client
+        .set_motor_power_ratio(left, right, Some(validity))
+        .unwrap();
+    thread::sleep(DRIVE_TIME);
+    client.set_motor_power_ratio(0.0, 0.0, None).unwrap();
+}
+
+fn read_answer(prompt: &str) -> String {
+    eprint!("{} ", prompt);
+    io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    answer.trim().to_lowercase()
+}
+
+fn wait_for_enter(prompt: &str) {
+    read_answer(prompt);
+}
+
+/// Ask a yes/no question.
+fn ask(question: &str) -> bool {
+    loop {
+        match read_answer(&format!("{} [y/n]", question)).as_str() {
+            "y" | "yes" => return true,
+            "n" | "no" => return false,
+            _ => continue,
+        }
+    }
+}
+
+/// Ask a question answered with one of s, l or r.
+fn choose(question: &str) -> char {
+    loop {
+        match read_answer(question).as_str() {
+            "s" => return 's',
+            "l" => return 'l',
+            "r" => return 'r',
+            _ => continue,
+        }
+    }
+}
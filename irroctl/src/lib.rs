@@ -1,3 +1,4 @@
 pub mod api;
+pub mod calibrate;
 pub mod network;
 pub mod test;
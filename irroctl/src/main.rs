@@ -1,8 +1,9 @@
 use clap::{App, AppSettings, Arg, ArgMatches, Error, ErrorKind, SubCommand};
 use libirroctl::api::Client;
+use libirroctl::calibrate;
 use libirroctl::network;
 use libirroctl::test;
-use log::info;
+use log::{info, warn};
 use simplelog::{Config, LevelFilter, TermLogger, TerminalMode};
 use std::path::Path;
 
@@ -61,6 +62,67 @@ fn main() {
                 .takes_value(true)
                 .allow_hyphen_values(true)
                 .required(true),
+        )
+        .arg(
+            Arg::with_name("validity")
+                .long("validity")
+                .help(
+                    "Seconds after which Irro stops the motors unless another \
+                     motor command is sent. Irro's default is used if not given.",
+                )
+                .takes_value(true),
+        );
+
+    let drive_cmd = SubCommand::with_name("drive")
+        .about(
+            "Drive Irro with a linear velocity (meters per second) and an \
+             angular rate (radians per second). Irro converts these to motor \
+             power ratios.",
+        )
+        .arg(
+            Arg::with_name("linear")
+                .long("linear")
+                .help("Forward velocity in meters per second, negative for backward.")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("angular")
+                .long("angular")
+                .help("Yaw rate in radians per second, positive for turning left.")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("validity")
+                .long("validity")
+                .help(
+                    "Seconds after which Irro stops the motors unless another \
+                     motor command is sent. Irro's default is used if not given.",
+                )
+                .takes_value(true),
+        );
+
+    let calibrate_cmd = SubCommand::with_name("calibrate")
+        .about("Calibrate direction, deadband and gain trim of motors")
+        .long_about(
+            "This command drives Irro's motors and asks the user how Irro \
+             moved. Direction of the motors, the smallest power which moves \
+             each track and gain trim making Irro go straight are found this \
+             way. The resulting calibration is stored on Irro.",
+        );
+
+    let estop_cmd = SubCommand::with_name("estop")
+        .about(
+            "Engage Irro's emergency stop. The motors are stopped and all motor \
+             commands are rejected until the emergency stop is released.",
+        )
+        .arg(
+            Arg::with_name("release")
+                .long("release")
+                .help("Release the emergency stop instead."),
         );
 
     let matches = App::new("irro-cli")
@@ -78,6 +140,9 @@ fn main() {
         .subcommand(test_cmd)
         .subcommand(discover_cmd)
         .subcommand(motor_cmd)
+        .subcommand(drive_cmd)
+        .subcommand(calibrate_cmd)
+        .subcommand(estop_cmd)
         .get_matches();
 
     match matches.subcommand() {
@@ -89,8 +154,34 @@ fn main() {
         ("motor", Some(matches)) => {
             let left = parse_motor_power_ratio(&matches, "left");
             let right = parse_motor_power_ratio(&matches, "right");
+            let validity = parse_validity(&matches);
             let client = Client::from_file(Path::new(IP_FILE)).unwrap();
-            client.set_motor_power_ratio(left, right).unwrap();
+            client.set_motor_power_ratio(left, right, validity).unwrap();
+        }
+        ("drive", Some(matches)) => {
+            let linear = parse_velocity(&matches, "linear");
+            let angular = parse_velocity(&matches, "angular");
+            let validity = parse_validity(&matches);
+            let client = Client::from_file(Path::new(IP_FILE)).unwrap();
+            client.drive_velocity(linear, angular, validity).unwrap();
+        }
+        ("calibrate", _) => {
+            let client = Client::from_file(Path::new(IP_FILE)).unwrap();
+            calibrate::calibrate(&client);
+        }
+        ("estop", Some(matches)) => {
+            let client = Client::from_file(Path::new(IP_FILE)).unwrap();
+            if matches.is_present("release") {
+                client.release_estop().unwrap();
+                info!("Emergency stop released.");
+            } else {
+                let status = client.engage_estop().unwrap();
+                if status.confirmed {
+                    info!("Emergency stop engaged.");
+                } else {
+                    warn!("Emergency stop engaged but Arduino didn't confirm it.");
+                }
+            }
         }
         _ => panic!("Unrecognized command"),
     }
@@ -128,3 +219,26 @@ fn discover_irro(store: bool) {
         Client::store_to_file(Path::new(IP_FILE), irro_ip).unwrap();
     }
 }
+
+fn parse_velocity(matches: &ArgMatches, arg_name: &str) -> f32 {
+    match matches.value_of(arg_name).unwrap().parse::<f32>() {
+        Ok(value) if value.is_finite() => value,
+        _ => Error::with_description(
+            &format!("Option --{} must be a number.", arg_name),
+            ErrorKind::InvalidValue,
+        )
+        .exit(),
+    }
+}
+
+fn parse_validity(matches: &ArgMatches) -> Option<f32> {
+    let value = matches.value_of("validity")?;
+    match value.parse::<f32>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Some(value),
+        _ => Error::with_description(
+            "Validity must be a positive number of seconds.",
+            ErrorKind::InvalidValue,
+        )
+        .exit(),
+    }
+}
@@ -6,6 +6,10 @@ use log::{info, warn};
 use std::thread;
 use std::time::Duration;
 
+/// Validity of motor commands in seconds, it has to outlast pauses between
+/// test steps.
+const MOTOR_VALIDITY: f32 = 6.0;
+
 macro_rules! validate {
     ($arg:tt) => {
         warn!("[VALIDATE] {}", $arg);
@@ -64,19 +68,25 @@ pub fn integration() {
 
     thread::sleep(Duration::from_secs(5));
     validate!("Going to move forwards at 10% speed.");
-    client.set_motor_power_ratio(0.1, 0.1).unwrap();
+    client
+        .set_motor_power_ratio(0.1, 0.1, Some(MOTOR_VALIDITY))
+        .unwrap();
 
     thread::sleep(Duration::from_secs(5));
     validate!("Going to move backwards 5% speed.");
-    client.set_motor_power_ratio(-0.05, -0.05).unwrap();
+    client
+        .set_motor_power_ratio(-0.05, -0.05, Some(MOTOR_VALIDITY))
+        .unwrap();
 
     thread::sleep(Duration::from_secs(5));
     validate!("Going to turn left.");
-    client.set_motor_power_ratio(0., 0.05).unwrap();
+    client
+        .set_motor_power_ratio(0., 0.05, Some(MOTOR_VALIDITY))
+        .unwrap();
 
     thread::sleep(Duration::from_secs(5));
     validate!("Going to stop.");
-    client.set_motor_power_ratio(0., 0.).unwrap();
+    client.set_motor_power_ratio(0., 0., None).unwrap();
 
     info!("Integration test suit is finished.");
 }
@@ -1,34 +1,120 @@
 //! This module implements REST API running on Irro's onboard computer.
 //! See [API documentation](https://irro.cz/api.html).
 
-use crate::arduino::binary::Message;
-use crate::arduino::cmd::led::LedMask;
-use crate::arduino::cmd::motor::MotorPowerRatio;
+use crate::arduino::binary::{Handle, Pending, ResponseError, State};
+use crate::arduino::cmd::led::{LedMask, ReadLedMask};
+use crate::arduino::cmd::sensor::{BatteryCalibration, ReadBattery};
+use crate::arduino::cmd::system::ReadFirmwareInfo;
+use crate::battery::BatteryWatch;
+use crate::calibration::{Calibration, CalibrationStore};
+use crate::drive::Chassis;
+use crate::estop::{self, Estop};
+use crate::heading::{self, HeadingHold};
+use crate::imu::ImuWatch;
+use crate::odometry::{Odometry, OdometryWatch};
+use crate::ramp::Ramp;
+use crate::range::RangeWatch;
+use crate::supervisor::SafetyWatch;
+use crate::watchdog::{self, MotorWatchdog};
 use actix_web::{middleware::Logger, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use log::{info, warn};
-use serde::Deserialize;
-use std::io;
-use std::sync::mpsc::Sender;
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const SERVER_ADDRESS: &str = "0.0.0.0:8080";
 
+/// Services controlling the motors.
+#[derive(Clone)]
+pub struct Motors {
+    /// Safety level limiting motor power.
+    pub safety: SafetyWatch,
+    /// Watchdog stopping the motors when motor commands expire.
+    pub watchdog: MotorWatchdog,
+    /// Ramp applying motor power ratios gradually.
+    pub ramp: Ramp,
+    /// Calibration applied to motor power ratios.
+    pub calibration: CalibrationStore,
+    /// Chassis used to convert velocities to motor power ratios.
+    pub chassis: Chassis,
+    /// Emergency stop rejecting all motor commands while engaged.
+    pub estop: Estop,
+    /// Heading hold correcting straight driving.
+    pub heading: HeadingHold,
+}
+
+/// Services processing sensor readings.
+#[derive(Clone)]
+pub struct Sensors {
+    /// Pose of the robot integrated from wheel encoders.
+    pub odometry: OdometryWatch,
+    /// Distances measured by range sensors.
+    pub range: RangeWatch,
+    /// Orientation estimated from the IMU.
+    pub imu: ImuWatch,
+}
+
 /// Start HTTP API server in blocking mode.
 ///
 /// # Arguments
 ///
-/// * `sender` - A channel for communication with Arduino via serial port.
-pub fn run_http_server(sender: Sender<Message>) -> io::Result<()> {
+/// * `arduino` - Handle of the connection to Arduino.
+///
+/// * `calibration` - Conversion of raw battery readings to volts and amps.
+///
+/// * `battery` - Battery state of charge estimate.
+///
+/// * `motors` - Services controlling the motors.
+///
+/// * `sensors` - Services processing sensor readings.
+pub fn run_http_server(
+    arduino: Handle,
+    calibration: BatteryCalibration,
+    battery: BatteryWatch,
+    motors: Motors,
+    sensors: Sensors,
+) -> io::Result<()> {
     info!("Starting HTTP server on {}...", SERVER_ADDRESS);
 
     HttpServer::new(move || {
         let scope_low = web::scope("/low")
             .route("/led/{id}", web::put().to(put_led))
             .route("/led", web::get().to(get_leds))
-            .route("/motor/power/ratio", web::post().to(post_motor_power_ratio));
+            .route("/firmware", web::get().to(get_firmware))
+            .route("/battery", web::get().to(get_battery))
+            .route("/motor/power/ratio", web::post().to(post_motor_power_ratio))
+            .route("/motor/power/ratio", web::get().to(get_motor_power_ratio));
 
         App::new()
             .wrap(Logger::default())
-            .data(sender.clone())
+            .data(arduino.clone())
+            .data(calibration)
+            .data(battery.clone())
+            .data(motors.safety.clone())
+            .data(motors.watchdog.clone())
+            .data(motors.ramp.clone())
+            .data(motors.calibration.clone())
+            .data(motors.estop.clone())
+            .data(motors.clone())
+            .data(motors.heading.clone())
+            .data(sensors.odometry.clone())
+            .data(sensors.range.clone())
+            .data(sensors.imu.clone())
+            .route("/battery", web::get().to(get_battery_state))
+            .route("/safety", web::get().to(get_safety))
+            .route("/watchdog", web::get().to(get_watchdog))
+            .route("/drive/velocity", web::post().to(post_drive_velocity))
+            .route("/motor/calibration", web::get().to(get_motor_calibration))
+            .route("/motor/calibration", web::put().to(put_motor_calibration))
+            .route("/estop", web::get().to(get_estop))
+            .route("/estop", web::post().to(post_estop))
+            .route("/estop", web::delete().to(delete_estop))
+            .route("/odometry", web::get().to(get_odometry))
+            .route("/odometry/reset", web::post().to(post_odometry_reset))
+            .route("/sensors/range", web::get().to(get_ranges))
+            .route("/sensors/imu", web::get().to(get_imu))
+            .route("/drive/heading-hold", web::get().to(get_heading_hold))
+            .route("/drive/heading-hold", web::put().to(put_heading_hold))
             .service(scope_low)
             .default_service(web::route().to(default_handler))
     })
@@ -47,16 +133,47 @@ fn default_handler(req: HttpRequest) -> impl Responder {
     )
 }
 
-fn get_leds(data: web::Data<Sender<Message>>) -> impl Responder {
-    let led_states: Vec<bool> = LedMask::read(data.get_ref()).into();
-    HttpResponse::Ok().json(led_states)
+/// Return an error response if the connection to Arduino is not established
+/// at the moment.
+fn check_connection(arduino: &Handle) -> Result<(), HttpResponse> {
+    match arduino.state() {
+        State::Connected => Ok(()),
+        state => Err(HttpResponse::ServiceUnavailable().body(format!(
+            "Arduino is not available, connection is {:?}.",
+            state
+        ))),
+    }
 }
 
-fn put_led(
-    data: web::Data<Sender<Message>>,
-    req: HttpRequest,
-    value: web::Json<bool>,
-) -> impl Responder {
+/// Construct an error response corresponding to a failed Arduino command.
+fn arduino_error(error: &ResponseError) -> HttpResponse {
+    warn!("Arduino command failed: {}", error);
+
+    match error {
+        ResponseError::Timeout => HttpResponse::GatewayTimeout().body(error.to_string()),
+        ResponseError::Disconnected => HttpResponse::ServiceUnavailable().body(error.to_string()),
+        ResponseError::ProtocolError(_) | ResponseError::Unsupported(_) => {
+            HttpResponse::BadGateway().body(error.to_string())
+        }
+        ResponseError::Superseded => HttpResponse::Conflict().body(error.to_string()),
+    }
+}
+
+fn get_leds(arduino: web::Data<Handle>) -> impl Responder {
+    if let Err(response) = check_connection(arduino.get_ref()) {
+        return response;
+    }
+
+    match arduino.execute(&ReadLedMask) {
+        Ok(mask) => {
+            let led_states: Vec<bool> = mask.into();
+            HttpResponse::Ok().json(led_states)
+        }
+        Err(error) => arduino_error(&error),
+    }
+}
+
+fn put_led(arduino: web::Data<Handle>, req: HttpRequest, value: web::Json<bool>) -> impl Responder {
     let led_id = req.match_info().get("id").unwrap();
     let led_id: u32 = match led_id.parse() {
         Err(reason) => {
@@ -70,18 +187,201 @@ fn put_led(
         return HttpResponse::NotFound().body(format!("LED \"{}\" does not exist.", led_id));
     }
 
-    LedMask::from_bools(vec![value.into_inner()]).send(data.get_ref());
-    HttpResponse::Ok().json(())
+    if let Err(response) = check_connection(arduino.get_ref()) {
+        return response;
+    }
+
+    match arduino.execute(&LedMask::from_bools(vec![value.into_inner()])) {
+        Ok(_) => HttpResponse::Ok().json(()),
+        Err(error) => arduino_error(&error),
+    }
+}
+
+#[derive(Serialize)]
+struct Firmware {
+    version: String,
+    protocol: u8,
+    max_protocol: u8,
+    uptime: u64,
+    free_ram: u16,
+    reset_cause: Vec<&'static str>,
+}
+
+fn get_firmware(arduino: web::Data<Handle>) -> impl Responder {
+    if let Err(response) = check_connection(arduino.get_ref()) {
+        return response;
+    }
+
+    match arduino.execute(&ReadFirmwareInfo) {
+        Ok(info) => HttpResponse::Ok().json(Firmware {
+            version: info.version_string(),
+            protocol: info.protocol,
+            max_protocol: info.max_protocol,
+            uptime: info.uptime.as_secs(),
+            free_ram: info.free_ram,
+            reset_cause: info.reset_cause.causes(),
+        }),
+        Err(error) => arduino_error(&error),
+    }
+}
+
+#[derive(Serialize)]
+struct Battery {
+    voltage: f32,
+    current: f32,
+    timestamp: f64,
+}
+
+fn get_battery(
+    arduino: web::Data<Handle>,
+    calibration: web::Data<BatteryCalibration>,
+) -> impl Responder {
+    if let Err(response) = check_connection(arduino.get_ref()) {
+        return response;
+    }
+
+    match arduino.execute(&ReadBattery) {
+        Ok(raw) => {
+            let battery = raw.convert(calibration.get_ref());
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            HttpResponse::Ok().json(Battery {
+                voltage: battery.voltage,
+                current: battery.current,
+                timestamp: seconds(timestamp),
+            })
+        }
+        Err(error) => arduino_error(&error),
+    }
+}
+
+#[derive(Serialize)]
+struct BatteryState {
+    state_of_charge: f32,
+    remaining_runtime: Option<u64>,
+    low: bool,
+    voltage: f32,
+    current: f32,
+}
+
+fn get_battery_state(battery: web::Data<BatteryWatch>) -> impl Responder {
+    match battery.get() {
+        Some(estimate) => HttpResponse::Ok().json(BatteryState {
+            state_of_charge: estimate.soc,
+            remaining_runtime: estimate.remaining.map(|remaining| remaining.as_secs()),
+            low: estimate.low,
+            voltage: estimate.battery.voltage,
+            current: estimate.battery.current,
+        }),
+        None => HttpResponse::ServiceUnavailable().body("Battery has not been read yet."),
+    }
+}
+
+#[derive(Serialize)]
+struct Safety {
+    level: String,
+    reason: Option<String>,
+    obstacle: Option<String>,
+}
+
+fn get_safety(safety: web::Data<SafetyWatch>) -> impl Responder {
+    let (level, reason) = safety.get();
+    HttpResponse::Ok().json(Safety {
+        level: level.to_string(),
+        reason,
+        obstacle: safety.obstacle(),
+    })
+}
+
+#[derive(Serialize)]
+struct RangeState {
+    distances: Vec<Option<f32>>,
+    closest: Option<f32>,
+    min_distance: f32,
+    obstacle: bool,
+    stale: bool,
+    failed: bool,
+    timestamp: f64,
+}
+
+fn get_ranges(range: web::Data<RangeWatch>, safety: web::Data<SafetyWatch>) -> impl Responder {
+    match range.get() {
+        Some(ranges) => HttpResponse::Ok().json(RangeState {
+            closest: ranges.closest(),
+            min_distance: range.min_distance(),
+            obstacle: safety.obstacle().is_some(),
+            stale: ranges.is_stale(),
+            failed: ranges.failed,
+            timestamp: seconds(ranges.time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+            distances: ranges.distances,
+        }),
+        None => HttpResponse::ServiceUnavailable().body("Range sensors have not been read yet."),
+    }
+}
+
+#[derive(Serialize)]
+struct Watchdog {
+    default_validity: f64,
+    remaining: Option<f64>,
+    expired: u64,
+    last_expired: Option<f64>,
+}
+
+fn get_watchdog(watchdog: web::Data<MotorWatchdog>) -> impl Responder {
+    let status = watchdog.status();
+    HttpResponse::Ok().json(Watchdog {
+        default_validity: seconds(status.default_validity),
+        remaining: status.remaining.map(seconds),
+        expired: status.expired,
+        last_expired: status
+            .last_expired
+            .map(|time| seconds(time.duration_since(UNIX_EPOCH).unwrap_or_default())),
+    })
+}
+
+/// Convert a duration to fractional seconds.
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
 }
 
 #[derive(Deserialize)]
 struct MotorRatio {
     left: f32,
     right: f32,
+    /// Validity of the command in seconds.
+    validity: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct PowerRatio {
+    left: f32,
+    right: f32,
+}
+
+impl From<(f32, f32)> for PowerRatio {
+    fn from((left, right): (f32, f32)) -> Self {
+        PowerRatio { left, right }
+    }
+}
+
+#[derive(Serialize)]
+struct MotorRatioStatus {
+    requested: PowerRatio,
+    applied: PowerRatio,
+}
+
+fn get_motor_power_ratio(ramp: web::Data<Ramp>) -> impl Responder {
+    let status = ramp.status();
+    HttpResponse::Ok().json(MotorRatioStatus {
+        requested: status.requested.into(),
+        applied: status.applied.into(),
+    })
 }
 
 fn post_motor_power_ratio(
-    data: web::Data<Sender<Message>>,
+    arduino: web::Data<Handle>,
+    motors: web::Data<Motors>,
     value: web::Json<MotorRatio>,
 ) -> impl Responder {
     let motor_ratio = value.into_inner();
@@ -95,7 +395,284 @@ fn post_motor_power_ratio(
         );
     }
 
-    let command = MotorPowerRatio::from_floats(left, right);
-    command.send(data.get_ref());
-    HttpResponse::Ok().json(())
+    let validity = match parse_validity(motor_ratio.validity) {
+        Ok(validity) => validity,
+        Err(response) => return response,
+    };
+
+    match set_motors(&arduino, &motors, (left, right), validity) {
+        Ok(_) => HttpResponse::Ok().json(()),
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+struct Velocity {
+    linear: f32,
+    angular: f32,
+    /// Validity of the command in seconds.
+    validity: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct VelocityRatio {
+    left: f32,
+    right: f32,
+    saturated: bool,
+}
+
+fn post_drive_velocity(
+    arduino: web::Data<Handle>,
+    motors: web::Data<Motors>,
+    value: web::Json<Velocity>,
+) -> impl Responder {
+    let velocity = value.into_inner();
+    if !velocity.linear.is_finite() || !velocity.angular.is_finite() {
+        return HttpResponse::BadRequest()
+            .body("Linear velocity and angular rate have to be finite numbers.");
+    }
+
+    let validity = match parse_validity(velocity.validity) {
+        Ok(validity) => validity,
+        Err(response) => return response,
+    };
+
+    let ratios = motors.chassis.ratios(velocity.linear, velocity.angular);
+    match set_motors(&arduino, &motors, (ratios.left, ratios.right), validity) {
+        Ok((left, right)) => HttpResponse::Ok().json(VelocityRatio {
+            left,
+            right,
+            saturated: ratios.saturated,
+        }),
+        Err(response) => response,
+    }
+}
+
+/// Parse validity of a motor command given in seconds.
+fn parse_validity(validity: Option<f32>) -> Result<Option<Duration>, HttpResponse> {
+    let max_validity = seconds(watchdog::MAX_VALIDITY) as f32;
+    match validity {
+        Some(validity) if !validity.is_finite() || validity <= 0.0 || validity > max_validity => {
+            Err(HttpResponse::BadRequest().body(format!(
+                "Validity has to be a positive number of seconds not larger than {}.",
+                max_validity
+            )))
+        }
+        Some(validity) => Ok(Some(Duration::from_millis((validity * 1000.0) as u64))),
+        None => Ok(None),
+    }
+}
+
+/// Set motor power ratio unless the emergency stop is engaged. The ratio is
+/// limited by the safety supervisor, guarded by the watchdog, applied via
+/// the ramp and corrected by the motor calibration. Return the ratio which
+/// was set, before calibration.
+fn set_motors(
+    arduino: &Handle,
+    motors: &Motors,
+    (left, right): (f32, f32),
+    validity: Option<Duration>,
+) -> Result<(f32, f32), HttpResponse> {
+    // The command is sent under the emergency stop guard so that it is never
+    // sent after the emergency stop. It is waited for afterwards.
+    let dispatched = motors
+        .estop
+        .guard(|| {
+            check_connection(arduino)?;
+            let (left, right) = motors
+                .safety
+                .limit_motors(left, right)
+                .map_err(|reason| HttpResponse::ServiceUnavailable().body(reason))?;
+            // Only commands which are dispatched feed the watchdog. It is fed
+            // just before the dispatch so that a stop by the watchdog which
+            // is in progress is sent first. The calibration keeps zero so
+            // this is the same as the calibrated command stopping the motors.
+            motors.watchdog.feed(left == 0.0 && right == 0.0, validity);
+            if motors.ramp.request(left, right) {
+                // The ramp sends the ratio gradually.
+                return Ok(((left, right), None));
+            }
+            let command = motors
+                .calibration
+                .command(left, right, motors.safety.power_cap());
+            match arduino.send(&command) {
+                Ok(pending) => Ok(((left, right), Some(pending))),
+                Err(error) => Err(arduino_error(&error)),
+            }
+        })
+        .map_err(|reason| HttpResponse::ServiceUnavailable().body(reason))?;
+
+    let (ratio, pending) = dispatched?;
+    match pending.map(Pending::wait) {
+        Some(Err(error)) => Err(arduino_error(&error)),
+        _ => Ok(ratio),
+    }
+}
+
+fn get_motor_calibration(calibration: web::Data<CalibrationStore>) -> impl Responder {
+    HttpResponse::Ok().json(calibration.get())
+}
+
+fn put_motor_calibration(
+    calibration: web::Data<CalibrationStore>,
+    value: web::Json<Calibration>,
+) -> impl Responder {
+    match calibration.set(value.into_inner()) {
+        Ok(_) => HttpResponse::Ok().json(calibration.get()),
+        Err(ref error) if error.kind() == ErrorKind::InvalidInput => {
+            HttpResponse::BadRequest().body(error.to_string())
+        }
+        Err(error) => {
+            warn!("Motor calibration couldn't be stored: {}", error);
+            HttpResponse::InternalServerError().body(format!(
+                "Calibration is in use but it couldn't be stored: {}",
+                error
+            ))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EstopState {
+    engaged: bool,
+    since: Option<f64>,
+    confirmed: bool,
+}
+
+impl From<estop::Status> for EstopState {
+    fn from(status: estop::Status) -> Self {
+        EstopState {
+            engaged: status.engaged,
+            since: status
+                .since
+                .map(|time| seconds(time.duration_since(UNIX_EPOCH).unwrap_or_default())),
+            confirmed: status.confirmed,
+        }
+    }
+}
+
+fn get_estop(estop: web::Data<Estop>) -> impl Responder {
+    HttpResponse::Ok().json(EstopState::from(estop.status()))
+}
+
+fn post_estop(estop: web::Data<Estop>, req: HttpRequest) -> impl Responder {
+    let source = match req.connection_info().remote() {
+        Some(remote) => format!("API client {}", remote),
+        None => "API client".to_owned(),
+    };
+    HttpResponse::Ok().json(EstopState::from(estop.engage(&source)))
+}
+
+fn delete_estop(estop: web::Data<Estop>) -> impl Responder {
+    match estop.release() {
+        Ok(status) => HttpResponse::Ok().json(EstopState::from(status)),
+        Err(error) => arduino_error(&error),
+    }
+}
+
+#[derive(Serialize)]
+struct OdometryState {
+    x: f64,
+    y: f64,
+    heading: f64,
+    distance: f64,
+    left_ticks: i32,
+    right_ticks: i32,
+}
+
+impl OdometryState {
+    fn from_odometry(odometry: &Odometry) -> Option<Self> {
+        let pose = odometry.pose();
+        odometry.ticks().map(|ticks| OdometryState {
+            x: pose.x,
+            y: pose.y,
+            heading: pose.heading,
+            distance: odometry.distance(),
+            left_ticks: ticks.left,
+            right_ticks: ticks.right,
+        })
+    }
+}
+
+fn odometry_response(odometry: &OdometryWatch) -> HttpResponse {
+    match OdometryState::from_odometry(&odometry.get()) {
+        Some(state) => HttpResponse::Ok().json(state),
+        None => HttpResponse::ServiceUnavailable().body("Encoders have not been read yet."),
+    }
+}
+
+fn get_odometry(odometry: web::Data<OdometryWatch>) -> impl Responder {
+    odometry_response(odometry.get_ref())
+}
+
+fn post_odometry_reset(odometry: web::Data<OdometryWatch>) -> impl Responder {
+    odometry.reset();
+    info!("Odometry reset via API.");
+    odometry_response(odometry.get_ref())
+}
+
+#[derive(Serialize)]
+struct ImuState {
+    acceleration: [f32; 3],
+    angular_velocity: [f32; 3],
+    roll: f32,
+    pitch: f32,
+    yaw: f32,
+    timestamp: f64,
+}
+
+fn get_imu(imu: web::Data<ImuWatch>) -> impl Responder {
+    match imu.get() {
+        Some(state) => HttpResponse::Ok().json(ImuState {
+            acceleration: state.imu.acceleration,
+            angular_velocity: state.imu.angular_velocity,
+            roll: state.orientation.roll,
+            pitch: state.orientation.pitch,
+            yaw: state.orientation.yaw,
+            timestamp: seconds(state.time.duration_since(UNIX_EPOCH).unwrap_or_default()),
+        }),
+        None => HttpResponse::ServiceUnavailable().body("IMU has not been calibrated yet."),
+    }
+}
+
+#[derive(Serialize)]
+struct HeadingHoldState {
+    enabled: bool,
+    active: bool,
+    yaw_rate: Option<f32>,
+    correction: f32,
+}
+
+impl From<heading::Status> for HeadingHoldState {
+    fn from(status: heading::Status) -> Self {
+        HeadingHoldState {
+            enabled: status.enabled,
+            active: status.active,
+            yaw_rate: status.yaw_rate,
+            correction: status.correction,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HeadingHoldRequest {
+    enabled: bool,
+}
+
+fn get_heading_hold(heading: web::Data<HeadingHold>) -> impl Responder {
+    HttpResponse::Ok().json(HeadingHoldState::from(heading.status()))
+}
+
+fn put_heading_hold(
+    heading: web::Data<HeadingHold>,
+    value: web::Json<HeadingHoldRequest>,
+) -> impl Responder {
+    if value.enabled != heading.enabled() {
+        info!(
+            "Heading hold {} via API.",
+            if value.enabled { "enabled" } else { "disabled" }
+        );
+    }
+    heading.set_enabled(value.enabled);
+    HttpResponse::Ok().json(HeadingHoldState::from(heading.status()))
 }
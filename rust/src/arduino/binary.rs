@@ -2,36 +2,246 @@
 //! Arduino over a serial port. The communication is handled in its own
 //! thread.
 //!
+//! When the serial device disappears (e.g. the Arduino is unplugged or reset),
+//! all pending messages are failed and the connection tries to re-open the
+//! same device with an exponential back-off.
+//!
+//! Protocol version is negotiated with the Arduino each time the device is
+//! opened, see `super::frame::Protocol`. Opening a serial port usually resets
+//! the Arduino, the negotiation is repeated once the firmware announces that
+//! it is ready with `super::frame::BOOT_BANNER`. The banner received later
+//! means that the Arduino was reset unexpectedly and the device is re-opened
+//! as if it was lost. State set by some commands is restored after the device
+//! is re-opened, see `Message::set_restore()`.
+//!
 //! See [protocol documentation](https://irro.cz/serial_protocol.html).
 
-use log::debug;
-use serialport::{self, DataBits, FlowControl, Parity, SerialPort, SerialPortSettings, StopBits};
-use std::collections::VecDeque;
-use std::io::prelude::*;
-use std::io::ErrorKind;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::thread;
-use std::time::Duration;
+use super::capture::{Capture, Direction};
+use super::cmd::ArduinoCommand;
+use super::frame::{self, BannerDetector, Protocol, ResponseDecoder, ResponseFrame};
+use super::transport::{Device, Transport};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::marker::PhantomData;
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 /// Size of Arduino serial port buffer. See [Arduino
 /// Docs](https://www.arduino.cc/en/Reference/SoftwareSerial).
 pub const ARDUINO_BUFFER_SIZE: usize = 64;
-// see: https://www.arduino.cc/en/Serial/Begin
-const SETTINGS: SerialPortSettings = SerialPortSettings {
-    baud_rate: 115_200,
-    data_bits: DataBits::Eight,
-    parity: Parity::None,
-    stop_bits: StopBits::One,
-    flow_control: FlowControl::None,
-    timeout: Duration::from_millis(1000),
-};
+/// Delay before the first attempt to re-open a lost serial device. The delay
+/// is doubled after each unsuccessful attempt up to `RECONNECT_MAX_DELAY`.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+/// Number of consecutive unsuccessful attempts to re-open the serial device
+/// after which the connection is reported as `State::Failed`.
+const RECONNECT_ATTEMPTS: u32 = 10;
+/// Time in which Arduino has to respond to a message unless a different
+/// timeout is given to `Message::with_timeout()`. The time is measured since
+/// the message is written to the serial port.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time after a message timeout after which its response is considered lost.
+/// Arduino doesn't drop messages so it means that a message or a response
+/// got corrupted. This makes protocol version 1 communication out of sync.
+const STALE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time in which Arduino has to respond to [protocol
+/// negotiation](https://irro.cz/serial_protocol.html#protocol-negotiation).
+/// It includes time the Arduino bootloader runs after the port is opened.
+const NEGOTIATE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Minimum time to wait for a negotiation response after the Arduino booted
+/// before the negotiation is sent again.
+const BOOT_SETTLE: Duration = Duration::from_millis(100);
+
+/// State of the connection to the Arduino.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// The serial device is open and messages are being exchanged.
+    Connected,
+    /// The serial device was lost and the connection is trying to re-open it.
+    /// All messages are failed in this state.
+    Reconnecting,
+    /// The serial device couldn't be re-opened in `RECONNECT_ATTEMPTS`
+    /// attempts. The connection keeps trying to re-open it in
+    /// `RECONNECT_MAX_DELAY` intervals and all messages are failed in the
+    /// meantime.
+    Failed,
+}
+
+/// A cloneable read-only view of the connection state, see `State`.
+#[derive(Clone)]
+pub struct StateWatch(Arc<Mutex<State>>);
+
+impl StateWatch {
+    pub(crate) fn new() -> Self {
+        StateWatch(Arc::new(Mutex::new(State::Connected)))
+    }
+
+    /// Return current state of the connection.
+    pub fn get(&self) -> State {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, state: State) {
+        *self.0.lock().unwrap() = state;
+    }
+}
+
+/// Reason why a message wasn't responded by Arduino.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResponseError {
+    /// Arduino didn't respond in time.
+    Timeout,
+    /// Connection to Arduino was lost before the message was responded.
+    Disconnected,
+    /// Arduino sent data which don't conform to the protocol or to the
+    /// command.
+    ProtocolError(String),
+    /// Arduino firmware doesn't know the command, e.g. older firmware. The
+    /// value describes what is not supported.
+    Unsupported(String),
+    /// The message was replaced by a newer message with the same command
+    /// before it was sent, see `Message::set_coalescing()`.
+    Superseded,
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseError::Timeout => write!(f, "Arduino didn't respond in time."),
+            ResponseError::Disconnected => write!(f, "Connection to Arduino was lost."),
+            ResponseError::ProtocolError(reason) => {
+                write!(f, "Invalid response from Arduino: {}", reason)
+            }
+            ResponseError::Unsupported(what) => {
+                write!(f, "Arduino firmware doesn't support {}.", what)
+            }
+            ResponseError::Superseded => write!(f, "Command was replaced by a newer command."),
+        }
+    }
+}
+
+impl error::Error for ResponseError {}
+
+/// Result of a message delivered via the response channel, see
+/// `Message::new()`.
+pub type Response = Result<Vec<u8>, ResponseError>;
+
+/// Block until the response of a message is delivered via the `receiver`.
+/// The connection guarantees that each message is eventually resolved, see
+/// `Message::new()`.
+pub fn receive(receiver: &Receiver<Response>) -> Response {
+    // The channel is closed without a response only if the connection thread
+    // is gone.
+    receiver.recv().unwrap_or(Err(ResponseError::Disconnected))
+}
+
+/// Priority of a message. Waiting messages with higher priority are sent
+/// before messages with lower priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Normal,
+    /// Priority of commands which have to be executed as soon as possible,
+    /// e.g. commands stopping the motors.
+    High,
+}
+
+/// Handle of a connection to the Arduino, see `Connection::init_from_device()`.
+/// The handle is cheap to clone and the connection is closed once all its
+/// clones are dropped.
+#[derive(Clone)]
+pub struct Handle {
+    sender: Sender<Message>,
+    state: StateWatch,
+}
+
+impl Handle {
+    pub(crate) fn new(sender: Sender<Message>, state: StateWatch) -> Self {
+        Handle { sender, state }
+    }
+
+    /// Return current state of the connection.
+    pub fn state(&self) -> State {
+        self.state.get()
+    }
+
+    /// Send a command to the Arduino and block until it is responded.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the command is not responded or if the
+    /// response cannot be decoded.
+    pub fn execute<C: ArduinoCommand>(&self, command: &C) -> Result<C::Response, ResponseError> {
+        self.send(command)?.wait()
+    }
+
+    /// Send a command to the Arduino without waiting for its response.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the connection is closed.
+    pub fn send<C: ArduinoCommand>(&self, command: &C) -> Result<Pending<C>, ResponseError> {
+        let (mut message, receiver) = Message::new(C::ID, command.payload());
+        command.configure(&mut message);
+        self.send_message(message)?;
+        Ok(Pending {
+            receiver,
+            command: PhantomData,
+        })
+    }
+
+    /// Send a raw message to the Arduino, see `Message::new()`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the connection is closed.
+    pub fn send_message(&self, message: Message) -> Result<(), ResponseError> {
+        debug!("Going to send command {:#06x} to Arduino.", message.command);
+        self.sender
+            .send(message)
+            .map_err(|_| ResponseError::Disconnected)
+    }
+}
+
+/// Response to a command which may be not yet received, see `Handle::send()`.
+pub struct Pending<C: ArduinoCommand> {
+    receiver: Receiver<Response>,
+    command: PhantomData<fn() -> C>,
+}
+
+impl<C: ArduinoCommand> Pending<C> {
+    /// Block until the command is responded and decode the response.
+    pub fn wait(self) -> Result<C::Response, ResponseError> {
+        let result = receive(&self.receiver).and_then(C::decode);
+        if let Err(ref error) = result {
+            debug!("Command {:#06x} failed: {}", C::ID, error);
+        }
+        result
+    }
+}
 
 /// This struct represent an individual command which could be send to Arduino.
 pub struct Message {
     command: u16,
     payload: Vec<u8>,
-    /// Arduino response (possibly an empty Vec) will be send via this Sender.
-    sender: Sender<Vec<u8>>,
+    timeout: Duration,
+    priority: Priority,
+    /// See `set_coalescing()`.
+    coalescing: bool,
+    /// See `set_restore()`.
+    restore: Option<Vec<u8>>,
+    /// See `set_restore_unsent()`.
+    restore_unsent: bool,
+    /// Arduino response (possibly an empty Vec) or an error will be send via
+    /// this Sender.
+    sender: Sender<Response>,
 }
 
 impl Message {
@@ -40,7 +250,10 @@ impl Message {
     /// `Receiver` via which a command response will be delivered.
     ///
     /// Command response is send via the channel to the `Receiver` once it is
-    /// obtained from the Arduino. No other data is ever send via the channel.
+    /// obtained from the Arduino. An error is send instead if the response
+    /// isn't obtained within `DEFAULT_TIMEOUT`, if the connection to Arduino
+    /// is lost or if Arduino doesn't follow the protocol. Exactly one
+    /// `Response` is send via the channel.
     ///
     /// # Arguments
     ///
@@ -48,7 +261,8 @@ impl Message {
     ///
     /// * `payload` - command payload. Note that the total message size cannot
     ///    be larger than `ARDUINO_BUFFER_SIZE`. Note that message size
-    ///    includes 2 bytes for command number and 2 bytes payload length.
+    ///    includes up to `super::frame::MAX_COMMAND_OVERHEAD` bytes of frame
+    ///    headers (e.g. command number and payload length).
     ///
     /// # Panics
     ///
@@ -61,10 +275,23 @@ impl Message {
     /// // Message which turns on LED 0.
     /// let (message, receiver) = Message::new(0, vec![128]);
     /// ```
-    pub fn new(command: u16, payload: Vec<u8>) -> (Self, Receiver<Vec<u8>>) {
-        // This size includes the two bytes for command and two bytes for
-        // payload length.
-        let bytes_len = 4 + payload.len();
+    pub fn new(command: u16, payload: Vec<u8>) -> (Self, Receiver<Response>) {
+        Self::with_timeout(command, payload, DEFAULT_TIMEOUT)
+    }
+
+    /// Construct a new message with a custom response timeout. See `new()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - time in which Arduino has to respond to the message,
+    ///   measured since the message is written to the serial port.
+    pub fn with_timeout(
+        command: u16,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> (Self, Receiver<Response>) {
+        // This size includes frame headers of any protocol version.
+        let bytes_len = frame::MAX_COMMAND_OVERHEAD + payload.len();
         if bytes_len > ARDUINO_BUFFER_SIZE {
             panic!(
                 "Overall message size of {} bytes is larger than Arduino buffer.",
@@ -76,54 +303,137 @@ impl Message {
         let message = Message {
             command,
             payload,
+            timeout,
+            priority: Priority::Normal,
+            coalescing: false,
+            restore: None,
+            restore_unsent: false,
             sender,
         };
         (message, receiver)
     }
 
-    /// Return message size including headers.
-    fn len(&self) -> usize {
-        self.payload.len() + 4
+    /// Set priority of the message, it is `Priority::Normal` by default.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Make the message replace all not yet sent coalescing messages with the
+    /// same command. Replaced messages are resolved with
+    /// `ResponseError::Superseded`. This is useful for commands which set a
+    /// state, e.g. motor power, where only the latest command is relevant.
+    pub fn set_coalescing(&mut self, coalescing: bool) {
+        self.coalescing = coalescing;
+    }
+
+    /// Make the connection send the same command with this payload each
+    /// time the device is re-opened, e.g. after an Arduino reset, so that
+    /// the state set by the command is restored. Only the restore payload of
+    /// the most recently sent message with the same command is kept.
+    pub fn set_restore(&mut self, payload: Vec<u8>) {
+        self.restore = Some(payload);
+    }
+
+    /// Keep the restore payload (see `set_restore()`) even if the message
+    /// fails before it is sent, e.g. while the device is being re-opened, so
+    /// that the state is set once the device is open. By default only the
+    /// restore payload of a sent message is kept.
+    pub fn set_restore_unsent(&mut self, restore_unsent: bool) {
+        self.restore_unsent = restore_unsent;
+    }
+
+    /// Return message size including frame headers of the given protocol.
+    fn len(&self, protocol: Protocol) -> usize {
+        self.payload.len() + protocol.command_overhead()
+    }
+
+    pub(crate) fn destructure(self) -> (u16, Vec<u8>, Duration, Sender<Response>) {
+        (self.command, self.payload, self.timeout, self.sender)
     }
 
-    fn destructure(self) -> (u16, Vec<u8>, Sender<Vec<u8>>) {
-        (self.command, self.payload, self.sender)
+    /// Resolve the message without sending it to Arduino.
+    fn fail(self, error: ResponseError) {
+        // Errors aren't handled because data receiving isn't enforced.
+        self.sender.send(Err(error)).unwrap_or(());
     }
 }
 
 /// This struct corresponds to a command sent to Arduino for which a reply
 /// hasn't been processed yet
 struct InAir {
-    /// Size of the data send to Arduino with this command. This is used to
-    /// avoid Arduino buffer overflow.
+    /// Sequence id of the command frame. It is used only in protocol version 2
+    /// and later.
+    seq: u8,
+    /// Size of the command frame send to Arduino, including frame headers.
+    /// This is used to avoid Arduino buffer overflow.
     len: usize,
-    /// This sender should be used to deliver command response.
-    sender: Sender<Vec<u8>>,
+    /// Time until which the response has to be received.
+    deadline: Instant,
+    /// This sender should be used to deliver command response. It is `None`
+    /// once the message is resolved with a timeout error.
+    sender: Option<Sender<Response>>,
 }
 
 impl InAir {
-    fn new(len: usize, sender: Sender<Vec<u8>>) -> Self {
-        InAir { len, sender }
+    fn new(seq: u8, len: usize, deadline: Instant, sender: Sender<Response>) -> Self {
+        InAir {
+            seq,
+            len,
+            deadline,
+            sender: Some(sender),
+        }
     }
 
     fn len(&self) -> usize {
         self.len
     }
 
-    /// Send response of the command to the client.
-    fn respond(self, data: Vec<u8>) {
-        // Errors aren't handled because data receiving isn't enforced.
-        self.sender.send(data).unwrap_or(());
+    /// Resolve the message with a timeout error if its deadline has passed.
+    /// The message is kept in air because its response may still arrive.
+    fn expire(&mut self, now: Instant) {
+        if self.deadline <= now {
+            if let Some(sender) = self.sender.take() {
+                sender.send(Err(ResponseError::Timeout)).unwrap_or(());
+            }
+        }
+    }
+
+    /// Return true if the message was resolved with a timeout error long
+    /// enough ago to consider the response lost.
+    fn is_stale(&self, now: Instant) -> bool {
+        self.deadline + STALE_TIMEOUT <= now
+    }
+
+    /// Return time at which the message times out or, if it already timed
+    /// out, time at which it becomes stale.
+    fn next_deadline(&self) -> Instant {
+        if self.sender.is_some() {
+            self.deadline
+        } else {
+            self.deadline + STALE_TIMEOUT
+        }
+    }
+
+    /// Send response of the command to the client unless the message was
+    /// already resolved with a timeout error.
+    fn respond(self, response: Response) {
+        if let Some(sender) = self.sender {
+            // Errors aren't handled because data receiving isn't enforced.
+            sender.send(response).unwrap_or(());
+        }
     }
 }
 
 /// A de-queue of not yet responded messages.
 struct InAirQueue {
     /// A queue of not yet responded messages. New messages are appended to
-    /// front and resolved messages are popped from back.
+    /// back. With protocol version 1, resolved messages are popped from front.
+    /// With later protocol versions, resolved messages are removed by their
+    /// sequence id.
     queue: VecDeque<InAir>,
-    /// Total (i.e. sum) size of all not responded messages. This bookkeeping
-    /// is necessary to avoid Arduino serial buffer overflow.
+    /// Total (i.e. sum) size of all not responded command frames. This
+    /// bookkeeping is necessary to avoid Arduino serial buffer overflow in
+    /// protocol versions without credits, see `Credits`.
     size: usize,
 }
 
@@ -136,149 +446,963 @@ impl InAirQueue {
         }
     }
 
-    fn push(&mut self, payload_len: usize, sender: Sender<Vec<u8>>) {
-        self.queue.push_back(InAir::new(payload_len, sender));
-        self.size += payload_len;
+    /// Append a message whose command frame of `len` bytes was just written
+    /// to the Arduino.
+    fn push(&mut self, seq: u8, len: usize, timeout: Duration, sender: Sender<Response>) {
+        let deadline = Instant::now() + timeout;
+        self.queue.push_back(InAir::new(seq, len, deadline, sender));
+        self.size += len;
     }
 
-    fn respond(&mut self, response: Vec<u8>) {
-        let in_air = self
-            .queue
-            .pop_front()
-            .expect("There is no message waiting for a response.");
+    /// Deliver the response to the message it belongs to, id est to the
+    /// message with the same sequence id or to the oldest message if the
+    /// response has no sequence id. Responses with an unknown sequence id are
+    /// dropped, they belong to messages which were considered lost.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if there is no message waiting for a response
+    /// without sequence id.
+    fn respond(&mut self, response: ResponseFrame) -> io::Result<()> {
+        let index = match response.seq {
+            Some(seq) => match self.queue.iter().position(|in_air| in_air.seq == seq) {
+                Some(index) => index,
+                None => {
+                    warn!("Dropping a response with unknown sequence id {}.", seq);
+                    return Ok(());
+                }
+            },
+            None if self.queue.is_empty() => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Received a response while no message is waiting for it.",
+                ));
+            }
+            None => 0,
+        };
+
+        let in_air = self.queue.remove(index).unwrap();
         self.size -= in_air.len();
-        in_air.respond(response);
+        in_air.respond(Ok(response.payload));
+        Ok(())
+    }
+
+    /// Resolve all messages whose deadline has passed with a timeout error.
+    fn expire(&mut self, now: Instant) {
+        for in_air in self.queue.iter_mut() {
+            in_air.expire(now);
+        }
+    }
+
+    /// Return true if the oldest message timed out more than `STALE_TIMEOUT`
+    /// ago.
+    fn has_stale(&self, now: Instant) -> bool {
+        self.queue
+            .front()
+            .map_or(false, |in_air| in_air.is_stale(now))
+    }
+
+    /// Remove all messages which timed out more than `STALE_TIMEOUT` ago.
+    /// Return number of removed messages.
+    fn remove_stale(&mut self, now: Instant) -> usize {
+        let original_len = self.queue.len();
+        let mut size = self.size;
+        self.queue.retain(|in_air| {
+            let stale = in_air.is_stale(now);
+            if stale {
+                size -= in_air.len();
+            }
+            !stale
+        });
+        self.size = size;
+        original_len - self.queue.len()
+    }
+
+    /// Return the earliest time at which a message times out or becomes stale.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.queue.iter().map(InAir::next_deadline).min()
+    }
+
+    /// Return true if a message with the sequence id is in air.
+    fn contains(&self, seq: u8) -> bool {
+        self.queue.iter().any(|in_air| in_air.seq == seq)
     }
 
     fn size(&self) -> usize {
         self.size
     }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Remove all messages from the queue and resolve them with the error.
+    fn fail(&mut self, error: &ResponseError) {
+        for in_air in self.queue.drain(..) {
+            in_air.respond(Err(error.clone()));
+        }
+        self.size = 0;
+    }
+}
+
+/// Bookkeeping of data written to the Arduino which it hasn't read from its
+/// serial buffer yet. It is used with protocol versions where the Arduino
+/// reports number of consumed bytes in each response, see
+/// `super::frame::ResponseFrame::consumed`. Unlike accounting of in air
+/// messages, this frees the buffer space as soon as the Arduino reads the
+/// data, including data of commands it dropped.
+///
+/// Both counters are modulo 2^16 and start at zero after protocol negotiation.
+struct Credits {
+    /// Number of bytes written to the Arduino.
+    sent: u16,
+    /// Number of bytes read by the Arduino as reported in the latest
+    /// response.
+    consumed: u16,
+}
+
+impl Credits {
+    fn new() -> Self {
+        Credits {
+            sent: 0,
+            consumed: 0,
+        }
+    }
+
+    /// Record data written to the Arduino.
+    fn send(&mut self, len: usize) {
+        self.sent = self.sent.wrapping_add(len as u16);
+    }
+
+    /// Update number of bytes read by the Arduino. Reports which would
+    /// increase number of pending bytes are ignored, they are either older
+    /// than the last `reset()` or claim data which weren't written yet.
+    fn consume(&mut self, consumed: u16) {
+        if self.sent.wrapping_sub(consumed) > self.sent.wrapping_sub(self.consumed) {
+            warn!(
+                "Ignoring Arduino report of {} consumed bytes, {} bytes were sent.",
+                consumed, self.sent
+            );
+        } else {
+            self.consumed = consumed;
+        }
+    }
+
+    /// Consider all written data read by the Arduino.
+    fn reset(&mut self) {
+        self.consumed = self.sent;
+    }
+
+    /// Return number of bytes written to the Arduino and not read by it yet.
+    fn pending(&self) -> usize {
+        usize::from(self.sent.wrapping_sub(self.consumed))
+    }
+}
+
+/// A queue of messages waiting to be sent. Messages are ordered by their
+/// priority and by the order in which they were pushed.
+struct WaitingQueue {
+    queue: VecDeque<Message>,
+}
+
+impl WaitingQueue {
+    /// Create an empty queue.
+    fn new() -> Self {
+        WaitingQueue {
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Append the message after all messages with the same or higher
+    /// priority. If the message is coalescing, all waiting coalescing
+    /// messages with the same command are removed and resolved with
+    /// `ResponseError::Superseded`.
+    fn push(&mut self, message: Message) {
+        if message.coalescing {
+            let command = message.command;
+            let (superseded, kept) = self
+                .queue
+                .drain(..)
+                .partition(|waiting: &Message| waiting.coalescing && waiting.command == command);
+            self.queue = kept;
+
+            for waiting in superseded {
+                debug!("Message with command {:#06x} superseded.", command);
+                waiting.fail(ResponseError::Superseded);
+            }
+        }
+
+        let index = self
+            .queue
+            .iter()
+            .position(|waiting| waiting.priority < message.priority)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(index, message);
+    }
+
+    /// Return a message back to the front of the queue.
+    fn push_front(&mut self, message: Message) {
+        self.queue.push_front(message);
+    }
+
+    fn pop_front(&mut self) -> Option<Message> {
+        self.queue.pop_front()
+    }
+}
+
+/// Event processed by the connection loop.
+enum Event {
+    /// A message to be send to the Arduino.
+    Message(Message),
+    /// All message senders were dropped.
+    Closed,
+    /// Data read by the reader with the given id.
+    Received(u64, io::Result<Vec<u8>>),
+}
+
+/// Handle of a thread which reads data from the Arduino and sends them as
+/// events to the connection loop.
+///
+/// The thread polls the port together with a wake-up socket so it sleeps
+/// until data arrive or until the handle is dropped. Dropping the handle
+/// wakes the thread up and waits for it to stop so that its handle of the
+/// port is closed before the device is re-opened.
+struct Reader {
+    wake: UnixStream,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reader {
+    fn spawn(id: u64, port: Box<dyn Transport>, events: Sender<Event>) -> io::Result<Self> {
+        let (wake, woken) = UnixStream::pair()?;
+        let thread = thread::spawn(move || Self::run(id, port, &woken, &events));
+        Ok(Reader {
+            wake,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(id: u64, mut port: Box<dyn Transport>, woken: &UnixStream, events: &Sender<Event>) {
+        let mut buf = [0; ARDUINO_BUFFER_SIZE];
+
+        loop {
+            let result = match wait_readable(&*port, woken) {
+                Ok(true) => match port.read(&mut buf) {
+                    // Serial port doesn't have an end, it means that the
+                    // device is gone.
+                    Ok(0) => Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                    Ok(len) => Ok(buf[..len].to_vec()),
+                    Err(ref err)
+                        if err.kind() == ErrorKind::TimedOut
+                            || err.kind() == ErrorKind::WouldBlock =>
+                    {
+                        continue
+                    }
+                    Err(err) => Err(err),
+                },
+                Ok(false) => return,
+                Err(err) => Err(err),
+            };
+
+            let failed = result.is_err();
+            if events.send(Event::Received(id, result)).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        // The thread is woken up by the end of the wake-up socket. An error
+        // means that the thread already stopped.
+        let _ = self.wake.shutdown(Shutdown::Both);
+        if let Some(thread) = self.thread.take() {
+            // An error means that the thread panicked, which has been already
+            // reported.
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Block until the port is readable or until the wake-up socket is woken up.
+/// Return true if the port is readable, a hang up or an error of the port
+/// counts as readable so that it is reported by the following read.
+fn wait_readable(port: &dyn Transport, woken: &UnixStream) -> io::Result<bool> {
+    let mut fds = [
+        libc::pollfd {
+            fd: port.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: woken.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+        if result >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+
+    if fds[1].revents != 0 {
+        Ok(false)
+    } else if fds[0].revents & libc::POLLNVAL != 0 {
+        Err(io::Error::from_raw_os_error(libc::EBADF))
+    } else {
+        Ok(true)
+    }
 }
 
 /// An asynchronous connecting to the Arduino.
+///
+/// The connection runs in its own thread which sleeps until a message is
+/// received, data are read from the Arduino by a reader thread or a message
+/// times out.
 pub struct Connection {
-    /// Receiver used to get commands to be send to the Arduino.
-    receiver: Receiver<Message>,
-    /// Serial port writer.
-    port: Box<SerialPort>,
+    /// Receiver of messages and data read from the Arduino.
+    events: Receiver<Event>,
+    /// Sender given to reader threads.
+    events_sender: Sender<Event>,
+    /// False once all message senders are dropped.
+    open: bool,
+    /// Arduino device, it is used to re-open the device after it is lost.
+    device: Device,
+    state: StateWatch,
+    /// Reader of the currently open device.
+    reader: Option<Reader>,
+    /// Id of the current reader. Data from readers of previously open devices
+    /// are ignored.
+    reader_id: u64,
+    /// Protocol version negotiated with the Arduino.
+    protocol: Protocol,
+    /// Sequence id of the next command frame.
+    seq: u8,
+    decoder: ResponseDecoder,
+    /// Detector of Arduino boots in the received data.
+    banner: BannerDetector,
     in_air_queue: InAirQueue,
+    credits: Credits,
+    /// Payloads of commands sent after the device is re-opened by command
+    /// id, see `Message::set_restore()`.
+    restore: BTreeMap<u16, Vec<u8>>,
     /// Buffer of messages waiting to be send.
-    waiting_messages: VecDeque<Message>,
+    waiting_messages: WaitingQueue,
+    /// Capture of all data written to and read from the device.
+    capture: Option<Capture>,
 }
 
 impl Connection {
     /// Initiate an asynchronous "connection" to the Arduino. This methods
-    /// creates a new thread and returns `Sender` through which messages can be
-    /// send to the Arduino.
+    /// creates a new thread and returns `Handle` through which commands can
+    /// be send to the Arduino and state of the connection can be observed.
     ///
     /// It is supposed that there is at most one running Connection at any
     /// given moment and that no other program interact with the Arduino.
     ///
     /// # Arguments
     ///
-    /// * `device` - serial port device, for example ```"/dev/ttyACM1"```.
-    pub fn init_from_device(device: &str) -> Result<Sender<Message>, serialport::Error> {
-        let port = serialport::open_with_settings(device, &SETTINGS)?;
-        Ok(Self::initiate(port))
+    /// * `device` - serial port device, for example ```"/dev/ttyACM1"```, or
+    ///   other device address, see `super::transport::Device`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the device address is invalid or if the device
+    /// cannot be opened. Failures after the
+    /// connection is initiated are handled by re-opening the device, see
+    /// `State`.
+    pub fn init_from_device(device: &str) -> io::Result<Handle> {
+        let device: Device = device.parse()?;
+        let port = device.open()?;
+        Ok(Self::initiate(device, port, None))
+    }
+
+    /// Same as `init_from_device()` but all data written to and read from the
+    /// device are recorded to the capture.
+    pub fn init_with_capture(device: &str, capture: Capture) -> io::Result<Handle> {
+        let device: Device = device.parse()?;
+        let port = device.open()?;
+        Ok(Self::initiate(device, port, Some(capture)))
     }
 
-    fn initiate(port: Box<SerialPort>) -> Sender<Message> {
+    /// Check that Irro's Arduino firmware runs on the other side of the port
+    /// by negotiating protocol version with it. Return the negotiated
+    /// protocol version.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the negotiation fails, see `negotiate()`.
+    pub(crate) fn probe(device: Device, port: &mut dyn Transport) -> io::Result<Protocol> {
+        let mut connection = Self::new(device, None);
+        connection.negotiate(port)?;
+        Ok(connection.protocol)
+    }
+
+    fn initiate(device: Device, port: Box<dyn Transport>, capture: Option<Capture>) -> Handle {
+        let connection = Self::new(device, capture);
         let (sender, receiver) = mpsc::channel();
-        thread::spawn(move || {
-            let connection = Connection {
-                receiver,
-                port,
-                in_air_queue: InAirQueue::new(),
-                waiting_messages: VecDeque::new(),
-            };
-            connection.start();
-        });
-        sender
+        let forward_sender = connection.events_sender.clone();
+        thread::spawn(move || Self::forward_messages(receiver, forward_sender));
+
+        let state = connection.state.clone();
+        thread::spawn(move || connection.start(port));
+        Handle::new(sender, state)
     }
 
-    /// Start the communication loop which sends messages to Arduino and
-    /// retrieve and delivers response. This method never returns.
-    fn start(mut self) -> ! {
+    fn new(device: Device, capture: Option<Capture>) -> Self {
+        let (events_sender, events) = mpsc::channel();
+        Connection {
+            events,
+            events_sender,
+            open: true,
+            device,
+            state: StateWatch::new(),
+            reader: None,
+            reader_id: 0,
+            protocol: Protocol::V1,
+            seq: 1,
+            decoder: ResponseDecoder::new(Protocol::V1),
+            banner: BannerDetector::new(),
+            in_air_queue: InAirQueue::new(),
+            credits: Credits::new(),
+            restore: BTreeMap::new(),
+            waiting_messages: WaitingQueue::new(),
+            capture,
+        }
+    }
+
+    /// Pass all messages to the connection loop and notify it once all
+    /// message senders are dropped.
+    fn forward_messages(receiver: Receiver<Message>, events: Sender<Event>) {
+        for message in receiver.iter() {
+            if events.send(Event::Message(message)).is_err() {
+                return;
+            }
+        }
+        // The connection loop might have already ended.
+        let _ = events.send(Event::Closed);
+    }
+
+    /// Negotiate protocol version and start the communication loop which
+    /// sends messages to Arduino and retrieve and delivers response. The loop
+    /// ends once all message senders are dropped and all messages are
+    /// resolved.
+    fn start(mut self, port: Box<dyn Transport>) {
+        let mut result = self.connect(port);
+
         loop {
-            self.process_responses();
-            self.process_messages();
+            let mut port = match result {
+                Ok(port) => port,
+                Err((port, err)) => {
+                    warn!("Connection to Arduino at {} lost: {}", self.device, err);
+                    let error = match err.kind() {
+                        ErrorKind::InvalidData => ResponseError::ProtocolError(err.to_string()),
+                        _ => ResponseError::Disconnected,
+                    };
+
+                    // The port has to be closed before it is re-opened, the
+                    // device might be still present.
+                    self.reader = None;
+                    drop(port);
+                    match self.reconnect(&error) {
+                        Some(port) => {
+                            self.restore();
+                            port
+                        }
+                        None => break,
+                    }
+                }
+            };
+
+            if !self.open && self.waiting_messages.is_empty() && self.in_air_queue.is_empty() {
+                break;
+            }
+
+            // Waiting messages are sent first, e.g. messages restoring state
+            // after the device was re-opened.
+            result = match self
+                .process_messages(&mut *port)
+                .and_then(|_| self.process_events())
+            {
+                Ok(()) => Ok(port),
+                Err(err) => Err((port, err)),
+            };
         }
+
+        debug!("Connection to Arduino at {} closed.", self.device);
     }
 
-    fn process_messages(&mut self) {
-        self.waiting_messages.extend(self.receiver.try_iter());
+    /// Negotiate protocol version and start a reader of the port.
+    ///
+    /// # Errors
+    ///
+    /// The port is returned together with the error if the negotiation fails.
+    fn connect(
+        &mut self,
+        mut port: Box<dyn Transport>,
+    ) -> Result<Box<dyn Transport>, (Box<dyn Transport>, io::Error)> {
+        let reader_id = self.reader_id + 1;
+        let events = self.events_sender.clone();
+        let result = self
+            .negotiate(&mut *port)
+            .and_then(|_| port.try_clone())
+            .and_then(|reader_port| Reader::spawn(reader_id, reader_port, events));
+        match result {
+            Ok(reader) => {
+                self.reader_id = reader_id;
+                self.reader = Some(reader);
+                Ok(port)
+            }
+            Err(err) => Err((port, err)),
+        }
+    }
 
-        debug!(
-            "Arduino serial: {} enqueued messages, {} in air bytes",
-            self.waiting_messages.len(),
-            self.in_air_queue.size()
+    /// Negotiate protocol version with the Arduino. The negotiation is done
+    /// with protocol version 1 frames, Arduino firmware which doesn't support
+    /// the negotiation responds with an empty response which means protocol
+    /// version 1.
+    ///
+    /// The negotiation command may be lost if the Arduino is just booting, it
+    /// is sent again if the Arduino announces that it booted and doesn't
+    /// respond shortly afterwards. Data received before the boot are ignored.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the Arduino doesn't respond in time or if it
+    /// selects an unsupported protocol version.
+    fn negotiate(&mut self, port: &mut dyn Transport) -> io::Result<()> {
+        self.protocol = Protocol::V1;
+        self.decoder = ResponseDecoder::new(Protocol::V1);
+        self.banner = BannerDetector::new();
+        self.credits = Credits::new();
+
+        let payload = [Protocol::LATEST.version()];
+        let command = frame::encode_command(Protocol::V1, 0, frame::NEGOTIATE_COMMAND, &payload);
+        self.write(port, &command)?;
+
+        let mut deadline = Instant::now() + NEGOTIATE_TIMEOUT;
+        // Time at which the command is sent again, it is set once the
+        // Arduino boots.
+        let mut resend_at = None;
+        let mut buf = [0; ARDUINO_BUFFER_SIZE];
+        let response = loop {
+            match self.decoder.next() {
+                Ok(Some(response)) => break response.payload,
+                Ok(None) => (),
+                Err(err) => {
+                    debug!("Dropping data received from booting Arduino: {}", err);
+                    self.decoder.clear();
+                }
+            }
+
+            let now = Instant::now();
+            if resend_at.map_or(false, |resend_at| now >= resend_at) {
+                debug!("Sending protocol negotiation again after Arduino booted.");
+                self.write(port, &command)?;
+                resend_at = None;
+                deadline = now + NEGOTIATE_TIMEOUT;
+            } else if now >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "Arduino didn't respond to protocol negotiation.",
+                ));
+            }
+
+            match port.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(ErrorKind::UnexpectedEof)),
+                Ok(len) => {
+                    if self.receive(&buf[..len]) {
+                        info!("Arduino at {} booted.", self.device);
+                        resend_at = Some(Instant::now() + BOOT_SETTLE);
+                    }
+                }
+                Err(ref err) if err.kind() == ErrorKind::TimedOut => (),
+                Err(err) => return Err(err),
+            }
+        };
+
+        let protocol = match response[..] {
+            [] => Some(Protocol::V1),
+            [version] => Protocol::from_version(version),
+            _ => None,
+        };
+        let protocol = match protocol {
+            Some(protocol) => protocol,
+            None => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid protocol negotiation response {:?}.", response),
+                ));
+            }
+        };
+
+        info!(
+            "Using serial protocol version {} with Arduino at {}.",
+            protocol.version(),
+            self.device
         );
+        self.protocol = protocol;
+        self.decoder.set_protocol(protocol);
+        Ok(())
+    }
 
-        let mut remaining = ARDUINO_BUFFER_SIZE - self.in_air_queue.size();
-        let mut to_send = Vec::new();
+    /// Fail all in air and waiting messages, re-open the serial port and
+    /// negotiate protocol version. This method blocks until the port is
+    /// successfully re-opened or until all message senders are dropped, `None`
+    /// is returned in the latter case.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - error with which in air messages are resolved. Messages
+    ///   which weren't send yet are resolved with
+    ///   `ResponseError::Disconnected`.
+    fn reconnect(&mut self, error: &ResponseError) -> Option<Box<dyn Transport>> {
+        self.state.set(State::Reconnecting);
+        self.fail_messages(error);
+
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut attempts = 0;
+
+        loop {
+            if !self.reject_messages_for(delay) {
+                return None;
+            }
+
+            let result = match self.device.open() {
+                Ok(port) => self.connect(port).map_err(|(_, err)| err),
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(port) => {
+                    info!("Connection to Arduino at {} re-opened.", self.device);
+                    self.state.set(State::Connected);
+                    return Some(port);
+                }
+                Err(err) => {
+                    attempts += 1;
+                    debug!(
+                        "Attempt {} to re-open Arduino at {} failed: {}",
+                        attempts, self.device, err
+                    );
+
+                    if attempts == RECONNECT_ATTEMPTS {
+                        warn!(
+                            "Arduino at {} couldn't be re-opened in {} attempts, \
+                             going to keep trying.",
+                            self.device, attempts
+                        );
+                        self.state.set(State::Failed);
+                    }
+                }
+            }
+
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
 
+    /// Queue commands which restore state of the Arduino, see
+    /// `Message::set_restore()`. The commands are sent before other waiting
+    /// messages.
+    fn restore(&mut self) {
+        for (&command, payload) in &self.restore {
+            debug!("Restoring Arduino state with command {:#06x}.", command);
+            // Nobody waits for the response.
+            let (mut message, _) = Message::new(command, payload.clone());
+            message.set_priority(Priority::High);
+            self.waiting_messages.push(message);
+        }
+    }
+
+    /// Fail all in air, waiting and newly received messages.
+    fn fail_messages(&mut self, error: &ResponseError) {
+        self.in_air_queue.fail(error);
         while let Some(message) = self.waiting_messages.pop_front() {
-            assert!(message.len() <= ARDUINO_BUFFER_SIZE);
+            self.fail_unsent(message);
+        }
+        while let Ok(event) = self.events.try_recv() {
+            self.reject_event(event);
+        }
+    }
+
+    /// Fail all messages received during the given time period. This method
+    /// blocks for the whole period unless all message senders are dropped, in
+    /// which case it returns false immediately.
+    fn reject_messages_for(&mut self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+
+        while self.open {
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+
+            match self.events.recv_timeout(deadline - now) {
+                Ok(event) => self.reject_event(event),
+                Err(RecvTimeoutError::Timeout) => return true,
+                // The connection keeps a sender of its own.
+                Err(RecvTimeoutError::Disconnected) => unreachable!(),
+            }
+        }
+
+        false
+    }
+
+    /// Resolve a message which wasn't sent with `ResponseError::Disconnected`.
+    /// Its restore payload is kept if the message asks for it, see
+    /// `Message::set_restore_unsent()`.
+    fn fail_unsent(&mut self, mut message: Message) {
+        if message.restore_unsent {
+            if let Some(restore) = message.restore.take() {
+                self.restore.insert(message.command, restore);
+            }
+        }
+        message.fail(ResponseError::Disconnected);
+    }
+
+    /// Fail the message of a message event and ignore data events.
+    fn reject_event(&mut self, event: Event) {
+        match event {
+            Event::Message(message) => self.fail_unsent(message),
+            Event::Closed => self.open = false,
+            Event::Received(..) => (),
+        }
+    }
+
+    /// Wait for the next event or for the next message timeout and process
+    /// all available events. Messages which weren't responded in time are
+    /// resolved with a timeout error.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the device couldn't be read from, which
+    /// includes reaching end of file, or if the received data doesn't conform
+    /// to the protocol.
+    fn process_events(&mut self) -> io::Result<()> {
+        let event = match self.in_air_queue.next_deadline() {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline > now {
+                    self.events.recv_timeout(deadline - now).ok()
+                } else {
+                    None
+                }
+            }
+            // The connection keeps a sender of its own.
+            None => Some(self.events.recv().unwrap()),
+        };
+
+        if let Some(event) = event {
+            self.process_event(event)?;
+            while let Ok(event) = self.events.try_recv() {
+                self.process_event(event)?;
+            }
+        }
+
+        self.process_timeouts()
+    }
+
+    fn process_event(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Message(message) => self.waiting_messages.push(message),
+            Event::Closed => self.open = false,
+            Event::Received(id, result) => {
+                if id == self.reader_id {
+                    if self.receive(&result?) {
+                        return Err(io::Error::new(
+                            ErrorKind::ConnectionReset,
+                            "Arduino was reset.",
+                        ));
+                    }
+                    while let Some(response) = self.decoder.next()? {
+                        if let Some(consumed) = response.consumed {
+                            self.credits.consume(consumed);
+                        }
+                        self.in_air_queue.respond(response)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve messages which weren't responded in time.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if a message wasn't responded long after its
+    /// timeout in protocol version 1, the communication is out of sync in
+    /// such a case.
+    fn process_timeouts(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        self.in_air_queue.expire(now);
+
+        if self.protocol == Protocol::V1 {
+            if self.in_air_queue.has_stale(now) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Arduino didn't respond to a message long after its timeout.",
+                ));
+            }
+        } else {
+            let lost = self.in_air_queue.remove_stale(now);
+            if lost > 0 {
+                warn!("Responses to {} messages were lost.", lost);
+                // Credits of dropped commands are reported only with
+                // subsequent responses. The Arduino has surely read all the
+                // data by now.
+                if self.in_air_queue.is_empty() {
+                    self.credits.reset();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send as many waiting messages as fit the Arduino serial buffer.
+    fn process_messages(&mut self, port: &mut dyn Transport) -> io::Result<()> {
+        let pending = if self.protocol.has_credits() {
+            self.credits.pending()
+        } else {
+            self.in_air_queue.size()
+        };
+        let mut remaining = ARDUINO_BUFFER_SIZE.saturating_sub(pending);
+        let mut to_send = Vec::new();
+
+        while let Some(mut message) = self.waiting_messages.pop_front() {
+            let len = message.len(self.protocol);
+            assert!(len <= ARDUINO_BUFFER_SIZE);
 
-            if remaining < message.len() {
+            if remaining < len {
                 self.waiting_messages.push_front(message);
                 break;
             }
 
-            remaining -= message.len();
+            remaining -= len;
 
-            let (command, payload, sender) = message.destructure();
-            let payload_len = payload.len();
-            assert!(payload_len < 256 * 256);
-
-            self.in_air_queue.push(payload_len, sender);
+            let restore = message.restore.take();
+            let (command, payload, timeout, sender) = message.destructure();
+            if let Some(restore) = restore {
+                self.restore.insert(command, restore);
+            }
+            assert!(payload.len() < 256 * 256);
 
-            to_send.push((command >> 8) as u8);
-            to_send.push((command & 0xff) as u8);
-            to_send.push((payload_len >> 8) as u8);
-            to_send.push((payload_len & 0xff) as u8);
-            to_send.extend(payload);
+            let seq = self.next_seq();
+            self.in_air_queue.push(seq, len, timeout, sender);
+            to_send.extend(frame::encode_command(self.protocol, seq, command, &payload));
         }
 
         if !to_send.is_empty() {
-            debug!("Going to send {} bytes to Arduino.", to_send.len());
+            debug!(
+                "Going to send {} bytes to Arduino, {} messages are waiting.",
+                to_send.len(),
+                self.waiting_messages.len()
+            );
+            self.write(port, &to_send)?;
+            self.credits.send(to_send.len());
+        }
 
-            if let Err(err) = self.port.write_all(&to_send[..]) {
-                panic!("Error while writing data to Arduino: {}", err);
+        Ok(())
+    }
+
+    /// Return sequence id for a new command frame. Sequence ids 1 to 255 are
+    /// used cyclically skipping ids of messages which are still in air.
+    fn next_seq(&mut self) -> u8 {
+        loop {
+            let seq = self.seq;
+            self.seq = if seq == 255 { 1 } else { seq + 1 };
+            if !self.in_air_queue.contains(seq) {
+                return seq;
             }
         }
     }
 
-    /// Read and process all available responses from the Arduino. Responses
-    /// are immediately send to clients via each message channel. The function
-    /// returns number of processed responses.
-    fn process_responses(&mut self) {
-        let mut buf = Vec::new();
-        if let Err(err) = self.port.read_to_end(&mut buf) {
-            if err.kind() != ErrorKind::TimedOut {
-                panic!("Error while reading data from Arduino: {}", err);
+    /// Pass data received from the Arduino to the decoder. Return true if
+    /// the Arduino booted, data received before the boot are dropped in such
+    /// a case.
+    fn receive(&mut self, data: &[u8]) -> bool {
+        debug!("Received {} bytes of data from Arduino.", data.len());
+        self.capture(Direction::Received, data);
+        match self.banner.find(data) {
+            Some(end) => {
+                self.decoder.clear();
+                self.decoder.push(&data[end..]);
+                true
+            }
+            None => {
+                self.decoder.push(data);
+                false
             }
         }
+    }
 
-        debug!("Received {} bytes of data from Arduino.", buf.len());
+    /// Write all data to the Arduino.
+    fn write(&mut self, port: &mut dyn Transport, data: &[u8]) -> io::Result<()> {
+        self.capture(Direction::Sent, data);
+        port.write_all(data)
+    }
 
-        let mut offset = 0;
-        while offset < buf.len() {
-            let payload_len: usize = ((buf[offset] as usize) << 8) | (buf[offset + 1] as usize);
-            offset += 2;
-            // Unfortunately there is no trivial way how to split the Vec into
-            // multiple owned Vec-s. See
-            // https://github.com/rust-lang/rust/issues/40708
-            self.in_air_queue
-                .respond(buf[offset..(offset + payload_len)].to_vec());
-            offset += payload_len;
+    /// Record data to the capture if capturing is enabled. Capturing is
+    /// disabled after the first failure.
+    fn capture(&mut self, direction: Direction, data: &[u8]) {
+        if let Some(ref mut capture) = self.capture {
+            if let Err(err) = capture.record(direction, data) {
+                warn!("Serial communication capture disabled: {}", err);
+                self.capture = None;
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::transport::SerialTransport;
     use super::*;
+    use serialport::posix::TTYPort;
+    use serialport::SerialPort;
+    use std::io::prelude::*;
+
+    /// Answer protocol negotiation on the Arduino side of the serial port.
+    fn negotiate(master: &mut TTYPort, response: &[u8]) {
+        let mut buf = [0; 5];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [2u8, 0, 0, 1, 3]);
+        master
+            .write_all(&frame::encode_response(Protocol::V1, 0, 0, response))
+            .unwrap();
+    }
+
+    /// Initiate a connection over the slave side of a pseudo terminal pair.
+    fn initiate(slave: TTYPort) -> (Sender<Message>, StateWatch) {
+        let device = Device::Pty(slave.name().unwrap().into());
+        let handle = Connection::initiate(device, Box::new(SerialTransport(slave)), None);
+        (handle.sender, handle.state)
+    }
+
+    fn v1_frame(payload: Vec<u8>) -> ResponseFrame {
+        ResponseFrame {
+            seq: None,
+            consumed: None,
+            payload,
+        }
+    }
 
     #[test]
     fn test_in_air_queue() {
@@ -286,40 +1410,182 @@ mod tests {
         assert_eq!(queue.size(), 0);
 
         let (sender, receiver_a) = mpsc::channel();
-        queue.push(2, sender);
+        queue.push(1, 2, DEFAULT_TIMEOUT, sender);
         assert_eq!(queue.size(), 2);
 
         let (sender, receiver_b) = mpsc::channel();
-        queue.push(3, sender);
+        queue.push(2, 3, DEFAULT_TIMEOUT, sender);
         assert_eq!(queue.size(), 5);
 
         let (sender, receiver_c) = mpsc::channel();
-        queue.push(4, sender);
+        queue.push(3, 4, DEFAULT_TIMEOUT, sender);
         assert_eq!(queue.size(), 9);
 
-        queue.respond(vec![1, 2]);
+        queue.respond(v1_frame(vec![1, 2])).unwrap();
         assert_eq!(queue.size(), 7);
         let response = receiver_a.recv().unwrap();
-        assert_eq!(response, vec![1, 2]);
+        assert_eq!(response, Ok(vec![1, 2]));
 
-        queue.respond(vec![3, 4]);
+        queue.respond(v1_frame(vec![3, 4])).unwrap();
         assert_eq!(queue.size(), 4);
         let response = receiver_b.recv().unwrap();
-        assert_eq!(response, vec![3, 4]);
+        assert_eq!(response, Ok(vec![3, 4]));
 
-        queue.respond(vec![5, 6]);
+        queue.respond(v1_frame(vec![5, 6])).unwrap();
         assert_eq!(queue.size(), 0);
         let response = receiver_c.recv().unwrap();
-        assert_eq!(response, vec![5, 6]);
+        assert_eq!(response, Ok(vec![5, 6]));
+
+        assert!(queue.respond(v1_frame(vec![7])).is_err());
     }
 
     #[test]
-    fn test_connection() {
-        use serialport::posix::TTYPort;
+    fn test_in_air_queue_seq() {
+        let mut queue = InAirQueue::new();
+
+        let (sender, receiver_a) = mpsc::channel();
+        queue.push(1, 2, DEFAULT_TIMEOUT, sender);
+        let (sender, receiver_b) = mpsc::channel();
+        queue.push(2, 3, DEFAULT_TIMEOUT, sender);
+        assert!(queue.contains(1));
+        assert!(!queue.contains(3));
+
+        let frame = ResponseFrame {
+            seq: Some(2),
+            consumed: None,
+            payload: vec![8],
+        };
+        queue.respond(frame).unwrap();
+        assert_eq!(queue.size(), 2);
+        assert_eq!(receiver_b.try_recv(), Ok(Ok(vec![8])));
+        assert!(receiver_a.try_recv().is_err());
+
+        // Responses with unknown sequence id are dropped.
+        let frame = ResponseFrame {
+            seq: Some(7),
+            consumed: None,
+            payload: vec![9],
+        };
+        queue.respond(frame).unwrap();
+        assert_eq!(queue.size(), 2);
+        assert!(receiver_a.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_in_air_queue_expire() {
+        let mut queue = InAirQueue::new();
+        let now = Instant::now();
+
+        let (sender, receiver_a) = mpsc::channel();
+        queue.push(1, 2, Duration::from_secs(1), sender);
+        let (sender, receiver_b) = mpsc::channel();
+        queue.push(2, 3, Duration::from_secs(3), sender);
+
+        queue.expire(now);
+        assert!(receiver_a.try_recv().is_err());
+
+        queue.expire(now + Duration::from_secs(2));
+        assert_eq!(receiver_a.try_recv(), Ok(Err(ResponseError::Timeout)));
+        assert!(receiver_b.try_recv().is_err());
 
+        // The late response still belongs to the first message.
+        queue.respond(v1_frame(vec![1])).unwrap();
+        assert_eq!(queue.size(), 3);
+        assert!(receiver_a.try_recv().is_err());
+
+        queue.expire(now + Duration::from_secs(4));
+        assert_eq!(receiver_b.try_recv(), Ok(Err(ResponseError::Timeout)));
+        assert!(!queue.has_stale(now + Duration::from_secs(4)));
+        assert!(queue.has_stale(now + Duration::from_secs(9)));
+        assert_eq!(queue.remove_stale(now + Duration::from_secs(9)), 1);
+        assert!(queue.is_empty());
+
+        let (sender, _receiver) = mpsc::channel();
+        queue.push(3, 4, Duration::from_secs(1), sender);
+
+        queue.fail(&ResponseError::Disconnected);
+        assert_eq!(queue.size(), 0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_credits() {
+        let mut credits = Credits::new();
+        assert_eq!(credits.pending(), 0);
+
+        credits.send(20);
+        credits.send(30);
+        assert_eq!(credits.pending(), 50);
+        credits.consume(20);
+        assert_eq!(credits.pending(), 30);
+
+        // Older and impossible reports are ignored.
+        credits.consume(10);
+        assert_eq!(credits.pending(), 30);
+        credits.consume(60);
+        assert_eq!(credits.pending(), 30);
+
+        credits.send(65_500);
+        assert_eq!(credits.pending(), 65_530);
+        credits.consume(65_530);
+        assert_eq!(credits.pending(), 20);
+        credits.reset();
+        assert_eq!(credits.pending(), 0);
+    }
+
+    #[test]
+    fn test_waiting_queue() {
+        let mut queue = WaitingQueue::new();
+
+        let (message_a, _) = Message::new(0x0000, vec![1]);
+        queue.push(message_a);
+        let (mut message_b, receiver_b) = Message::new(0x0100, vec![1]);
+        message_b.set_coalescing(true);
+        queue.push(message_b);
+        let (message_c, _) = Message::new(0x0000, vec![2]);
+        queue.push(message_c);
+        assert_eq!(queue.len(), 3);
+
+        let (mut message_d, _) = Message::new(0x0100, vec![2]);
+        message_d.set_coalescing(true);
+        queue.push(message_d);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(receiver_b.try_recv(), Ok(Err(ResponseError::Superseded)));
+
+        let (mut message_e, _) = Message::new(0x0100, vec![0]);
+        message_e.set_priority(Priority::High);
+        queue.push(message_e);
+        let (mut message_f, _) = Message::new(0x0001, vec![]);
+        message_f.set_priority(Priority::High);
+        queue.push(message_f);
+
+        let order: Vec<(u16, Vec<u8>)> = (0..5)
+            .map(|_| {
+                let message = queue.pop_front().unwrap();
+                (message.command, message.payload)
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (0x0100, vec![0]),
+                (0x0001, vec![]),
+                (0x0000, vec![1]),
+                (0x0000, vec![2]),
+                (0x0100, vec![2]),
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_connection() {
         let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
 
-        let sender = Connection::initiate(Box::new(slave));
+        let (sender, state) = initiate(slave);
+        negotiate(&mut master, &[]);
+        assert_eq!(state.get(), State::Connected);
         let (message_a, receiver_a) = Message::new(23, vec![6, 2, 1]);
         let (message_b, receiver_b) = Message::new(25, vec![10, 20, 30, 40]);
 
@@ -338,9 +1604,351 @@ mod tests {
         master.write(&[0u8, 2, 255, 128]).unwrap();
 
         let recv = receiver_b.recv().unwrap();
-        assert_eq!(recv, vec![255, 128]);
+        assert_eq!(recv, Ok(vec![255, 128]));
 
         let recv = receiver_a.recv().unwrap();
-        assert_eq!(recv, vec![10, 9, 8, 7, 6]);
+        assert_eq!(recv, Ok(vec![10, 9, 8, 7, 6]));
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, state) = initiate(slave);
+        negotiate(&mut master, &[]);
+        let (message_a, receiver_a) = Message::new(23, vec![6, 2, 1]);
+        sender.send(message_a).unwrap();
+
+        let mut buf = [0; 7];
+        master.read_exact(&mut buf).unwrap();
+        drop(master);
+
+        let result = receiver_a.recv_timeout(Duration::from_secs(5));
+        assert_eq!(result, Ok(Err(ResponseError::Disconnected)));
+        assert_ne!(state.get(), State::Connected);
+
+        let (message_b, receiver_b) = Message::new(25, vec![]);
+        sender.send(message_b).unwrap();
+        let result = receiver_b.recv_timeout(Duration::from_secs(5));
+        assert_eq!(result, Ok(Err(ResponseError::Disconnected)));
+    }
+
+    #[test]
+    fn test_connection_byte_by_byte() {
+        let (mut master, mut slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+        // Make the connection finish reading before each subsequent byte is
+        // written.
+        slave.set_timeout(Duration::from_millis(10)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[]);
+        let (message_a, receiver_a) = Message::new(23, vec![6, 2, 1]);
+        let (message_b, receiver_b) = Message::new(25, vec![]);
+        sender.send(message_a).unwrap();
+        sender.send(message_b).unwrap();
+
+        let mut buf = [0; 11];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0u8, 23, 0, 3, 6, 2, 1, 0, 25, 0, 0]);
+
+        for &byte in &[0u8, 3, 10, 9, 8, 0, 0] {
+            master.write_all(&[byte]).unwrap();
+            thread::sleep(Duration::from_millis(30));
+        }
+
+        let recv = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![10, 9, 8]));
+        let recv = receiver_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![]));
+    }
+
+    #[test]
+    fn test_connection_impossible_length() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[]);
+        let (message_a, receiver_a) = Message::new(23, vec![]);
+        sender.send(message_a).unwrap();
+
+        let mut buf = [0; 4];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&[255, 255]).unwrap();
+
+        let result = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        match result {
+            Err(ResponseError::ProtocolError(_)) => (),
+            _ => panic!("Unexpected response: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_connection_timeout() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[]);
+        let (message_a, receiver_a) = Message::with_timeout(23, vec![], Duration::from_millis(50));
+        sender.send(message_a).unwrap();
+
+        let mut buf = [0; 4];
+        master.read_exact(&mut buf).unwrap();
+
+        let result = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result, Err(ResponseError::Timeout));
+
+        let (message_b, receiver_b) = Message::new(25, vec![]);
+        sender.send(message_b).unwrap();
+        master.read_exact(&mut buf).unwrap();
+
+        // Late response to the first message is dropped.
+        master.write_all(&[0u8, 1, 42, 0, 1, 43]).unwrap();
+        let result = receiver_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result, Ok(vec![43]));
+    }
+
+    #[test]
+    fn test_connection_boot() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        // The first negotiation is lost while the Arduino boots.
+        let mut buf = [0; 5];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&[0x13, 0x37]).unwrap();
+        master.write_all(&frame::BOOT_BANNER).unwrap();
+        negotiate(&mut master, &[]);
+
+        let (message, receiver) = Message::new(23, vec![]);
+        sender.send(message).unwrap();
+        let mut buf = [0; 4];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&[0, 1, 42]).unwrap();
+        let recv = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![42]));
+    }
+
+    #[test]
+    fn test_connection_reset() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+        // Reads from the master fail while no handle of the slave is open,
+        // unlike a serial device which stays present while it is re-opened.
+        let _slave = slave.try_clone().unwrap();
+
+        let (sender, state) = initiate(slave);
+        negotiate(&mut master, &[]);
+
+        let (mut message_a, receiver_a) = Message::new(0x0000, vec![7]);
+        message_a.set_restore(vec![7]);
+        sender.send(message_a).unwrap();
+        let mut buf = [0; 5];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&[0, 0]).unwrap();
+        let recv = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![]));
+
+        let (message_b, receiver_b) = Message::new(23, vec![]);
+        sender.send(message_b).unwrap();
+        let mut buf = [0; 4];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&frame::BOOT_BANNER).unwrap();
+        let recv = receiver_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Err(ResponseError::Disconnected));
+
+        // The device is re-opened and the state is restored.
+        negotiate(&mut master, &[]);
+        let mut buf = [0; 5];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0u8, 0, 0, 1, 7]);
+        assert_eq!(state.get(), State::Connected);
+    }
+
+    #[test]
+    fn test_connection_restore_unsent() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+        let _slave = slave.try_clone().unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[]);
+
+        let (message_a, receiver_a) = Message::new(23, vec![]);
+        sender.send(message_a).unwrap();
+        let mut buf = [0; 4];
+        master.read_exact(&mut buf).unwrap();
+        master.write_all(&frame::BOOT_BANNER).unwrap();
+        let recv = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Err(ResponseError::Disconnected));
+
+        // Messages are rejected while the device is being re-opened, only
+        // the restore payload which is asked for is kept.
+        let (mut message_b, receiver_b) = Message::new(0x0001, vec![1]);
+        message_b.set_restore(vec![1]);
+        message_b.set_restore_unsent(true);
+        sender.send(message_b).unwrap();
+        let (mut message_c, receiver_c) = Message::new(0x0002, vec![2]);
+        message_c.set_restore(vec![2]);
+        sender.send(message_c).unwrap();
+        let recv = receiver_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Err(ResponseError::Disconnected));
+        let recv = receiver_c.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Err(ResponseError::Disconnected));
+
+        negotiate(&mut master, &[]);
+        let mut buf = [0; 5];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0u8, 1, 0, 1, 1]);
+        master.set_timeout(Duration::from_millis(200)).unwrap();
+        assert!(master.read_exact(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_connection_v2() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[2]);
+
+        let (message_a, receiver_a) = Message::new(23, vec![6, 2, 1]);
+        let (message_b, receiver_b) = Message::new(25, vec![]);
+        sender.send(message_a).unwrap();
+        sender.send(message_b).unwrap();
+
+        let expected_a = frame::encode_command(Protocol::V2, 1, 23, &[6, 2, 1]);
+        let mut buf = vec![0; expected_a.len()];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected_a);
+
+        let expected_b = frame::encode_command(Protocol::V2, 2, 25, &[]);
+        let mut buf = vec![0; expected_b.len()];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, expected_b);
+
+        // Responses are matched by sequence id, corrupted data are skipped.
+        master.write_all(&[1, 2, 3]).unwrap();
+        let mut corrupted = frame::encode_response(Protocol::V2, 1, 0, &[7]);
+        corrupted[4] = 8;
+        master.write_all(&corrupted).unwrap();
+        master
+            .write_all(&frame::encode_response(Protocol::V2, 2, 0, &[9]))
+            .unwrap();
+        master
+            .write_all(&frame::encode_response(Protocol::V2, 1, 0, &[7]))
+            .unwrap();
+
+        let recv = receiver_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![9]));
+        let recv = receiver_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![7]));
+    }
+
+    #[test]
+    fn test_connection_v3() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[3]);
+
+        let frame_len = 1 + Protocol::V3.command_overhead();
+        let fitting = ARDUINO_BUFFER_SIZE / frame_len;
+        let receivers: Vec<Receiver<Response>> = (0..(2 * fitting))
+            .map(|_| {
+                let (message, receiver) = Message::new(0x0000, vec![1]);
+                sender.send(message).unwrap();
+                receiver
+            })
+            .collect();
+
+        // Only messages which fit the Arduino buffer are sent.
+        let mut buf = vec![0; fitting * frame_len];
+        master.read_exact(&mut buf).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        master.set_timeout(Duration::from_millis(10)).unwrap();
+        assert!(master.read(&mut [0; 1]).is_err());
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        // The Arduino read all data before it responded to the first message,
+        // the rest of the messages fits the buffer.
+        let consumed = buf.len() as u16;
+        master
+            .write_all(&frame::encode_response(Protocol::V3, buf[1], consumed, &[]))
+            .unwrap();
+        let recv = receivers[0].recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(recv, Ok(vec![]));
+
+        let mut buf = vec![0; fitting * frame_len];
+        master.read_exact(&mut buf).unwrap();
+        assert_eq!(
+            buf[..frame_len],
+            frame::encode_command(Protocol::V3, buf[1], 0x0000, &[1])[..]
+        );
+    }
+
+    #[test]
+    fn test_reader() {
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        let (events_sender, events) = mpsc::channel();
+        let reader = Reader::spawn(7, Box::new(SerialTransport(slave)), events_sender).unwrap();
+
+        master.write_all(&[1, 2]).unwrap();
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Event::Received(7, Ok(data)) => assert_eq!(data, vec![1, 2]),
+            _ => panic!("Data were expected."),
+        }
+
+        // The idle thread is woken up and stopped at once.
+        let start = Instant::now();
+        drop(reader);
+        assert!(start.elapsed() < Duration::from_millis(10));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_connection_latency() {
+        const ROUNDS: u32 = 10;
+
+        let (mut master, slave) = TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+
+        let (sender, _) = initiate(slave);
+        negotiate(&mut master, &[2]);
+
+        let mut sending = Duration::from_secs(0);
+        let mut receiving = Duration::from_secs(0);
+        for _ in 0..ROUNDS {
+            // Let the connection go idle.
+            thread::sleep(Duration::from_millis(20));
+
+            let start = Instant::now();
+            let (message, receiver) = Message::new(23, vec![]);
+            sender.send(message).unwrap();
+            let mut buf = [0; 8];
+            master.read_exact(&mut buf).unwrap();
+            sending += start.elapsed();
+
+            let start = Instant::now();
+            master
+                .write_all(&frame::encode_response(Protocol::V2, buf[1], 0, &[]))
+                .unwrap();
+            let recv = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(recv, Ok(vec![]));
+            receiving += start.elapsed();
+        }
+
+        // Neither a command nor a response waits for a timeout.
+        assert!(sending / ROUNDS < Duration::from_millis(3), "{:?}", sending);
+        assert!(
+            receiving / ROUNDS < Duration::from_millis(3),
+            "{:?}",
+            receiving
+        );
     }
 }
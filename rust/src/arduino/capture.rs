@@ -0,0 +1,417 @@
+//! Capture of the serial communication with the Arduino.
+//!
+//! A capture file is a text file with one record per line. Each record
+//! consists of time since the capture start in seconds, direction (`>` for
+//! data sent to the Arduino, `<` for data received from the Arduino) and the
+//! data in hexadecimal, e.g. `0.001250 > 0200000102`. Lines starting with `#`
+//! are comments.
+//!
+//! Records contain raw data as they were written to or read from the serial
+//! port, use `Decoder` to get commands and responses.
+
+use super::frame::{
+    self, BannerDetector, CommandDecoder, CommandFrame, Protocol, ResponseDecoder, ResponseFrame,
+};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// First line of each capture file.
+const HEADER: &str = "# irro serial capture";
+
+/// Direction of captured data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Data sent to the Arduino.
+    Sent,
+    /// Data received from the Arduino.
+    Received,
+}
+
+/// A chunk of captured data.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the capture start.
+    pub time: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Writer of a capture file.
+pub struct Capture {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl Capture {
+    /// Create a new capture file, an existing file is truncated.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file couldn't be created.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        Ok(Capture {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Append a record with current time to the capture file.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file couldn't be written to.
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let time = self.start.elapsed();
+        let direction = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        writeln!(
+            self.writer,
+            "{} {} {}",
+            format_time(time),
+            direction,
+            to_hex(data)
+        )?;
+        // The capture is mostly useful when something goes wrong, don't lose
+        // the last records.
+        self.writer.flush()
+    }
+}
+
+/// Read all records from a capture file.
+///
+/// # Errors
+///
+/// An error is returned if the file couldn't be read or if it isn't a valid
+/// capture file.
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = parse_record(&line).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid capture record on line {}.", number + 1),
+            )
+        })?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut parts = line.split_whitespace();
+
+    let mut time = parts.next()?.splitn(2, '.');
+    let secs: u64 = time.next()?.parse().ok()?;
+    let micros: u32 = time.next()?.parse().ok()?;
+
+    let direction = match parts.next()? {
+        ">" => Direction::Sent,
+        "<" => Direction::Received,
+        _ => return None,
+    };
+    let data = from_hex(parts.next().unwrap_or(""))?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(Record {
+        time: Duration::from_secs(secs) + Duration::from_micros(u64::from(micros)),
+        direction,
+        data,
+    })
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..(i + 2)], 16).ok())
+        .collect()
+}
+
+/// A command or a response decoded from a capture.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    Command(Duration, CommandFrame),
+    Response(Duration, ResponseFrame),
+    /// Arduino announced that it booted, see `super::frame::BOOT_BANNER`.
+    Boot(Duration),
+    /// Received data which couldn't be decoded or other failure to receive a
+    /// response.
+    Error(Duration, String),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Command(time, command) => write!(
+                f,
+                "{:>12} > {} {}",
+                format_time(*time),
+                format_seq(command.seq),
+                describe(command.command, &command.payload)
+            ),
+            Event::Response(time, response) => write!(
+                f,
+                "{:>12} < {} response [{}]",
+                format_time(*time),
+                format_seq(response.seq),
+                to_hex(&response.payload)
+            ),
+            Event::Boot(time) => write!(f, "{:>12} < Arduino booted", format_time(*time)),
+            Event::Error(time, reason) => {
+                write!(f, "{:>12} < error: {}", format_time(*time), reason)
+            }
+        }
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    format!("{}.{:06}", time.as_secs(), time.subsec_micros())
+}
+
+fn format_seq(seq: Option<u8>) -> String {
+    match seq {
+        Some(seq) => format!("#{:<3}", seq),
+        None => "    ".to_owned(),
+    }
+}
+
+/// Return human readable description of a command.
+pub fn describe(command: u16, payload: &[u8]) -> String {
+    match (command, payload) {
+        (0x0000, [mask]) => format!("set LED mask {:08b}", mask),
+        (0x0001, []) => "read LED mask".to_owned(),
+        (0x0300, []) => "read system info".to_owned(),
+        (0x0400, []) => "read battery".to_owned(),
+        (0x0500, []) => "read encoders".to_owned(),
+        (0x0600, []) => "read ranges".to_owned(),
+        (0x0700, []) => "read IMU".to_owned(),
+        (0x0100, [l0, l1, r0, r1]) => format!(
+            "set motor power ratio left {} right {}",
+            i16::from_be_bytes([*l0, *l1]),
+            i16::from_be_bytes([*r0, *r1])
+        ),
+        (0x0101, [1]) => "latch emergency stop".to_owned(),
+        (0x0101, [0]) => "release emergency stop".to_owned(),
+        (frame::NEGOTIATE_COMMAND, [version]) => {
+            format!("negotiate protocol version {}", version)
+        }
+        _ => format!("command 0x{:04x} [{}]", command, to_hex(payload)),
+    }
+}
+
+/// Decoder of captured data. It follows protocol negotiation and Arduino
+/// boots in the capture.
+pub struct Decoder {
+    commands: CommandDecoder,
+    responses: ResponseDecoder,
+    banner: BannerDetector,
+    negotiating: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            commands: CommandDecoder::new(Protocol::V1),
+            responses: ResponseDecoder::new(Protocol::V1),
+            banner: BannerDetector::new(),
+            negotiating: false,
+        }
+    }
+
+    /// Decode a record and return all commands or responses completed by it.
+    pub fn push(&mut self, record: &Record) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        match record.direction {
+            Direction::Sent => {
+                self.commands.push(&record.data);
+                loop {
+                    match self.commands.next() {
+                        Ok(Some(command)) => {
+                            if command.command == frame::NEGOTIATE_COMMAND && command.seq.is_none()
+                            {
+                                // Negotiation is always done from scratch, e.g.
+                                // after the serial port is re-opened.
+                                self.negotiating = true;
+                                self.responses = ResponseDecoder::new(Protocol::V1);
+                            }
+                            events.push(Event::Command(record.time, command));
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            events.push(Event::Error(record.time, err.to_string()));
+                            self.commands.clear();
+                            break;
+                        }
+                    }
+                }
+            }
+            Direction::Received => {
+                let data = match self.banner.find(&record.data) {
+                    Some(end) => {
+                        // Arduino uses protocol version 1 after boot.
+                        events.push(Event::Boot(record.time));
+                        self.negotiating = false;
+                        self.commands = CommandDecoder::new(Protocol::V1);
+                        self.responses = ResponseDecoder::new(Protocol::V1);
+                        &record.data[end..]
+                    }
+                    None => &record.data[..],
+                };
+
+                self.responses.push(data);
+                loop {
+                    match self.responses.next() {
+                        Ok(Some(response)) => {
+                            if self.negotiating {
+                                self.negotiated(&response.payload);
+                            }
+                            events.push(Event::Response(record.time, response));
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            events.push(Event::Error(record.time, err.to_string()));
+                            self.responses.clear();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    fn negotiated(&mut self, response: &[u8]) {
+        self.negotiating = false;
+        let protocol = match response {
+            [version] => Protocol::from_version(*version).unwrap_or(Protocol::V1),
+            _ => Protocol::V1,
+        };
+        self.commands.set_protocol(protocol);
+        self.responses.set_protocol(protocol);
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_capture() {
+        let path = env::temp_dir().join(format!("irro-capture-{}.txt", process::id()));
+
+        let mut capture = Capture::create(&path).unwrap();
+        capture.record(Direction::Sent, &[2, 0, 0, 1, 2]).unwrap();
+        capture.record(Direction::Received, &[]).unwrap();
+        capture.record(Direction::Received, &[0, 1, 2]).unwrap();
+        drop(capture);
+
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].data, vec![2, 0, 0, 1, 2]);
+        assert_eq!(records[1].data, vec![]);
+        assert_eq!(records[2].direction, Direction::Received);
+        assert_eq!(records[2].data, vec![0, 1, 2]);
+        assert!(records[0].time <= records[2].time);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        assert_eq!(
+            parse_record("1.000250 < 00ff"),
+            Some(Record {
+                time: Duration::from_micros(1_000_250),
+                direction: Direction::Received,
+                data: vec![0, 255],
+            })
+        );
+        assert_eq!(parse_record("1.000250 = 00"), None);
+        assert_eq!(parse_record("1.000250 > 0"), None);
+        assert_eq!(parse_record("x > 00"), None);
+    }
+
+    #[test]
+    fn test_decoder() {
+        let time = Duration::from_secs(0);
+        let record = |direction, data: Vec<u8>| Record {
+            time,
+            direction,
+            data,
+        };
+
+        let mut decoder = Decoder::new();
+        let events = decoder.push(&record(Direction::Sent, vec![2, 0, 0, 1, 2]));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].to_string(),
+            "    0.000000 >      negotiate protocol version 2"
+        );
+        decoder.push(&record(Direction::Received, vec![0, 1, 2]));
+
+        let mut data = frame::encode_command(Protocol::V2, 1, 0x0000, &[0b1010_0000]);
+        data.extend(frame::encode_command(Protocol::V2, 2, 0x0001, &[]));
+        let events = decoder.push(&record(Direction::Sent, data));
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[0].to_string(),
+            "    0.000000 > #1   set LED mask 10100000"
+        );
+        assert_eq!(events[1].to_string(), "    0.000000 > #2   read LED mask");
+
+        let data = frame::encode_response(Protocol::V2, 2, 0, &[0b1010_0000]);
+        let events = decoder.push(&record(Direction::Received, data));
+        assert_eq!(events[0].to_string(), "    0.000000 < #2   response [a0]");
+
+        let mut data = frame::BOOT_BANNER.to_vec();
+        data.extend(&[0, 0]);
+        let events = decoder.push(&record(Direction::Received, data));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].to_string(), "    0.000000 < Arduino booted");
+        assert_eq!(events[1].to_string(), "    0.000000 <      response []");
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            describe(0x0100, &[0x7f, 0xff, 0xdf, 0xff]),
+            "set motor power ratio left 32767 right -8193"
+        );
+        assert_eq!(describe(0x0100, &[1]), "command 0x0100 [01]");
+        assert_eq!(describe(0x0101, &[1]), "latch emergency stop");
+        assert_eq!(describe(0x0300, &[]), "read system info");
+    }
+}
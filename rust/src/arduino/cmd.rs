@@ -1,13 +1,65 @@
 //! Implementation of individual commands for Arduino. Please see
 //! [documentation of the commands](https://irro.cz/serial_protocol.html#serial-commands)
+//!
+//! Commands are executed with `super::binary::Handle::execute()`.
+
+use super::binary::{Message, ResponseError};
+
+/// A command which could be executed by Arduino.
+pub trait ArduinoCommand {
+    /// Value the response payload is decoded to.
+    type Response;
+
+    /// Command ID, i.e. command group in the most significant byte and
+    /// command within the group in the least significant byte.
+    const ID: u16;
+
+    /// Encode the command into payload of the message.
+    fn payload(&self) -> Vec<u8>;
+
+    /// Decode response payload of the command.
+    ///
+    /// # Errors
+    ///
+    /// `ResponseError::Unsupported` is returned if the firmware doesn't know
+    /// the command and `ResponseError::ProtocolError` is returned if the
+    /// payload is not a valid response to the command.
+    fn decode(response: Vec<u8>) -> Result<Self::Response, ResponseError>;
+
+    /// Adjust the message before it is sent, e.g. its priority. The message
+    /// is sent as is by default.
+    fn configure(&self, _message: &mut Message) {}
+}
+
+/// Return an error if a response payload is empty. Firmware which doesn't
+/// know a command responds with empty payload.
+fn check_supported(response: &[u8], what: &str) -> Result<(), ResponseError> {
+    if response.is_empty() {
+        Err(ResponseError::Unsupported(what.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Return an error if a response payload doesn't have the expected length.
+fn check_len(response: &[u8], expected: usize, what: &str) -> Result<(), ResponseError> {
+    if response.len() == expected {
+        Ok(())
+    } else {
+        Err(ResponseError::ProtocolError(format!(
+            "Expected {} byte(s) with {}, got {} bytes.",
+            expected,
+            what,
+            response.len()
+        )))
+    }
+}
 
 pub mod led {
     //! Implementation of [LED](https://irro.cz/hw.html#hw-leds) commands.
 
-    use super::super::binary::Message;
-    use log::debug;
-    use std::sync::mpsc::Sender;
-    use std::time::Duration;
+    use super::super::binary::{Message, ResponseError};
+    use super::{check_len, ArduinoCommand};
 
     /// Bit mask of which LEDs are turned on/off. LED 0 is mapped to the most
     /// significant bit.
@@ -37,42 +89,42 @@ pub mod led {
             }
             LedMask(mask)
         }
+    }
 
-        /// Obtain current LED setup from Arduino.
-        ///
-        /// # Arguments
-        ///
-        /// * `sender` - message sender channel
-        ///
-        /// # Panics
-        ///
-        /// This method panics if command response is not retrieved from
-        /// Arduino within 10 seconds or if the retrieved data are incorrect.
-        pub fn read(sender: &Sender<Message>) -> Self {
-            let (message, receiver) = Message::new(0x0001, vec![]);
-            sender.send(message).unwrap();
-            let masks = receiver
-                .recv_timeout(Duration::from_secs(10))
-                .expect("LED mask not received from Arduino");
-
-            if masks.len() != 1 {
-                panic!("Expected 1 byte with LED mask, got {} bytes.", masks.len());
-            }
+    /// Command Arduino turn on/off LEDs with this mask. The mask is set again
+    /// after the Arduino is reset.
+    impl ArduinoCommand for LedMask {
+        type Response = ();
+        const ID: u16 = 0x0000;
 
-            Self(masks[0])
+        fn payload(&self) -> Vec<u8> {
+            vec![self.0]
         }
 
-        /// Command Arduino turn on/off LEDs with this mask.
-        ///
-        /// # Arguments
-        ///
-        /// * `sender` - sender as returned from
-        ///   `super::binary::Connection::new()`.
-        pub fn send(&self, sender: &Sender<Message>) {
-            debug!("Going to send LED command to Arduino: {}", self.0);
+        fn decode(_response: Vec<u8>) -> Result<(), ResponseError> {
             // There is no interesting response.
-            let (message, _) = Message::new(0x0000, vec![self.0]);
-            sender.send(message).unwrap();
+            Ok(())
+        }
+
+        fn configure(&self, message: &mut Message) {
+            message.set_restore(self.payload());
+        }
+    }
+
+    /// Obtain current LED setup from Arduino.
+    pub struct ReadLedMask;
+
+    impl ArduinoCommand for ReadLedMask {
+        type Response = LedMask;
+        const ID: u16 = 0x0001;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<LedMask, ResponseError> {
+            check_len(&response, 1, "LED mask")?;
+            Ok(LedMask(response[0]))
         }
     }
 
@@ -97,7 +149,7 @@ pub mod led {
 
             let test = MessageTestBuilder::new().start();
             let pr = LedMask::from_bools(vec![true, false, true]);
-            pr.send(test.sender());
+            test.handle().execute(&pr).unwrap();
             test.test(0x0000, vec![160]);
         }
 
@@ -108,7 +160,7 @@ pub mod led {
             let test = MessageTestBuilder::new()
                 .response(vec![0b0100_0001])
                 .start();
-            let leds: Vec<bool> = LedMask::read(test.sender()).into();
+            let leds: Vec<bool> = test.handle().execute(&ReadLedMask).unwrap().into();
             test.test(0x0001, vec![]);
 
             assert_eq!(
@@ -116,17 +168,26 @@ pub mod led {
                 vec![false, true, false, false, false, false, false, true]
             );
         }
+
+        #[test]
+        fn test_read_invalid() {
+            use super::super::tests::MessageTestBuilder;
+
+            let test = MessageTestBuilder::new().response(vec![1, 2]).start();
+            match test.handle().execute(&ReadLedMask) {
+                Err(ResponseError::ProtocolError(_)) => (),
+                _ => panic!("Invalid response must be rejected."),
+            }
+        }
     }
 }
 
 pub mod motor {
     //! Implementation of motor commands.
 
-    use super::super::binary::Message;
+    use super::super::binary::{Message, Priority, ResponseError};
+    use super::{check_len, check_supported, ArduinoCommand};
     use std::i16;
-    use std::sync::mpsc::Sender;
-
-    const PREFIX: u16 = 0x0100;
 
     /// Power ratio of Irro's left and right motor.
     pub struct MotorPowerRatio {
@@ -154,19 +215,9 @@ pub mod motor {
             MotorPowerRatio { left, right }
         }
 
-        /// Command Arduino to set motor power ratio to this.
-        ///
-        /// # Arguments
-        ///
-        /// * `sender` - sender as returned from `super::binary::Connection::new()`.
-        pub fn send(&self, sender: &Sender<Message>) {
-            let left_bytes = self.left.to_be_bytes();
-            let right_bytes = self.right.to_be_bytes();
-
-            let payload = vec![left_bytes[0], left_bytes[1], right_bytes[0], right_bytes[1]];
-            // There is no interesting response.
-            let (message, _) = Message::new(PREFIX, payload);
-            sender.send(message).unwrap();
+        /// Return true if both motors are stopped.
+        pub fn is_stop(&self) -> bool {
+            self.left == 0 && self.right == 0
         }
 
         /// Convert an f32 value between -1.0 and 1.0 to full range i16.
@@ -179,19 +230,107 @@ pub mod motor {
         }
     }
 
+    /// Command Arduino to set motor power ratio to this.
+    ///
+    /// The command replaces not yet sent motor power ratio commands, which
+    /// then fail with `ResponseError::Superseded`, and stop commands are sent
+    /// before other waiting commands. Motors are stopped after the Arduino is
+    /// reset.
+    impl ArduinoCommand for MotorPowerRatio {
+        type Response = ();
+        const ID: u16 = 0x0100;
+
+        fn payload(&self) -> Vec<u8> {
+            let left_bytes = self.left.to_be_bytes();
+            let right_bytes = self.right.to_be_bytes();
+            vec![left_bytes[0], left_bytes[1], right_bytes[0], right_bytes[1]]
+        }
+
+        fn decode(_response: Vec<u8>) -> Result<(), ResponseError> {
+            // There is no interesting response.
+            Ok(())
+        }
+
+        fn configure(&self, message: &mut Message) {
+            message.set_coalescing(true);
+            message.set_restore(vec![0; 4]);
+            if self.is_stop() {
+                message.set_priority(Priority::High);
+            }
+        }
+    }
+
+    /// Command Arduino to latch or release its emergency stop. Arduino stops
+    /// the motors and refuses to power them while the emergency stop is
+    /// latched. The response is true if the emergency stop is latched.
+    ///
+    /// The command is sent before other waiting commands and the latch is
+    /// restored after the Arduino is reset. A latch which couldn't be sent,
+    /// e.g. because Arduino is disconnected, is set once it is connected.
+    pub struct EmergencyStop {
+        latched: bool,
+    }
+
+    impl EmergencyStop {
+        pub fn new(latched: bool) -> Self {
+            EmergencyStop { latched }
+        }
+    }
+
+    impl ArduinoCommand for EmergencyStop {
+        type Response = bool;
+        const ID: u16 = 0x0101;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![self.latched as u8]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<bool, ResponseError> {
+            check_supported(&response, "emergency stop command")?;
+            check_len(&response, 1, "emergency stop state")?;
+            Ok(response[0] != 0)
+        }
+
+        fn configure(&self, message: &mut Message) {
+            message.set_priority(Priority::High);
+            message.set_restore(self.payload());
+            message.set_restore_unsent(self.latched);
+        }
+    }
+
     #[cfg(test)]
     mod tests {
         use super::super::tests::MessageTestBuilder;
-        use super::MotorPowerRatio;
+        use super::{EmergencyStop, MotorPowerRatio};
 
         #[test]
         fn test_send() {
             let test = MessageTestBuilder::new().start();
             let pr = MotorPowerRatio::from_floats(0.5, 0.25);
-            pr.send(test.sender());
+            test.handle().execute(&pr).unwrap();
             test.test(0x0100, vec![63, 255, 31, 255]);
         }
 
+        #[test]
+        fn test_emergency_stop() {
+            let test = MessageTestBuilder::new().response(vec![1]).start();
+            assert!(test.handle().execute(&EmergencyStop::new(true)).unwrap());
+            test.test(0x0101, vec![1]);
+
+            let test = MessageTestBuilder::new().response(vec![0]).start();
+            assert!(!test.handle().execute(&EmergencyStop::new(false)).unwrap());
+            test.test(0x0101, vec![0]);
+
+            let test = MessageTestBuilder::new().start();
+            assert!(test.handle().execute(&EmergencyStop::new(true)).is_err());
+        }
+
+        #[test]
+        fn test_is_stop() {
+            assert!(MotorPowerRatio::from_floats(0.0, -0.0).is_stop());
+            assert!(!MotorPowerRatio::from_floats(0.0, 0.1).is_stop());
+        }
+
         #[test]
         fn test_float_to_int() {
             let res: i16 = MotorPowerRatio::float_to_int(-1.0);
@@ -206,10 +345,613 @@ pub mod motor {
     }
 }
 
+pub mod system {
+    //! Implementation of system information commands.
+
+    use super::super::binary::ResponseError;
+    use super::{check_len, check_supported, ArduinoCommand};
+    use std::fmt;
+    use std::time::Duration;
+
+    /// Oldest firmware version the server works with.
+    pub const MIN_FIRMWARE_VERSION: (u8, u8, u8) = (0, 6, 0);
+
+    /// Cause of the last Arduino reset, i.e. content of the AVR MCU status
+    /// register at boot. More than one flag may be set.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct ResetCause(u8);
+
+    impl ResetCause {
+        const FLAGS: [(u8, &'static str); 4] = [
+            (0x01, "power-on"),
+            (0x02, "external"),
+            (0x04, "brown-out"),
+            (0x08, "watchdog"),
+        ];
+
+        /// Return names of all reset causes, an empty vector is returned if
+        /// the cause is unknown.
+        pub fn causes(self) -> Vec<&'static str> {
+            Self::FLAGS
+                .iter()
+                .filter(|(flag, _)| self.0 & flag > 0)
+                .map(|&(_, name)| name)
+                .collect()
+        }
+    }
+
+    /// Identification and status of the firmware running on Arduino.
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct FirmwareInfo {
+        /// Major, minor and patch firmware version.
+        pub version: (u8, u8, u8),
+        /// Protocol version used by the connection.
+        pub protocol: u8,
+        /// Latest protocol version supported by the firmware.
+        pub max_protocol: u8,
+        /// Time since the last reset of Arduino. Note that Arduino uptime
+        /// overflows after approximately 50 days.
+        pub uptime: Duration,
+        /// Free RAM in bytes.
+        pub free_ram: u16,
+        /// Cause of the last reset.
+        pub reset_cause: ResetCause,
+    }
+
+    impl FirmwareInfo {
+        /// Return true if the server works with the firmware, id est the
+        /// firmware has the same major version as `MIN_FIRMWARE_VERSION` and
+        /// is not older.
+        pub fn is_compatible(&self) -> bool {
+            self.version.0 == MIN_FIRMWARE_VERSION.0 && self.version >= MIN_FIRMWARE_VERSION
+        }
+
+        /// Return the firmware version formatted as `major.minor.patch`.
+        pub fn version_string(&self) -> String {
+            let (major, minor, patch) = self.version;
+            format!("{}.{}.{}", major, minor, patch)
+        }
+    }
+
+    impl fmt::Display for FirmwareInfo {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "firmware {} (protocol {}/{}), up {} s, {} B free RAM",
+                self.version_string(),
+                self.protocol,
+                self.max_protocol,
+                self.uptime.as_secs(),
+                self.free_ram
+            )
+        }
+    }
+
+    /// Obtain firmware identification and status from Arduino.
+    pub struct ReadFirmwareInfo;
+
+    impl ArduinoCommand for ReadFirmwareInfo {
+        type Response = FirmwareInfo;
+        const ID: u16 = 0x0300;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<FirmwareInfo, ResponseError> {
+            check_supported(&response, "system info command")?;
+            check_len(&response, 12, "system info")?;
+
+            let uptime = u32::from_be_bytes([response[5], response[6], response[7], response[8]]);
+            Ok(FirmwareInfo {
+                version: (response[0], response[1], response[2]),
+                protocol: response[3],
+                max_protocol: response[4],
+                uptime: Duration::from_millis(u64::from(uptime)),
+                free_ram: u16::from_be_bytes([response[9], response[10]]),
+                reset_cause: ResetCause(response[11]),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::tests::MessageTestBuilder;
+        use super::*;
+
+        #[test]
+        fn test_read() {
+            let test = MessageTestBuilder::new()
+                .response(vec![0, 9, 1, 3, 3, 0, 1, 0, 1, 0x03, 0x20, 0x06])
+                .start();
+            let info = test.handle().execute(&ReadFirmwareInfo).unwrap();
+            test.test(0x0300, vec![]);
+
+            assert_eq!(info.version_string(), "0.9.1");
+            assert_eq!(info.protocol, 3);
+            assert_eq!(info.max_protocol, 3);
+            assert_eq!(info.uptime, Duration::from_millis(65_537));
+            assert_eq!(info.free_ram, 800);
+            assert_eq!(info.reset_cause.causes(), vec!["external", "brown-out"]);
+            assert!(info.is_compatible());
+        }
+
+        #[test]
+        fn test_read_unsupported() {
+            let test = MessageTestBuilder::new().start();
+            match test.handle().execute(&ReadFirmwareInfo) {
+                Err(ResponseError::Unsupported(_)) => (),
+                _ => panic!("Empty response must be rejected."),
+            }
+        }
+
+        #[test]
+        fn test_is_compatible() {
+            let mut info = FirmwareInfo {
+                version: MIN_FIRMWARE_VERSION,
+                protocol: 3,
+                max_protocol: 3,
+                uptime: Duration::from_secs(1),
+                free_ram: 1000,
+                reset_cause: ResetCause(0),
+            };
+            assert!(info.is_compatible());
+            assert!(info.reset_cause.causes().is_empty());
+            let (major, minor, _) = MIN_FIRMWARE_VERSION;
+            info.version = (major, minor - 1, 9);
+            assert!(!info.is_compatible());
+            info.version = (1, 0, 0);
+            assert!(!info.is_compatible());
+        }
+    }
+}
+
+pub mod sensor {
+    //! Implementation of sensor commands.
+
+    use super::super::binary::ResponseError;
+    use super::{check_len, check_supported, ArduinoCommand};
+    use std::io::{self, ErrorKind};
+    use std::str::FromStr;
+
+    /// Raw 10-bit ADC readings of the battery voltage divider and of the
+    /// current sensor.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct RawBattery {
+        pub voltage: u16,
+        pub current: u16,
+    }
+
+    impl RawBattery {
+        /// Convert the raw readings to volts and amps.
+        pub fn convert(self, calibration: &BatteryCalibration) -> Battery {
+            Battery {
+                voltage: f32::from(self.voltage) * calibration.voltage_scale
+                    + calibration.voltage_offset,
+                current: f32::from(self.current) * calibration.current_scale
+                    + calibration.current_offset,
+            }
+        }
+    }
+
+    /// Battery voltage in volts and current drawn from the battery in amps.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Battery {
+        pub voltage: f32,
+        pub current: f32,
+    }
+
+    /// Linear conversion of raw ADC readings to volts and amps, id est
+    /// `value = raw * scale + offset`.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct BatteryCalibration {
+        pub voltage_scale: f32,
+        pub voltage_offset: f32,
+        pub current_scale: f32,
+        pub current_offset: f32,
+    }
+
+    impl Default for BatteryCalibration {
+        /// Calibration of 5V ADC reference, 1:6 voltage divider and current
+        /// sensor with 100 mV/A sensitivity centered at 2.5V.
+        fn default() -> Self {
+            let volts_per_count = 5.0 / 1023.0;
+            BatteryCalibration {
+                voltage_scale: volts_per_count * 6.0,
+                voltage_offset: 0.0,
+                current_scale: volts_per_count / 0.1,
+                current_offset: -25.0,
+            }
+        }
+    }
+
+    /// Parse the calibration from four comma separated numbers: voltage
+    /// scale, voltage offset, current scale and current offset.
+    impl FromStr for BatteryCalibration {
+        type Err = io::Error;
+
+        fn from_str(calibration: &str) -> Result<Self, Self::Err> {
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Battery calibration must be four comma separated numbers, got \"{}\".",
+                        calibration
+                    ),
+                )
+            };
+
+            let values = calibration
+                .split(',')
+                .map(|value| value.trim().parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()
+                .map_err(|_| invalid())?;
+            if values.len() != 4 || values.iter().any(|value| !value.is_finite()) {
+                return Err(invalid());
+            }
+
+            Ok(BatteryCalibration {
+                voltage_scale: values[0],
+                voltage_offset: values[1],
+                current_scale: values[2],
+                current_offset: values[3],
+            })
+        }
+    }
+
+    /// Read battery voltage and current from Arduino.
+    pub struct ReadBattery;
+
+    impl ArduinoCommand for ReadBattery {
+        type Response = RawBattery;
+        const ID: u16 = 0x0400;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<RawBattery, ResponseError> {
+            check_supported(&response, "battery readings")?;
+            check_len(&response, 4, "battery readings")?;
+            Ok(RawBattery {
+                voltage: u16::from_be_bytes([response[0], response[1]]),
+                current: u16::from_be_bytes([response[2], response[3]]),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::tests::MessageTestBuilder;
+        use super::*;
+
+        #[test]
+        fn test_read() {
+            let test = MessageTestBuilder::new()
+                .response(vec![0x02, 0xee, 0x02, 0x14])
+                .start();
+            let raw = test.handle().execute(&ReadBattery).unwrap();
+            test.test(0x0400, vec![]);
+
+            assert_eq!(
+                raw,
+                RawBattery {
+                    voltage: 750,
+                    current: 532
+                }
+            );
+        }
+
+        #[test]
+        fn test_read_unsupported() {
+            let test = MessageTestBuilder::new().start();
+            match test.handle().execute(&ReadBattery) {
+                Err(ResponseError::Unsupported(what)) => assert_eq!(what, "battery readings"),
+                _ => panic!("Empty response must be rejected."),
+            }
+        }
+
+        #[test]
+        fn test_convert() {
+            let raw = RawBattery {
+                voltage: 750,
+                current: 532,
+            };
+            let battery = raw.convert(&BatteryCalibration::default());
+            assert!((battery.voltage - 21.994).abs() < 0.001);
+            assert!((battery.current - 1.002).abs() < 0.001);
+
+            let calibration: BatteryCalibration = "0.03, -0.5, 0.05,-25".parse().unwrap();
+            let battery = raw.convert(&calibration);
+            assert!((battery.voltage - 22.0).abs() < 0.001);
+            assert!((battery.current - 1.6).abs() < 0.001);
+        }
+
+        #[test]
+        fn test_parse_calibration() {
+            assert!("1,2,3".parse::<BatteryCalibration>().is_err());
+            assert!("1,2,3,x".parse::<BatteryCalibration>().is_err());
+            assert!("1,2,3,inf".parse::<BatteryCalibration>().is_err());
+            assert_eq!(
+                "1,2,3,4".parse::<BatteryCalibration>().unwrap(),
+                BatteryCalibration {
+                    voltage_scale: 1.0,
+                    voltage_offset: 2.0,
+                    current_scale: 3.0,
+                    current_offset: 4.0,
+                }
+            );
+        }
+    }
+}
+
+pub mod encoder {
+    //! Implementation of wheel encoder commands.
+
+    use super::super::binary::ResponseError;
+    use super::{check_len, check_supported, ArduinoCommand};
+
+    /// Encoder tick counters of left and right tracks. Forward movement
+    /// increments the counters, backward movement decrements them. The
+    /// counters wrap around and they are reset to zero when Arduino resets.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct EncoderTicks {
+        pub left: i32,
+        pub right: i32,
+    }
+
+    /// Read encoder tick counters from Arduino.
+    pub struct ReadEncoders;
+
+    impl ArduinoCommand for ReadEncoders {
+        type Response = EncoderTicks;
+        const ID: u16 = 0x0500;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<EncoderTicks, ResponseError> {
+            check_supported(&response, "encoders")?;
+            check_len(&response, 8, "encoder ticks")?;
+            Ok(EncoderTicks {
+                left: i32::from_be_bytes([response[0], response[1], response[2], response[3]]),
+                right: i32::from_be_bytes([response[4], response[5], response[6], response[7]]),
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::tests::MessageTestBuilder;
+        use super::*;
+
+        #[test]
+        fn test_read() {
+            let test = MessageTestBuilder::new()
+                .response(vec![0, 0, 1, 2, 0xff, 0xff, 0xff, 0xfe])
+                .start();
+            let ticks = test.handle().execute(&ReadEncoders).unwrap();
+            test.test(0x0500, vec![]);
+            assert_eq!(
+                ticks,
+                EncoderTicks {
+                    left: 258,
+                    right: -2
+                }
+            );
+        }
+
+        #[test]
+        fn test_read_unsupported() {
+            let test = MessageTestBuilder::new().start();
+            assert!(test.handle().execute(&ReadEncoders).is_err());
+        }
+    }
+}
+
+pub mod range {
+    //! Implementation of [range sensor](https://irro.cz/hw.html#hw-range)
+    //! commands.
+
+    use super::super::binary::ResponseError;
+    use super::{check_supported, ArduinoCommand};
+
+    /// Distance reported by a sensor which received no echo.
+    const NO_ECHO: u16 = 0xffff;
+    /// Distance reported by a sensor which didn't respond at all.
+    const RANGE_ERROR: u16 = 0xfffe;
+
+    /// The last measurement of a range sensor.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Range {
+        /// Distance to an obstacle in millimeters.
+        Distance(u16),
+        /// There is nothing in range of the sensor.
+        NoEcho,
+        /// The sensor didn't respond, e.g. it is disconnected or broken.
+        Failed,
+    }
+
+    /// Read distances measured by range sensors. The response has one item
+    /// per sensor.
+    pub struct ReadRanges;
+
+    impl ArduinoCommand for ReadRanges {
+        type Response = Vec<Range>;
+        const ID: u16 = 0x0600;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<Vec<Range>, ResponseError> {
+            check_supported(&response, "range sensors")?;
+            if response.len() % 2 != 0 {
+                return Err(ResponseError::ProtocolError(format!(
+                    "Expected even number of bytes with ranges, got {} bytes.",
+                    response.len()
+                )));
+            }
+
+            Ok(response
+                .chunks(2)
+                .map(|chunk| match u16::from_be_bytes([chunk[0], chunk[1]]) {
+                    NO_ECHO => Range::NoEcho,
+                    RANGE_ERROR => Range::Failed,
+                    distance => Range::Distance(distance),
+                })
+                .collect())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::tests::MessageTestBuilder;
+        use super::*;
+
+        #[test]
+        fn test_read() {
+            let test = MessageTestBuilder::new()
+                .response(vec![0x01, 0x2c, 0xff, 0xff, 0xff, 0xfe])
+                .start();
+            let ranges = test.handle().execute(&ReadRanges).unwrap();
+            test.test(0x0600, vec![]);
+            assert_eq!(
+                ranges,
+                vec![Range::Distance(300), Range::NoEcho, Range::Failed]
+            );
+        }
+
+        #[test]
+        fn test_read_invalid() {
+            let test = MessageTestBuilder::new().start();
+            assert!(test.handle().execute(&ReadRanges).is_err());
+            let test = MessageTestBuilder::new().response(vec![1, 2, 3]).start();
+            assert!(test.handle().execute(&ReadRanges).is_err());
+        }
+    }
+}
+
+pub mod imu {
+    //! Implementation of [IMU](https://irro.cz/hw.html#hw-imu) commands.
+
+    use super::super::binary::ResponseError;
+    use super::{check_supported, ArduinoCommand};
+    use std::f32::consts::PI;
+
+    /// Acceleration in m/s^2 per raw count at the +-2 g range.
+    const ACCELERATION_SCALE: f32 = 9.806_65 / 16384.0;
+    /// Angular velocity in rad/s per raw count at the +-250 deg/s range.
+    const ROTATION_SCALE: f32 = PI / 180.0 / 131.0;
+
+    /// Raw accelerometer and gyroscope readings along X (forward), Y (left)
+    /// and Z (up) axes of the robot.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct RawImu {
+        pub acceleration: [i16; 3],
+        pub rotation: [i16; 3],
+    }
+
+    impl RawImu {
+        /// Convert the raw readings to m/s^2 and rad/s.
+        pub fn convert(self) -> Imu {
+            let convert = |raw: [i16; 3], scale: f32| {
+                [
+                    f32::from(raw[0]) * scale,
+                    f32::from(raw[1]) * scale,
+                    f32::from(raw[2]) * scale,
+                ]
+            };
+            Imu {
+                acceleration: convert(self.acceleration, ACCELERATION_SCALE),
+                angular_velocity: convert(self.rotation, ROTATION_SCALE),
+            }
+        }
+    }
+
+    /// Acceleration in m/s^2 and counter-clockwise angular velocity in rad/s
+    /// along X, Y and Z axes.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct Imu {
+        pub acceleration: [f32; 3],
+        pub angular_velocity: [f32; 3],
+    }
+
+    /// Read accelerometer and gyroscope.
+    pub struct ReadImu;
+
+    impl ArduinoCommand for ReadImu {
+        type Response = RawImu;
+        const ID: u16 = 0x0700;
+
+        fn payload(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn decode(response: Vec<u8>) -> Result<RawImu, ResponseError> {
+            check_supported(&response, "IMU")?;
+            match response.len() {
+                1 => Err(ResponseError::ProtocolError(
+                    "IMU is not responding.".to_owned(),
+                )),
+                12 => {
+                    let value = |index: usize| {
+                        i16::from_be_bytes([response[2 * index], response[2 * index + 1]])
+                    };
+                    Ok(RawImu {
+                        acceleration: [value(0), value(1), value(2)],
+                        rotation: [value(3), value(4), value(5)],
+                    })
+                }
+                len => Err(ResponseError::ProtocolError(format!(
+                    "Expected 12 bytes with IMU readings, got {} bytes.",
+                    len
+                ))),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::tests::MessageTestBuilder;
+        use super::*;
+
+        #[test]
+        fn test_read() {
+            let test = MessageTestBuilder::new()
+                .response(vec![0, 1, 0xff, 0xfe, 0x40, 0, 0, 0, 0, 0, 0, 131])
+                .start();
+            let raw = test.handle().execute(&ReadImu).unwrap();
+            test.test(0x0700, vec![]);
+            assert_eq!(
+                raw,
+                RawImu {
+                    acceleration: [1, -2, 16384],
+                    rotation: [0, 0, 131],
+                }
+            );
+
+            let imu = raw.convert();
+            assert!((imu.acceleration[2] - 9.806_65).abs() < 1e-4);
+            assert!((imu.angular_velocity[2] - PI / 180.0).abs() < 1e-6);
+        }
+
+        #[test]
+        fn test_read_invalid() {
+            let test = MessageTestBuilder::new().start();
+            assert!(test.handle().execute(&ReadImu).is_err());
+            let test = MessageTestBuilder::new().response(vec![1]).start();
+            let error = test.handle().execute(&ReadImu).unwrap_err();
+            assert!(error.to_string().contains("not responding"), "{}", error);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
-    use super::super::binary::Message;
+    use super::super::binary::{Handle, Message, StateWatch};
     use std::sync::mpsc::{self, Receiver, Sender};
     use std::thread;
     use std::time::Duration;
@@ -217,7 +959,6 @@ mod tests {
     struct MessageLocal {
         command: u16,
         payload: Vec<u8>,
-        sender: Sender<Vec<u8>>,
     }
 
     pub struct MessageTestBuilder {
@@ -229,7 +970,7 @@ mod tests {
     }
 
     pub struct MessageTest {
-        here_sender: Sender<Message>,
+        handle: Handle,
         here_receiver: Receiver<MessageLocal>,
         // Keep this so the channel is not closed until this struct is dropped.
         _there_sender: Sender<MessageLocal>,
@@ -263,15 +1004,17 @@ mod tests {
                 let message = there_receiver
                     .recv_timeout(Duration::from_millis(100))
                     .unwrap();
-                let message_exposed: MessageLocal = unsafe { std::mem::transmute(message) };
+                let (command, payload, _, sender) = message.destructure();
                 // The channel may be closed already if the command doesn't
                 // read the response which is completely ok.
-                message_exposed.sender.send(response).unwrap_or(());
-                there_sender.send(message_exposed).unwrap();
+                sender.send(Ok(response)).unwrap_or(());
+                there_sender
+                    .send(MessageLocal { command, payload })
+                    .unwrap();
             });
 
             MessageTest {
-                here_sender: self.here_sender,
+                handle: Handle::new(self.here_sender, StateWatch::new()),
                 here_receiver: self.here_receiver,
                 _there_sender: self.there_sender,
             }
@@ -279,8 +1022,8 @@ mod tests {
     }
 
     impl MessageTest {
-        pub fn sender(&self) -> &Sender<Message> {
-            &self.here_sender
+        pub fn handle(&self) -> &Handle {
+            &self.handle
         }
 
         pub fn test(self, expected_cmd: u16, expected_payload: Vec<u8>) {
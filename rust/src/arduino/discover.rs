@@ -0,0 +1,343 @@
+//! Discovery of the serial port Irro's Arduino is connected to, see
+//! `super::transport::Device::Auto`.
+//!
+//! USB serial ports are listed from the system (with their USB vendor and
+//! product ids) and from `/dev/serial/by-id`, missing USB ids are read from
+//! sysfs. Ports which are likely an Arduino are probed first. A port is
+//! accepted once Irro's firmware responds to protocol negotiation on it.
+
+use super::binary::Connection;
+use super::transport::{Device, Transport};
+use log::{debug, info};
+use serialport::{self, SerialPortType};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Directory with symbolic links to serial ports named after the connected
+/// USB devices.
+const BY_ID_DIR: &str = "/dev/serial/by-id";
+/// Sysfs directory with all TTY devices.
+const SYS_TTY_DIR: &str = "/sys/class/tty";
+/// USB vendor ids of Arduino boards and of USB to serial converters common
+/// on Arduino clones.
+const ARDUINO_VENDORS: [u16; 4] = [
+    0x2341, // Arduino SA
+    0x2a03, // Arduino SRL
+    0x1a86, // QinHeng Electronics (CH340)
+    0x0403, // Future Technology Devices International (FTDI)
+];
+
+/// A serial port which may be connected to the Arduino.
+#[derive(Debug, PartialEq, Eq)]
+struct Candidate {
+    /// Device path, for example `/dev/ttyACM0`.
+    path: PathBuf,
+    /// USB vendor and product id if known.
+    usb_id: Option<(u16, u16)>,
+    /// File name of the link to the port in `BY_ID_DIR` if there is any.
+    by_id: Option<String>,
+}
+
+impl Candidate {
+    fn new(path: PathBuf) -> Self {
+        Candidate {
+            path,
+            usb_id: None,
+            by_id: None,
+        }
+    }
+
+    /// Return true if the port belongs to a USB device. Other serial ports
+    /// (e.g. Raspberry Pi UART) are never probed.
+    fn is_usb(&self) -> bool {
+        self.usb_id.is_some()
+            || self
+                .by_id
+                .as_ref()
+                .map_or(false, |name| name.starts_with("usb-"))
+    }
+
+    /// Return true if the port is likely an Arduino.
+    fn is_likely(&self) -> bool {
+        let vendor = self
+            .usb_id
+            .map_or(false, |(vid, _)| ARDUINO_VENDORS.contains(&vid));
+        let name = self
+            .by_id
+            .as_ref()
+            .map_or(false, |name| name.to_lowercase().contains("arduino"));
+        vendor || name
+    }
+
+    fn describe(&self) -> String {
+        let mut description = self.path.display().to_string();
+        if let Some((vid, pid)) = self.usb_id {
+            description.push_str(&format!(" ({:04x}:{:04x})", vid, pid));
+        }
+        if let Some(ref name) = self.by_id {
+            description.push_str(&format!(" [{}]", name));
+        }
+        description
+    }
+}
+
+/// Find the serial port with Irro's Arduino and return the port open.
+///
+/// # Errors
+///
+/// An error is returned if no port with Irro's Arduino is found.
+pub fn discover() -> io::Result<Box<dyn Transport>> {
+    let ports = match serialport::available_ports() {
+        Ok(ports) => ports
+            .into_iter()
+            .map(|port| {
+                let usb_id = match port.port_type {
+                    SerialPortType::UsbPort(ref info) => Some((info.vid, info.pid)),
+                    _ => None,
+                };
+                (PathBuf::from(port.port_name), usb_id)
+            })
+            .collect(),
+        Err(err) => {
+            debug!("Serial ports couldn't be enumerated: {}", err);
+            Vec::new()
+        }
+    };
+
+    let by_id = read_by_id(Path::new(BY_ID_DIR));
+    let usb_id = |path: &Path| read_usb_id(Path::new(SYS_TTY_DIR), path);
+    for candidate in candidates(ports, by_id, usb_id) {
+        if !candidate.is_usb() {
+            info!(
+                "Rejected serial port {}: not a USB serial port.",
+                candidate.describe()
+            );
+            continue;
+        }
+
+        debug!("Probing serial port {}...", candidate.describe());
+        match probe(&candidate.path) {
+            Ok(port) => {
+                info!("Found Irro Arduino at {}.", candidate.describe());
+                return Ok(port);
+            }
+            Err(err) => info!("Rejected serial port {}: {}", candidate.describe(), err),
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::NotFound,
+        "No serial port with Irro Arduino was found.",
+    ))
+}
+
+/// Open the port and check that Irro's firmware responds on it.
+fn probe(path: &Path) -> io::Result<Box<dyn Transport>> {
+    let device = Device::Serial(path.display().to_string());
+    let mut port = device.open()?;
+    Connection::probe(device, &mut *port)?;
+    Ok(port)
+}
+
+/// Merge ports listed by the system with ports found in `BY_ID_DIR` and
+/// order them so that likely Arduino ports are probed first.
+///
+/// # Arguments
+///
+/// * `ports` - paths of serial ports with their USB vendor and product ids.
+///
+/// * `by_id` - link names and canonical paths of ports in `BY_ID_DIR`.
+///
+/// * `usb_id` - returns USB vendor and product ids of a port, it is used for
+///   ports whose ids are not known otherwise.
+fn candidates<F>(
+    ports: Vec<(PathBuf, Option<(u16, u16)>)>,
+    by_id: Vec<(String, PathBuf)>,
+    usb_id: F,
+) -> Vec<Candidate>
+where
+    F: Fn(&Path) -> Option<(u16, u16)>,
+{
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for (path, usb_id) in ports {
+        let mut candidate = Candidate::new(path);
+        candidate.usb_id = usb_id;
+        candidates.push(candidate);
+    }
+
+    for (name, path) in by_id {
+        match candidates
+            .iter()
+            .position(|candidate| candidate.path == path)
+        {
+            Some(index) => candidates[index].by_id = Some(name),
+            None => {
+                let mut candidate = Candidate::new(path);
+                candidate.by_id = Some(name);
+                candidates.push(candidate);
+            }
+        }
+    }
+
+    for candidate in candidates.iter_mut() {
+        if candidate.usb_id.is_none() {
+            candidate.usb_id = usb_id(&candidate.path);
+        }
+    }
+
+    // Stable sort keeps system order of equally likely ports.
+    candidates.sort_by_key(|candidate| !candidate.is_likely());
+    candidates
+}
+
+/// Return names and canonical paths of all links in the directory. An empty
+/// vector is returned if the directory doesn't exist, e.g. when no USB serial
+/// device is connected.
+fn read_by_id(dir: &Path) -> Vec<(String, PathBuf)> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("Couldn't read {}: {}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+
+    let mut links: Vec<(String, PathBuf)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = fs::canonicalize(entry.path()).ok()?;
+            Some((entry.file_name().to_string_lossy().into_owned(), path))
+        })
+        .collect();
+    links.sort();
+    links
+}
+
+/// Read USB vendor and product ids of a serial port from sysfs. The ids are
+/// attributes of the USB device which is the parent of the USB interface
+/// (ttyACM) or the grandparent of the USB serial converter port (ttyUSB).
+///
+/// # Arguments
+///
+/// * `sys_tty` - sysfs directory with TTY devices.
+///
+/// * `path` - device path of the port.
+fn read_usb_id(sys_tty: &Path, path: &Path) -> Option<(u16, u16)> {
+    let device = sys_tty.join(path.file_name()?).join("device");
+    let read_id = |dir: &Path, name: &str| -> Option<u16> {
+        let id = fs::read_to_string(dir.join(name)).ok()?;
+        u16::from_str_radix(id.trim(), 16).ok()
+    };
+
+    [device.join(".."), device.join("../..")]
+        .iter()
+        .filter_map(|dir| Some((read_id(dir, "idVendor")?, read_id(dir, "idProduct")?)))
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+    use std::process;
+
+    #[test]
+    fn test_candidates() {
+        let ports = vec![
+            (PathBuf::from("/dev/ttyAMA0"), None),
+            (PathBuf::from("/dev/ttyUSB0"), Some((0x10c4, 0xea60))),
+            (PathBuf::from("/dev/ttyACM1"), Some((0x2341, 0x0043))),
+        ];
+        let by_id = vec![
+            (
+                "usb-Arduino__www.arduino.cc__0043_8573-if00".to_owned(),
+                PathBuf::from("/dev/ttyACM1"),
+            ),
+            (
+                "usb-1a86_USB2.0-Serial-if00-port0".to_owned(),
+                PathBuf::from("/dev/ttyUSB1"),
+            ),
+        ];
+
+        let usb_id = |path: &Path| {
+            if path == Path::new("/dev/ttyUSB1") {
+                Some((0x1a86, 0x7523))
+            } else {
+                None
+            }
+        };
+        let candidates = candidates(ports, by_id, usb_id);
+        let paths: Vec<&Path> = candidates
+            .iter()
+            .map(|candidate| candidate.path.as_path())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("/dev/ttyACM1"),
+                Path::new("/dev/ttyUSB1"),
+                Path::new("/dev/ttyAMA0"),
+                Path::new("/dev/ttyUSB0"),
+            ]
+        );
+
+        assert_eq!(candidates[0].usb_id, Some((0x2341, 0x0043)));
+        assert!(candidates[0].by_id.is_some());
+        assert_eq!(candidates[1].usb_id, Some((0x1a86, 0x7523)));
+        assert!(!candidates[2].is_usb());
+        assert!(candidates[3].is_usb());
+        assert!(!candidates[3].is_likely());
+    }
+
+    #[test]
+    fn test_read_usb_id() {
+        let dir = env::temp_dir().join(format!("irro-sysfs-{}", process::id()));
+        let tty = dir.join("class/tty");
+        let usb = dir.join("devices/usb1");
+
+        let acm = usb.join("1-1.2/1-1.2:1.0");
+        fs::create_dir_all(&acm).unwrap();
+        fs::write(usb.join("1-1.2/idVendor"), "2341\n").unwrap();
+        fs::write(usb.join("1-1.2/idProduct"), "0043\n").unwrap();
+        fs::create_dir_all(tty.join("ttyACM0")).unwrap();
+        symlink(&acm, tty.join("ttyACM0/device")).unwrap();
+
+        let converter = usb.join("1-1.3/1-1.3:1.0/ttyUSB0");
+        fs::create_dir_all(&converter).unwrap();
+        fs::write(usb.join("1-1.3/idVendor"), "1a86\n").unwrap();
+        fs::write(usb.join("1-1.3/idProduct"), "7523\n").unwrap();
+        fs::create_dir_all(tty.join("ttyUSB0")).unwrap();
+        symlink(&converter, tty.join("ttyUSB0/device")).unwrap();
+
+        let acm_id = read_usb_id(&tty, Path::new("/dev/ttyACM0"));
+        let converter_id = read_usb_id(&tty, Path::new("/dev/ttyUSB0"));
+        let missing_id = read_usb_id(&tty, Path::new("/dev/ttyAMA0"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(acm_id, Some((0x2341, 0x0043)));
+        assert_eq!(converter_id, Some((0x1a86, 0x7523)));
+        assert_eq!(missing_id, None);
+    }
+
+    #[test]
+    fn test_read_by_id() {
+        let dir = env::temp_dir().join(format!("irro-by-id-{}", process::id()));
+        fs::create_dir(&dir).unwrap();
+        let target = dir.join("ttyACM0");
+        fs::write(&target, b"").unwrap();
+        symlink(&target, dir.join("usb-Arduino-if00")).unwrap();
+
+        let links = read_by_id(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].0, "ttyACM0");
+        assert_eq!(links[1].0, "usb-Arduino-if00");
+        assert_eq!(links[1].1, links[0].1);
+
+        assert!(read_by_id(&dir).is_empty());
+    }
+}
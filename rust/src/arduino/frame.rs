@@ -0,0 +1,630 @@
+//! Encoding and decoding of [serial protocol](https://irro.cz/serial_protocol.html)
+//! frames.
+//!
+//! Protocol version 1 frames consist of a header with command and payload
+//! length followed by the payload. Protocol version 2 frames start with a
+//! start marker and a sequence id and end with a CRC so that corrupted frames
+//! are detected and skipped. Protocol version 3 responses additionally report
+//! how many bytes Arduino has read from its serial buffer so far.
+//!
+//! Both sides of the communication are implemented here so that Arduino can
+//! be simulated, see `super::sim`.
+
+use log::warn;
+use std::io::{self, ErrorKind};
+
+/// First byte of each protocol version 2 frame.
+pub const START_MARKER: u8 = 0xa5;
+/// Maximum size of a command or response payload. Neither side sends more data
+/// than fits Arduino serial buffer.
+pub const MAX_PAYLOAD_LEN: usize = 64;
+/// Maximum number of bytes a command frame adds to its payload, id est
+/// command frame overhead of the protocol version with the largest overhead.
+pub const MAX_COMMAND_OVERHEAD: usize = 8;
+/// Command which negotiates protocol version. It is always sent with protocol
+/// version 1 framing.
+pub const NEGOTIATE_COMMAND: u16 = 0x0200;
+/// Header of a protocol version 1 negotiation command frame with one byte
+/// payload.
+const NEGOTIATE_HEADER: [u8; 4] = [0x02, 0x00, 0x00, 0x01];
+/// Data Arduino writes once its firmware starts, e.g. after the serial port is
+/// opened and the Arduino is reset. The banner doesn't contain the start
+/// marker so it is never part of a protocol version 2 frame header.
+pub const BOOT_BANNER: [u8; 6] = [0xff, 0xff, b'I', b'R', b'R', b'O'];
+
+/// Version of the serial protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+    V2,
+    V3,
+}
+
+impl Protocol {
+    /// Latest protocol version supported by this implementation.
+    pub const LATEST: Protocol = Protocol::V3;
+
+    /// Return protocol with the given version number or `None` if the version
+    /// is unknown.
+    pub fn from_version(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Protocol::V1),
+            2 => Some(Protocol::V2),
+            3 => Some(Protocol::V3),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> u8 {
+        match self {
+            Protocol::V1 => 1,
+            Protocol::V2 => 2,
+            Protocol::V3 => 3,
+        }
+    }
+
+    /// Return true if frames of this protocol version start with a start
+    /// marker and a sequence id and end with a CRC.
+    pub fn is_framed(self) -> bool {
+        self != Protocol::V1
+    }
+
+    /// Return true if Arduino reports number of consumed bytes in each
+    /// response, see `ResponseFrame::consumed`.
+    pub fn has_credits(self) -> bool {
+        self == Protocol::V3
+    }
+
+    /// Number of bytes a command frame adds to its payload.
+    pub fn command_overhead(self) -> usize {
+        match self {
+            // command (2 bytes) and payload length (2 bytes)
+            Protocol::V1 => 4,
+            // plus start marker, sequence id and CRC (2 bytes)
+            Protocol::V2 | Protocol::V3 => 8,
+        }
+    }
+
+    /// Number of bytes a response frame adds to its payload.
+    pub fn response_overhead(self) -> usize {
+        match self {
+            // payload length (2 bytes)
+            Protocol::V1 => 2,
+            // plus start marker, sequence id and CRC (2 bytes)
+            Protocol::V2 => 6,
+            // plus number of consumed bytes (2 bytes)
+            Protocol::V3 => 8,
+        }
+    }
+}
+
+/// Compute CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xffff) of
+/// the data.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 == 0 {
+                crc <<= 1;
+            } else {
+                crc = (crc << 1) ^ 0x1021;
+            }
+        }
+    }
+    crc
+}
+
+/// Encode a command frame.
+///
+/// # Arguments
+///
+/// * `protocol` - protocol version of the frame.
+///
+/// * `seq` - sequence id of the frame. It is ignored in protocol version 1.
+///
+/// * `command` - command identifier.
+///
+/// * `payload` - command payload.
+pub fn encode_command(protocol: Protocol, seq: u8, command: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + protocol.command_overhead());
+    if protocol.is_framed() {
+        frame.push(START_MARKER);
+        frame.push(seq);
+    }
+    frame.extend_from_slice(&command.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    if protocol.is_framed() {
+        // Start marker is not part of CRC.
+        let crc = crc16(&frame[1..]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+    }
+    frame
+}
+
+/// Encode a response frame. See `encode_command()`.
+///
+/// # Arguments
+///
+/// * `consumed` - number of bytes Arduino has read from its serial buffer,
+///   see `ResponseFrame::consumed`. It is ignored in protocol versions
+///   without credits.
+pub fn encode_response(protocol: Protocol, seq: u8, consumed: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + protocol.response_overhead());
+    if protocol.is_framed() {
+        frame.push(START_MARKER);
+        frame.push(seq);
+    }
+    if protocol.has_credits() {
+        frame.extend_from_slice(&consumed.to_be_bytes());
+    }
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    if protocol.is_framed() {
+        let crc = crc16(&frame[1..]);
+        frame.extend_from_slice(&crc.to_be_bytes());
+    }
+    frame
+}
+
+/// A decoded response frame.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ResponseFrame {
+    /// Sequence id of the command this response belongs to. It is `None` in
+    /// protocol version 1 where responses are matched by order.
+    pub seq: Option<u8>,
+    /// Number of bytes Arduino has read from its serial buffer since protocol
+    /// negotiation, modulo 2^16. It is `None` in protocol versions without
+    /// credits.
+    pub consumed: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+/// Incremental decoder of Arduino responses. Data read from the serial port
+/// may end in the middle of a response, so they are buffered until the whole
+/// response is received.
+pub struct ResponseDecoder {
+    protocol: Protocol,
+    buffer: Vec<u8>,
+}
+
+impl ResponseDecoder {
+    pub fn new(protocol: Protocol) -> Self {
+        ResponseDecoder {
+            protocol,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Change protocol version of subsequently decoded frames.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Append data received from the Arduino.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Remove and return the first buffered response. `None` is returned if
+    /// no complete response is buffered.
+    ///
+    /// Data which don't form a valid protocol version 2 frame (e.g. frames
+    /// with invalid CRC) are skipped.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the buffered data start with an impossible
+    /// protocol version 1 response length. The stream is out of sync in such
+    /// a case.
+    pub fn next(&mut self) -> io::Result<Option<ResponseFrame>> {
+        match self.protocol {
+            Protocol::V1 => self.next_v1(),
+            Protocol::V2 => Ok(self.next_v2()),
+            Protocol::V3 => Ok(self.next_v3()),
+        }
+    }
+
+    /// Drop all buffered data.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    fn next_v1(&mut self) -> io::Result<Option<ResponseFrame>> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+
+        let payload_len = ((self.buffer[0] as usize) << 8) | (self.buffer[1] as usize);
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Received response with impossible length {}.", payload_len),
+            ));
+        }
+
+        let frame_len = 2 + payload_len;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[2..frame_len].to_vec();
+        self.buffer.drain(..frame_len);
+        Ok(Some(ResponseFrame {
+            seq: None,
+            consumed: None,
+            payload,
+        }))
+    }
+
+    fn next_v2(&mut self) -> Option<ResponseFrame> {
+        next_v2_frame(&mut self.buffer, 2).map(|body| ResponseFrame {
+            seq: Some(body[0]),
+            consumed: None,
+            payload: body[3..].to_vec(),
+        })
+    }
+
+    fn next_v3(&mut self) -> Option<ResponseFrame> {
+        next_v2_frame(&mut self.buffer, 4).map(|body| ResponseFrame {
+            seq: Some(body[0]),
+            consumed: Some((u16::from(body[1]) << 8) | u16::from(body[2])),
+            payload: body[5..].to_vec(),
+        })
+    }
+}
+
+/// A decoded command frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommandFrame {
+    /// Sequence id of the command. It is `None` for protocol version 1 frames.
+    pub seq: Option<u8>,
+    pub command: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Incremental decoder of commands sent to Arduino. See `ResponseDecoder`.
+///
+/// Protocol negotiation commands are decoded with protocol version 1 framing
+/// in any protocol version.
+pub struct CommandDecoder {
+    protocol: Protocol,
+    buffer: Vec<u8>,
+}
+
+impl CommandDecoder {
+    pub fn new(protocol: Protocol) -> Self {
+        CommandDecoder {
+            protocol,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Change protocol version of subsequently decoded frames.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    /// Append data received from the onboard computer.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Remove and return the first buffered command. See
+    /// `ResponseDecoder::next()`.
+    pub fn next(&mut self) -> io::Result<Option<CommandFrame>> {
+        if self.protocol == Protocol::V1 {
+            return self.next_v1();
+        }
+
+        let header_len = self.buffer.len().min(NEGOTIATE_HEADER.len());
+        if self.buffer[..header_len] == NEGOTIATE_HEADER[..header_len] {
+            return if self.buffer.len() > NEGOTIATE_HEADER.len() {
+                self.next_v1()
+            } else {
+                Ok(None)
+            };
+        }
+
+        Ok(next_v2_frame(&mut self.buffer, 4).map(|body| CommandFrame {
+            seq: Some(body[0]),
+            command: (u16::from(body[1]) << 8) | u16::from(body[2]),
+            payload: body[5..].to_vec(),
+        }))
+    }
+
+    /// Drop all buffered data.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    fn next_v1(&mut self) -> io::Result<Option<CommandFrame>> {
+        if self.buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let command = (u16::from(self.buffer[0]) << 8) | u16::from(self.buffer[1]);
+        let payload_len = ((self.buffer[2] as usize) << 8) | (self.buffer[3] as usize);
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Received command with impossible length {}.", payload_len),
+            ));
+        }
+
+        let frame_len = 4 + payload_len;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let payload = self.buffer[4..frame_len].to_vec();
+        self.buffer.drain(..frame_len);
+        Ok(Some(CommandFrame {
+            seq: None,
+            command,
+            payload,
+        }))
+    }
+}
+
+/// Incremental search for `BOOT_BANNER` in data received from the Arduino.
+/// The banner may be split among multiple chunks of data.
+pub struct BannerDetector {
+    /// Number of banner bytes at the end of the data seen so far.
+    matched: usize,
+}
+
+impl BannerDetector {
+    pub fn new() -> Self {
+        BannerDetector { matched: 0 }
+    }
+
+    /// Search the data for the banner. Return index of the first byte
+    /// following the last banner which ends in the data, if any.
+    pub fn find(&mut self, data: &[u8]) -> Option<usize> {
+        let mut end = None;
+        for (index, &byte) in data.iter().enumerate() {
+            self.matched = Self::advance(self.matched, byte);
+            if self.matched == BOOT_BANNER.len() {
+                end = Some(index + 1);
+                self.matched = 0;
+            }
+        }
+        end
+    }
+
+    /// Return length of the longest banner prefix which is a suffix of the
+    /// first `matched` banner bytes followed by the byte.
+    fn advance(matched: usize, byte: u8) -> usize {
+        (1..=(matched + 1))
+            .rev()
+            .find(|&len| {
+                BOOT_BANNER[len - 1] == byte
+                    && BOOT_BANNER[(matched + 1 - len)..matched] == BOOT_BANNER[..(len - 1)]
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Default for BannerDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Remove the first valid protocol version 2 (or later) frame from the buffer
+/// and return its body, id est everything between the start marker and the
+/// CRC. Invalid data preceding the frame are dropped.
+///
+/// # Arguments
+///
+/// * `buffer` - received data.
+///
+/// * `header_len` - number of bytes between the sequence id and the payload.
+///   Last two of these bytes are payload length.
+fn next_v2_frame(buffer: &mut Vec<u8>, header_len: usize) -> Option<Vec<u8>> {
+    loop {
+        let skip = buffer
+            .iter()
+            .position(|&byte| byte == START_MARKER)
+            .unwrap_or(buffer.len());
+        if skip > 0 {
+            warn!("Skipping {} bytes of data not starting a frame.", skip);
+            buffer.drain(..skip);
+        }
+
+        if buffer.len() < 2 + header_len {
+            return None;
+        }
+
+        let payload_len = ((buffer[header_len] as usize) << 8) | (buffer[header_len + 1] as usize);
+        if payload_len > MAX_PAYLOAD_LEN {
+            warn!("Skipping a frame with impossible length {}.", payload_len);
+            buffer.remove(0);
+            continue;
+        }
+
+        let frame_len = 4 + header_len + payload_len;
+        if buffer.len() < frame_len {
+            return None;
+        }
+
+        let crc = (u16::from(buffer[frame_len - 2]) << 8) | u16::from(buffer[frame_len - 1]);
+        if crc != crc16(&buffer[1..(frame_len - 2)]) {
+            warn!("Skipping a frame with invalid CRC.");
+            buffer.remove(0);
+            continue;
+        }
+
+        let body = buffer[1..(frame_len - 2)].to_vec();
+        buffer.drain(..frame_len);
+        return Some(body);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(seq: Option<u8>, payload: Vec<u8>) -> Option<ResponseFrame> {
+        Some(ResponseFrame {
+            seq,
+            consumed: None,
+            payload,
+        })
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+        assert_eq!(crc16(&[]), 0xffff);
+    }
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(Protocol::V1, 7, 0x0100, &[1, 2]),
+            vec![1, 0, 0, 2, 1, 2]
+        );
+
+        let encoded = encode_command(Protocol::V2, 7, 0x0100, &[1, 2]);
+        let crc = crc16(&[7, 1, 0, 0, 2, 1, 2]).to_be_bytes();
+        assert_eq!(encoded, vec![0xa5, 7, 1, 0, 0, 2, 1, 2, crc[0], crc[1]]);
+        assert_eq!(encoded.len(), 2 + Protocol::V2.command_overhead());
+    }
+
+    #[test]
+    fn test_response_decoder_v1() {
+        let mut decoder = ResponseDecoder::new(Protocol::V1);
+        assert_eq!(decoder.next().unwrap(), None);
+
+        decoder.push(&[0]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[2, 7]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[8, 0, 0, 0]);
+        assert_eq!(decoder.next().unwrap(), frame(None, vec![7, 8]));
+        assert_eq!(decoder.next().unwrap(), frame(None, vec![]));
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[1]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[5]);
+        assert_eq!(decoder.next().unwrap(), frame(None, vec![5]));
+        assert_eq!(decoder.next().unwrap(), None);
+
+        decoder.clear();
+        decoder.push(&[1, 0]);
+        assert!(decoder.next().is_err());
+    }
+
+    #[test]
+    fn test_response_decoder_v2() {
+        let mut decoder = ResponseDecoder::new(Protocol::V2);
+
+        let frame_a = encode_response(Protocol::V2, 3, 0, &[1, 2, 3]);
+        for &byte in &frame_a {
+            assert_eq!(decoder.next().unwrap(), None);
+            decoder.push(&[byte]);
+        }
+        assert_eq!(decoder.next().unwrap(), frame(Some(3), vec![1, 2, 3]));
+        assert_eq!(decoder.next().unwrap(), None);
+
+        // Garbage and a frame with a corrupted payload are skipped.
+        decoder.push(&[1, 2, 3]);
+        let mut corrupted = encode_response(Protocol::V2, 4, 0, &[4, 5]);
+        corrupted[4] = 9;
+        decoder.push(&corrupted);
+        decoder.push(&encode_response(Protocol::V2, 5, 0, &[]));
+        assert_eq!(decoder.next().unwrap(), frame(Some(5), vec![]));
+        assert_eq!(decoder.next().unwrap(), None);
+
+        // Start marker followed by an impossible length is skipped.
+        decoder.push(&[START_MARKER, 6, 0xff]);
+        decoder.push(&encode_response(Protocol::V2, 6, 0, &[6]));
+        assert_eq!(decoder.next().unwrap(), frame(Some(6), vec![6]));
+    }
+
+    #[test]
+    fn test_response_decoder_v3() {
+        let mut decoder = ResponseDecoder::new(Protocol::V3);
+
+        let encoded = encode_response(Protocol::V3, 3, 0x1234, &[1, 2]);
+        let crc = crc16(&[3, 0x12, 0x34, 0, 2, 1, 2]).to_be_bytes();
+        assert_eq!(
+            encoded,
+            vec![0xa5, 3, 0x12, 0x34, 0, 2, 1, 2, crc[0], crc[1]]
+        );
+        assert_eq!(encoded.len(), 2 + Protocol::V3.response_overhead());
+
+        decoder.push(&[7]);
+        decoder.push(&encoded);
+        assert_eq!(
+            decoder.next().unwrap(),
+            Some(ResponseFrame {
+                seq: Some(3),
+                consumed: Some(0x1234),
+                payload: vec![1, 2],
+            })
+        );
+        assert_eq!(decoder.next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_command_decoder() {
+        let mut decoder = CommandDecoder::new(Protocol::V1);
+        decoder.push(&[0, 1, 0]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[1, 9]);
+        let frame = decoder.next().unwrap().unwrap();
+        assert_eq!(frame.seq, None);
+        assert_eq!(frame.command, 0x0001);
+        assert_eq!(frame.payload, vec![9]);
+
+        decoder.set_protocol(Protocol::V2);
+        decoder.push(&[7]);
+        decoder.push(&encode_command(Protocol::V2, 4, 0x0100, &[1, 2, 3, 4]));
+        let frame = decoder.next().unwrap().unwrap();
+        assert_eq!(frame.seq, Some(4));
+        assert_eq!(frame.command, 0x0100);
+        assert_eq!(frame.payload, vec![1, 2, 3, 4]);
+
+        // Negotiation is accepted with protocol version 1 framing.
+        decoder.push(&[2, 0, 0]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[1]);
+        assert_eq!(decoder.next().unwrap(), None);
+        decoder.push(&[2]);
+        let frame = decoder.next().unwrap().unwrap();
+        assert_eq!(frame.seq, None);
+        assert_eq!(frame.command, NEGOTIATE_COMMAND);
+        assert_eq!(frame.payload, vec![2]);
+        assert_eq!(decoder.next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_banner_detector() {
+        let mut detector = BannerDetector::new();
+        assert_eq!(detector.find(&[0, 1, 2]), None);
+        assert_eq!(detector.find(&[0xff, 0xff, 0xff, b'I']), None);
+        assert_eq!(detector.find(&[b'R', b'R', b'O', 7, 8]), Some(3));
+        assert_eq!(detector.find(b"IRRO"), None);
+
+        let mut data = BOOT_BANNER.to_vec();
+        data.extend(&[0xff, 0xff, b'I', b'R', b'R', 0xff]);
+        data.extend(&BOOT_BANNER);
+        data.push(1);
+        assert_eq!(detector.find(&data), Some(data.len() - 1));
+    }
+
+    #[test]
+    fn test_response_decoder_set_protocol() {
+        let mut decoder = ResponseDecoder::new(Protocol::V1);
+        decoder.push(&encode_response(Protocol::V1, 0, 0, &[2]));
+        assert_eq!(decoder.next().unwrap(), frame(None, vec![2]));
+
+        decoder.set_protocol(Protocol::V2);
+        decoder.push(&encode_response(Protocol::V2, 1, 0, &[]));
+        assert_eq!(decoder.next().unwrap(), frame(Some(1), vec![]));
+    }
+}
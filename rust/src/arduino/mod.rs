@@ -3,4 +3,9 @@
 //! [serial protocol](https://irro.cz/serial_protocol.html) documentation.
 
 pub mod binary;
+pub mod capture;
 pub mod cmd;
+pub mod discover;
+pub mod frame;
+pub mod sim;
+pub mod transport;
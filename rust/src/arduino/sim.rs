@@ -0,0 +1,338 @@
+//! Simulated Arduino which makes it possible to run the server without the
+//! real hardware. The simulated Arduino behaves as `arduino/main.ino` and
+//! communicates over a pseudo terminal so that `super::binary::Connection`
+//! uses it exactly as a real serial port.
+
+use super::frame::{self, CommandDecoder, Protocol};
+use log::{debug, info, warn};
+use serialport::posix::TTYPort;
+use serialport::SerialPort;
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::Instant;
+
+/// Firmware version reported by the simulated Arduino.
+const FIRMWARE_VERSION: [u8; 3] = [0, 6, 0];
+/// Free RAM reported by the simulated Arduino.
+const FREE_RAM: u16 = 1024;
+/// Power-on reset flag of the AVR MCU status register.
+const POWER_ON_RESET: u8 = 0x01;
+
+/// Raw battery voltage and current readings of the simulated Arduino,
+/// approximately 22V and 1A with the default calibration.
+const RAW_BATTERY: (u16, u16) = (750, 532);
+
+/// Distance in millimeters measured by the simulated front range sensor.
+const RANGE: u16 = 1500;
+
+/// Yaw rate in rad/s of the robot turning with full power on the spot, it
+/// corresponds to the default chassis.
+const MAX_YAW_RATE: f64 = 4.0;
+/// Raw gyroscope counts per rad/s and raw accelerometer reading of gravity.
+const RAW_ROTATION_SCALE: f64 = 131.0 * 180.0 / std::f64::consts::PI;
+const RAW_GRAVITY: i16 = 16384;
+
+/// Encoder ticks per second of a track driven at full power.
+const TICKS_PER_SECOND: f64 = 1000.0;
+
+/// Device name which makes `super::binary::Connection` use a simulated
+/// Arduino.
+pub const DEVICE: &str = "sim://";
+
+/// State of the simulated Arduino.
+pub struct Arduino {
+    protocol: Protocol,
+    led_mask: u8,
+    motor_powers: (i16, i16),
+    emergency_stop: bool,
+    /// Encoder tick counters and time they were last updated.
+    encoders: (f64, f64),
+    encoders_updated: Instant,
+    started: Instant,
+}
+
+impl Arduino {
+    /// Create Arduino in its after reset state: all LEDs are off, motors are
+    /// stopped, emergency stop is released and protocol version 1 is used.
+    pub fn new() -> Self {
+        Arduino {
+            protocol: Protocol::V1,
+            led_mask: 0,
+            motor_powers: (0, 0),
+            emergency_stop: false,
+            encoders: (0.0, 0.0),
+            encoders_updated: Instant::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Protocol version negotiated with the onboard computer.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Current LED on/off bit mask.
+    pub fn led_mask(&self) -> u8 {
+        self.led_mask
+    }
+
+    /// Last set power of left and right motor.
+    pub fn motor_powers(&self) -> (i16, i16) {
+        self.motor_powers
+    }
+
+    /// Current encoder tick counters of left and right track.
+    pub fn encoder_ticks(&mut self) -> (i32, i32) {
+        self.update_encoders();
+        (self.encoders.0 as i32, self.encoders.1 as i32)
+    }
+
+    /// Move the encoders according to motor powers since the last update.
+    fn update_encoders(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.encoders_updated;
+        self.encoders_updated = now;
+
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_micros()) / 1e6;
+        let ticks = |power: i16| {
+            f64::from(power) / f64::from(i16::max_value()) * TICKS_PER_SECOND * seconds
+        };
+        self.encoders.0 += ticks(self.motor_powers.0);
+        self.encoders.1 += ticks(self.motor_powers.1);
+    }
+
+    /// Execute a command and return its response payload. Unknown commands
+    /// have an empty response.
+    pub fn handle(&mut self, command: u16, payload: &[u8]) -> Vec<u8> {
+        // Motor powers are about to change.
+        self.update_encoders();
+
+        match command {
+            0x0000 => {
+                if let Some(&mask) = payload.first() {
+                    self.led_mask = mask;
+                }
+                vec![]
+            }
+            0x0001 => vec![self.led_mask],
+            0x0100 => {
+                if payload.len() >= 4 && !self.emergency_stop {
+                    let left = i16::from_be_bytes([payload[0], payload[1]]);
+                    let right = i16::from_be_bytes([payload[2], payload[3]]);
+                    self.motor_powers = (left, right);
+                }
+                vec![]
+            }
+            0x0101 => {
+                if let Some(&latched) = payload.first() {
+                    self.emergency_stop = latched != 0;
+                    if self.emergency_stop {
+                        self.motor_powers = (0, 0);
+                    }
+                }
+                vec![self.emergency_stop as u8]
+            }
+            0x0300 => {
+                let uptime = self.started.elapsed();
+                let uptime = uptime.as_secs() * 1000 + u64::from(uptime.subsec_millis());
+                let mut response = FIRMWARE_VERSION.to_vec();
+                response.push(self.protocol.version());
+                response.push(Protocol::LATEST.version());
+                response.extend_from_slice(&(uptime as u32).to_be_bytes());
+                response.extend_from_slice(&FREE_RAM.to_be_bytes());
+                response.push(POWER_ON_RESET);
+                response
+            }
+            0x0400 => {
+                let mut response = RAW_BATTERY.0.to_be_bytes().to_vec();
+                response.extend_from_slice(&RAW_BATTERY.1.to_be_bytes());
+                response
+            }
+            0x0500 => {
+                let (left, right) = self.encoder_ticks();
+                let mut response = left.to_be_bytes().to_vec();
+                response.extend_from_slice(&right.to_be_bytes());
+                response
+            }
+            0x0600 => RANGE.to_be_bytes().to_vec(),
+            0x0700 => {
+                // Standing level, turning according to the motor powers.
+                let (left, right) = self.motor_powers;
+                let yaw_rate =
+                    (f64::from(right) - f64::from(left)) / f64::from(i16::max_value()) / 2.0
+                        * MAX_YAW_RATE;
+                let rotation = (yaw_rate * RAW_ROTATION_SCALE) as i16;
+                [0, 0, RAW_GRAVITY, 0, 0, rotation]
+                    .iter()
+                    .flat_map(|value| value.to_be_bytes().to_vec())
+                    .collect()
+            }
+            frame::NEGOTIATE_COMMAND => {
+                self.protocol = match payload.first() {
+                    Some(&version) if version > 1 => {
+                        Protocol::from_version(version).unwrap_or(Protocol::LATEST)
+                    }
+                    _ => Protocol::V1,
+                };
+                vec![self.protocol.version()]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+impl Default for Arduino {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Start a new simulated Arduino and return serial port connected to it. The
+/// simulated Arduino stops once the returned port is closed.
+///
+/// # Errors
+///
+/// An error is returned if a pseudo terminal couldn't be created.
+pub fn open() -> Result<TTYPort, serialport::Error> {
+    let (master, slave) = TTYPort::pair()?;
+    info!(
+        "Simulating Arduino at {}.",
+        slave.name().unwrap_or_else(|| "unknown device".to_owned())
+    );
+    thread::spawn(move || run(master));
+    Ok(slave)
+}
+
+/// Announce boot and serve commands received over the port until the other
+/// side of the port is closed.
+fn run(mut port: TTYPort) {
+    if let Err(err) = port.write_all(&frame::BOOT_BANNER) {
+        debug!("Simulated Arduino stopped: {}", err);
+        return;
+    }
+
+    let mut arduino = Arduino::new();
+    let mut decoder = CommandDecoder::new(arduino.protocol());
+    let mut buf = [0; frame::MAX_PAYLOAD_LEN];
+    // Number of bytes read since the last protocol negotiation.
+    let mut consumed: u16 = 0;
+
+    loop {
+        match port.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                consumed = consumed.wrapping_add(len as u16);
+                decoder.push(&buf[..len]);
+            }
+            Err(ref err) if err.kind() == ErrorKind::TimedOut => continue,
+            Err(err) => {
+                debug!("Simulated Arduino stopped: {}", err);
+                break;
+            }
+        }
+
+        loop {
+            let command = match decoder.next() {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Simulated Arduino dropped received data: {}", err);
+                    decoder.clear();
+                    break;
+                }
+            };
+
+            let protocol = arduino.protocol();
+            let response = arduino.handle(command.command, &command.payload);
+            let encoded = match command.seq {
+                Some(seq) => frame::encode_response(protocol, seq, consumed, &response),
+                None => frame::encode_response(Protocol::V1, 0, 0, &response),
+            };
+            if let Err(err) = port.write_all(&encoded) {
+                debug!("Simulated Arduino stopped: {}", err);
+                return;
+            }
+
+            if command.command == frame::NEGOTIATE_COMMAND {
+                consumed = 0;
+            }
+            decoder.set_protocol(arduino.protocol());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::binary::{Connection, State};
+    use super::super::cmd::led::{LedMask, ReadLedMask};
+    use super::super::cmd::system::ReadFirmwareInfo;
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_arduino() {
+        let mut arduino = Arduino::new();
+        assert_eq!(arduino.handle(0x0001, &[]), vec![0]);
+        assert_eq!(arduino.handle(0x0000, &[0b1010_0000]), vec![]);
+        assert_eq!(arduino.handle(0x0001, &[]), vec![0b1010_0000]);
+        assert_eq!(arduino.led_mask(), 0b1010_0000);
+
+        assert_eq!(arduino.handle(0x0100, &[0x7f, 0xff, 0xdf, 0xff]), vec![]);
+        assert_eq!(arduino.motor_powers(), (32767, -8193));
+
+        assert_eq!(arduino.handle(0x0101, &[1]), vec![1]);
+        assert_eq!(arduino.motor_powers(), (0, 0));
+        assert_eq!(arduino.handle(0x0100, &[0x7f, 0xff, 0xdf, 0xff]), vec![]);
+        assert_eq!(arduino.motor_powers(), (0, 0));
+        assert_eq!(arduino.handle(0x0101, &[0]), vec![0]);
+        assert_eq!(arduino.handle(0x0100, &[0x7f, 0xff, 0xdf, 0xff]), vec![]);
+        assert_eq!(arduino.motor_powers(), (32767, -8193));
+
+        assert_eq!(arduino.handle(0x0200, &[9]), vec![3]);
+        assert_eq!(arduino.protocol(), Protocol::V3);
+        assert_eq!(arduino.handle(0x0200, &[2]), vec![2]);
+        assert_eq!(arduino.protocol(), Protocol::V2);
+        assert_eq!(arduino.handle(0x0200, &[1]), vec![1]);
+        assert_eq!(arduino.protocol(), Protocol::V1);
+
+        assert_eq!(arduino.handle(0x0400, &[]), vec![0x02, 0xee, 0x02, 0x14]);
+        assert_eq!(arduino.handle(0x0600, &[]), vec![0x05, 0xdc]);
+        assert_eq!(arduino.handle(0x7f00, &[1, 2]), vec![]);
+
+        assert_eq!(arduino.handle(0x0100, &[0, 0, 0, 0]), vec![]);
+        assert_eq!(
+            arduino.handle(0x0700, &[]),
+            vec![0, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let ticks = arduino.handle(0x0500, &[]);
+        assert_eq!(ticks.len(), 8);
+        assert_eq!(arduino.encoder_ticks(), arduino.encoder_ticks());
+        arduino.handle(0x0100, &[0x7f, 0xff, 0x80, 0x00]);
+        thread::sleep(Duration::from_millis(20));
+        let (left, right) = arduino.encoder_ticks();
+        assert!(left > 0 && right < 0, "{} {}", left, right);
+        // Turning clockwise.
+        let imu = arduino.handle(0x0700, &[]);
+        assert!(i16::from_be_bytes([imu[10], imu[11]]) < 0, "{:?}", imu);
+    }
+
+    #[test]
+    fn test_simulated_connection() {
+        let arduino = Connection::init_from_device(DEVICE).unwrap();
+        assert_eq!(arduino.state(), State::Connected);
+
+        let mask: Vec<bool> = arduino.execute(&ReadLedMask).unwrap().into();
+        assert_eq!(mask, vec![false; 8]);
+
+        arduino
+            .execute(&LedMask::from_bools(vec![true, false, true]))
+            .unwrap();
+        let mask: Vec<bool> = arduino.execute(&ReadLedMask).unwrap().into();
+        assert_eq!(mask[..3], [true, false, true]);
+
+        let info = arduino.execute(&ReadFirmwareInfo).unwrap();
+        assert!(info.is_compatible());
+        assert_eq!(info.protocol, Protocol::LATEST.version());
+        assert_eq!(info.reset_cause.causes(), vec!["power-on"]);
+    }
+}
@@ -0,0 +1,320 @@
+//! Byte streams over which `super::binary::Connection` communicates with the
+//! Arduino.
+//!
+//! The Arduino is usually connected to a serial port but the same protocol
+//! may run over any reliable byte stream, e.g. over TCP to a serial port
+//! shared with ser2net from another computer.
+
+use super::{discover, sim};
+use serialport::posix::TTYPort;
+use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortSettings, StopBits};
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Time after which a read returns an error with `ErrorKind::TimedOut` if no
+/// data are received.
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Device name which makes the connection discover the Arduino serial port.
+const AUTO_DEVICE: &str = "auto";
+/// Time in which a TCP connection has to be established.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// see: https://www.arduino.cc/en/Serial/Begin
+const SETTINGS: SerialPortSettings = SerialPortSettings {
+    baud_rate: 115_200,
+    data_bits: DataBits::Eight,
+    parity: Parity::None,
+    stop_bits: StopBits::One,
+    flow_control: FlowControl::None,
+    timeout: READ_TIMEOUT,
+};
+
+/// A bidirectional byte stream connected to the Arduino.
+///
+/// Reads time out after a while with an error of kind `ErrorKind::TimedOut`
+/// and reaching end of the stream means that the Arduino is gone. The file
+/// descriptor of the stream can be polled for readiness.
+pub trait Transport: Read + Write + Send + AsRawFd {
+    /// Create another handle to the same stream so that it can be read from
+    /// and written to from different threads.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+}
+
+/// Address of the Arduino.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Device {
+    /// Serial port device, for example `/dev/ttyACM0`.
+    Serial(String),
+    /// An existing pseudo terminal, given as `pty:///dev/pts/3`.
+    Pty(PathBuf),
+    /// TCP socket address, given as `tcp://10.0.0.5:4000`.
+    Tcp(String),
+    /// Unix domain socket path, given as `unix:///run/arduino.sock`.
+    Unix(PathBuf),
+    /// Simulated Arduino, given as `super::sim::DEVICE`.
+    Sim,
+    /// Serial port found by `super::discover::discover()`, given as `auto`.
+    /// The port is discovered again each time the device is opened.
+    Auto,
+}
+
+impl Device {
+    /// Open the device. A new simulated Arduino is started each time a
+    /// `Device::Sim` is opened.
+    ///
+    /// Note that Irro's firmware already responded to protocol negotiation
+    /// on a port returned for `Device::Auto`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the device couldn't be opened or connected
+    /// to.
+    pub fn open(&self) -> io::Result<Box<dyn Transport>> {
+        match self {
+            Device::Serial(path) => {
+                let port = TTYPort::open(Path::new(path), &SETTINGS)?;
+                Ok(Box::new(SerialTransport(port)))
+            }
+            Device::Pty(path) => {
+                let port = TTYPort::open(path, &SETTINGS)?;
+                Ok(Box::new(SerialTransport(port)))
+            }
+            Device::Tcp(address) => {
+                let mut last_error = None;
+                for address in address.to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                        Ok(stream) => {
+                            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                            // Commands are small and latency matters.
+                            stream.set_nodelay(true)?;
+                            return Ok(Box::new(StreamTransport(stream)));
+                        }
+                        Err(error) => last_error = Some(error),
+                    }
+                }
+                Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidInput, "Address resolved to nothing.")
+                }))
+            }
+            Device::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                Ok(Box::new(StreamTransport(stream)))
+            }
+            Device::Sim => {
+                let mut port = sim::open()?;
+                port.set_timeout(READ_TIMEOUT)?;
+                Ok(Box::new(SerialTransport(port)))
+            }
+            Device::Auto => discover::discover(),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = io::Error;
+
+    fn from_str(device: &str) -> Result<Self, Self::Err> {
+        if device == sim::DEVICE {
+            return Ok(Device::Sim);
+        }
+        if device == AUTO_DEVICE {
+            return Ok(Device::Auto);
+        }
+
+        let (scheme, address) = match device.find("://") {
+            Some(index) => (&device[..index], &device[(index + 3)..]),
+            None => return Ok(Device::Serial(device.to_owned())),
+        };
+
+        if address.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Device {} has no address.", device),
+            ));
+        }
+
+        match scheme {
+            "pty" => Ok(Device::Pty(PathBuf::from(address))),
+            "tcp" => Ok(Device::Tcp(address.to_owned())),
+            "unix" => Ok(Device::Unix(PathBuf::from(address))),
+            _ => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unsupported device scheme {}://.", scheme),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Device::Serial(path) => write!(f, "{}", path),
+            Device::Pty(path) => write!(f, "pty://{}", path.display()),
+            Device::Tcp(address) => write!(f, "tcp://{}", address),
+            Device::Unix(path) => write!(f, "unix://{}", path.display()),
+            Device::Sim => write!(f, "{}", sim::DEVICE),
+            Device::Auto => write!(f, "{}", AUTO_DEVICE),
+        }
+    }
+}
+
+/// Transport over a serial port (or a pseudo terminal).
+pub struct SerialTransport(pub TTYPort);
+
+impl Transport for SerialTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        // `SerialPort::try_clone()` boxes the clone, which hides its file
+        // descriptor, so the descriptor is duplicated here.
+        let fd = unsafe { libc::dup(self.0.as_raw_fd()) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut port = unsafe { TTYPort::from_raw_fd(fd) };
+        // The port is made exclusive by `from_raw_fd()`, the setting of the
+        // original handle is kept instead.
+        port.set_exclusive(self.0.exclusive())?;
+        port.set_timeout(self.0.timeout())?;
+        Ok(Box::new(SerialTransport(port)))
+    }
+}
+
+impl AsRawFd for SerialTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Transport over a socket. Socket read timeouts, which are reported as
+/// `ErrorKind::WouldBlock` on some platforms, are reported as
+/// `ErrorKind::TimedOut`.
+struct StreamTransport<S: Read + Write + Send>(S);
+
+impl Transport for StreamTransport<TcpStream> {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        let stream = self.0.try_clone()?;
+        Ok(Box::new(StreamTransport(stream)))
+    }
+}
+
+impl Transport for StreamTransport<UnixStream> {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        let stream = self.0.try_clone()?;
+        Ok(Box::new(StreamTransport(stream)))
+    }
+}
+
+impl<S: Read + Write + Send + AsRawFd> AsRawFd for StreamTransport<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl<S: Read + Write + Send> Read for StreamTransport<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                Err(io::Error::from(ErrorKind::TimedOut))
+            }
+            result => result,
+        }
+    }
+}
+
+impl<S: Read + Write + Send> Write for StreamTransport<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse_device() {
+        assert_eq!(
+            "/dev/ttyACM0".parse::<Device>().unwrap(),
+            Device::Serial("/dev/ttyACM0".to_owned())
+        );
+        assert_eq!(
+            "pty:///dev/pts/3".parse::<Device>().unwrap(),
+            Device::Pty(PathBuf::from("/dev/pts/3"))
+        );
+        assert_eq!(
+            "tcp://10.0.0.5:4000".parse::<Device>().unwrap(),
+            Device::Tcp("10.0.0.5:4000".to_owned())
+        );
+        assert_eq!(
+            "unix:///run/arduino.sock".parse::<Device>().unwrap(),
+            Device::Unix(PathBuf::from("/run/arduino.sock"))
+        );
+        assert_eq!("sim://".parse::<Device>().unwrap(), Device::Sim);
+        assert_eq!("auto".parse::<Device>().unwrap(), Device::Auto);
+
+        assert!("tcp://".parse::<Device>().is_err());
+        assert!("http://irro.cz".parse::<Device>().is_err());
+
+        for device in &[
+            "/dev/ttyACM0",
+            "pty:///dev/pts/3",
+            "tcp://irro:4000",
+            "sim://",
+            "auto",
+        ] {
+            assert_eq!(device.parse::<Device>().unwrap().to_string(), *device);
+        }
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let device = Device::Tcp(listener.local_addr().unwrap().to_string());
+
+        let mut transport = device.open().unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        transport.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0; 3];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+
+        let mut buf = [0; 3];
+        let mut clone = transport.try_clone().unwrap();
+        stream.write_all(&[4]).unwrap();
+        assert_eq!(clone.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 4);
+
+        let err = transport.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        drop(stream);
+        assert_eq!(transport.read(&mut buf).unwrap(), 0);
+    }
+}
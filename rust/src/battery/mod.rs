@@ -0,0 +1,318 @@
+//! Estimation of battery state of charge. See
+//! [hardware documentation](https://irro.cz/hw.html#hw-battery).
+//!
+//! Battery voltage and current are periodically read from Arduino. Charge
+//! consumed since the last reading is subtracted from the state of charge
+//! (coulomb counting) and the state of charge is slowly pulled towards an
+//! estimate from open circuit voltage so that the integration error doesn't
+//! accumulate.
+
+use crate::arduino::binary::{Handle, State};
+use crate::arduino::cmd::sensor::{Battery, BatteryCalibration, ReadBattery};
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of serially connected cells.
+const CELLS: f32 = 6.0;
+/// Battery capacity in ampere-hours.
+const CAPACITY: f32 = 3.3;
+/// Internal resistance of the whole pack (including wiring and BMS) in ohms,
+/// used to compensate voltage drop under load.
+const PACK_RESISTANCE: f32 = 0.3;
+/// Open circuit cell voltage and corresponding state of charge. The first
+/// point is the BMS cut-off voltage.
+const CELL_CURVE: [(f32, f32); 11] = [
+    (2.50, 0.00),
+    (3.00, 0.05),
+    (3.30, 0.10),
+    (3.50, 0.20),
+    (3.60, 0.30),
+    (3.70, 0.45),
+    (3.80, 0.60),
+    (3.90, 0.72),
+    (4.00, 0.83),
+    (4.10, 0.93),
+    (4.20, 1.00),
+];
+/// Fraction of the difference between voltage based and coulomb counted state
+/// of charge corrected per second.
+const VOLTAGE_BLEND: f32 = 0.002;
+/// Weight of a new current reading in the average current used for runtime
+/// estimation.
+const CURRENT_SMOOTHING: f32 = 0.05;
+/// Average currents below this (in amps) are considered idle and remaining
+/// runtime is not estimated.
+const IDLE_CURRENT: f32 = 0.05;
+/// Battery is considered low below this state of charge.
+const LOW_SOC: f32 = 0.15;
+/// Low battery flag is cleared only above this state of charge.
+const LOW_SOC_CLEAR: f32 = 0.20;
+/// Readings further apart than this are not integrated, e.g. after the
+/// connection to Arduino was lost.
+const MAX_STEP: Duration = Duration::from_secs(10);
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+/// Estimate is logged once per this number of samples.
+const LOG_PERIOD: u32 = 60;
+
+/// Estimated state of the battery.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    /// Last battery reading.
+    pub battery: Battery,
+    /// Time of the last battery reading.
+    pub time: Instant,
+    /// State of charge between 0.0 (empty) and 1.0 (full).
+    pub soc: f32,
+    /// Remaining runtime with the recent average current, `None` when the
+    /// robot is idle.
+    pub remaining: Option<Duration>,
+    /// True if the battery is low and should be charged.
+    pub low: bool,
+}
+
+/// Battery state of charge estimator.
+pub struct Estimator {
+    estimate: Option<Estimate>,
+    last_reading: Option<Instant>,
+    average_current: f32,
+}
+
+impl Estimator {
+    pub fn new() -> Self {
+        Estimator {
+            estimate: None,
+            last_reading: None,
+            average_current: 0.0,
+        }
+    }
+
+    /// Return the current estimate, `None` is returned before the first
+    /// reading.
+    pub fn estimate(&self) -> Option<Estimate> {
+        self.estimate
+    }
+
+    /// Update the estimate with a new battery reading taken at `now`.
+    pub fn update(&mut self, battery: Battery, now: Instant) -> Estimate {
+        let voltage_soc = voltage_soc(battery);
+
+        let (soc, low) = match (self.estimate, self.last_reading) {
+            (Some(estimate), Some(last_reading)) if now - last_reading <= MAX_STEP => {
+                let step = now - last_reading;
+                let seconds = step.as_secs() as f32 + step.subsec_millis() as f32 / 1000.0;
+                let current = (estimate.battery.current + battery.current) / 2.0;
+                let soc = estimate.soc - current * seconds / 3600.0 / CAPACITY;
+                let blend = (VOLTAGE_BLEND * seconds).min(1.0);
+                (soc + blend * (voltage_soc - soc), estimate.low)
+            }
+            (Some(estimate), _) => (voltage_soc, estimate.low),
+            (None, _) => {
+                self.average_current = battery.current;
+                (voltage_soc, false)
+            }
+        };
+        let soc = soc.max(0.0).min(1.0);
+
+        self.average_current += CURRENT_SMOOTHING * (battery.current - self.average_current);
+        let remaining = if self.average_current > IDLE_CURRENT {
+            let hours = soc * CAPACITY / self.average_current;
+            Some(Duration::from_secs((hours * 3600.0) as u64))
+        } else {
+            None
+        };
+
+        let estimate = Estimate {
+            battery,
+            time: now,
+            soc,
+            remaining,
+            low: soc < LOW_SOC || (low && soc < LOW_SOC_CLEAR),
+        };
+        self.estimate = Some(estimate);
+        self.last_reading = Some(now);
+        estimate
+    }
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shared latest battery estimate, it is updated by the thread started with
+/// `start_monitoring()`.
+#[derive(Clone)]
+pub struct BatteryWatch(Arc<Mutex<Option<Estimate>>>);
+
+impl BatteryWatch {
+    /// Return the latest estimate, `None` is returned if the battery has not
+    /// been read yet.
+    pub fn get(&self) -> Option<Estimate> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Start a new thread periodically reading battery from Arduino and updating
+/// the estimate.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino.
+///
+/// * `calibration` - Conversion of raw battery readings to volts and amps.
+pub fn start_monitoring(arduino: Handle, calibration: BatteryCalibration) -> BatteryWatch {
+    info!("Starting battery monitoring...");
+
+    let watch = BatteryWatch(Arc::new(Mutex::new(None)));
+    let shared = watch.clone();
+
+    thread::spawn(move || {
+        let mut estimator = Estimator::new();
+        let mut samples: u32 = 0;
+
+        loop {
+            thread::sleep(SAMPLE_PERIOD);
+            if arduino.state() != State::Connected {
+                continue;
+            }
+
+            let battery = match arduino.execute(&ReadBattery) {
+                Ok(raw) => raw.convert(&calibration),
+                Err(error) => {
+                    debug!("Battery couldn't be read: {}", error);
+                    continue;
+                }
+            };
+
+            let was_low = estimator.estimate().map_or(false, |estimate| estimate.low);
+            let estimate = estimator.update(battery, Instant::now());
+            *shared.0.lock().unwrap() = Some(estimate);
+
+            if estimate.low && !was_low {
+                warn!("Battery is low: {}", describe(&estimate));
+            } else if !estimate.low && was_low {
+                info!("Battery is no longer low: {}", describe(&estimate));
+            } else if samples % LOG_PERIOD == 0 {
+                info!("Battery: {}", describe(&estimate));
+            }
+            samples = samples.wrapping_add(1);
+        }
+    });
+
+    watch
+}
+
+fn describe(estimate: &Estimate) -> String {
+    let remaining = match estimate.remaining {
+        Some(remaining) => format!("{} min remaining", remaining.as_secs() / 60),
+        None => "idle".to_owned(),
+    };
+    format!(
+        "{:.0}% ({:.2} V, {:.2} A, {})",
+        estimate.soc * 100.0,
+        estimate.battery.voltage,
+        estimate.battery.current,
+        remaining
+    )
+}
+
+/// Return battery voltage compensated for the voltage drop caused by the
+/// current, id est approximate open circuit voltage.
+pub fn resting_voltage(battery: Battery) -> f32 {
+    battery.voltage + battery.current * PACK_RESISTANCE
+}
+
+/// Estimate state of charge from the resting battery voltage.
+fn voltage_soc(battery: Battery) -> f32 {
+    let cell_voltage = resting_voltage(battery) / CELLS;
+
+    let (first_voltage, first_soc) = CELL_CURVE[0];
+    if cell_voltage <= first_voltage {
+        return first_soc;
+    }
+
+    for window in CELL_CURVE.windows(2) {
+        let ((low_voltage, low_soc), (high_voltage, high_soc)) = (window[0], window[1]);
+        if cell_voltage <= high_voltage {
+            let ratio = (cell_voltage - low_voltage) / (high_voltage - low_voltage);
+            return low_soc + ratio * (high_soc - low_soc);
+        }
+    }
+
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(voltage: f32, current: f32) -> Battery {
+        Battery { voltage, current }
+    }
+
+    #[test]
+    fn test_voltage_soc() {
+        assert_eq!(voltage_soc(battery(25.2, 0.0)), 1.0);
+        assert_eq!(voltage_soc(battery(26.0, 0.0)), 1.0);
+        assert_eq!(voltage_soc(battery(15.0, 0.0)), 0.0);
+        assert_eq!(voltage_soc(battery(12.0, 0.0)), 0.0);
+        assert!((voltage_soc(battery(22.5, 0.0)) - 0.525).abs() < 0.001);
+        // Voltage drop under load is compensated.
+        assert!((voltage_soc(battery(21.9, 2.0)) - 0.525).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_coulomb_counting() {
+        let mut estimator = Estimator::new();
+        assert!(estimator.estimate().is_none());
+
+        let start = Instant::now();
+        let estimate = estimator.update(battery(22.5, 3.3), start);
+        assert!((estimate.soc - voltage_soc(battery(22.5, 3.3))).abs() < 0.001);
+
+        // 3.3 A for 10 seconds is 1/360 of the capacity, the voltage based
+        // estimate is the same so blending doesn't change anything.
+        let initial = estimate.soc;
+        let estimate = estimator.update(battery(22.5, 3.3), start + Duration::from_secs(10));
+        assert!((initial - estimate.soc - 1.0 / 360.0).abs() < 0.0001);
+
+        // Readings too far apart are not integrated.
+        let estimate = estimator.update(battery(22.5, 3.3), start + Duration::from_secs(60));
+        assert!((estimate.soc - initial).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_remaining() {
+        let mut estimator = Estimator::new();
+        let start = Instant::now();
+
+        let estimate = estimator.update(battery(25.2, 0.0), start);
+        assert_eq!(estimate.remaining, None);
+
+        let mut estimator = Estimator::new();
+        let estimate = estimator.update(battery(25.2 - 3.3 * PACK_RESISTANCE, 3.3), start);
+        assert!((estimate.soc - 1.0).abs() < 0.001);
+        let remaining = estimate.remaining.unwrap().as_secs();
+        assert!(remaining > 3590 && remaining <= 3600);
+    }
+
+    #[test]
+    fn test_low() {
+        let mut estimator = Estimator::new();
+        let start = Instant::now();
+
+        assert!(!estimator.update(battery(22.5, 0.0), start).low);
+        // Readings are too far apart, the estimate follows voltage.
+        let later = |secs| start + Duration::from_secs(secs);
+        assert!(estimator.update(battery(18.5, 0.0), later(20)).low);
+        // 20.7 V is 17.5%, not enough to clear the flag.
+        assert!(estimator.update(battery(20.7, 0.0), later(40)).low);
+        assert!(!estimator.update(battery(21.6, 0.0), later(60)).low);
+
+        let mut estimator = Estimator::new();
+        assert!(!estimator.update(battery(20.7, 0.0), start).low);
+    }
+}
@@ -0,0 +1,407 @@
+//! Motor calibration.
+//!
+//! Left and right tracks don't move at the same speed for the same power
+//! ratio and small ratios don't move the robot at all because of static
+//! friction. Power ratios requested by the API, the ramp and the safety
+//! supervisor are therefore corrected per side just before they are
+//! converted to a `MotorPowerRatio` command. The power cap of the safety
+//! supervisor applies to the corrected ratios.
+
+use crate::arduino::cmd::motor::MotorPowerRatio;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Largest allowed gain trim.
+const MAX_GAIN: f32 = 2.0;
+
+/// Calibration of motors on one side of the robot.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SideCalibration {
+    /// Multiplier of the requested power ratio, used to match speed of the
+    /// faster side to the slower one.
+    pub gain: f32,
+    /// Smallest power ratio which moves the track. Any non-zero ratio is
+    /// mapped above this value.
+    pub deadband: f32,
+    /// Reverse direction of the motors, e.g. when they are wired the other
+    /// way round.
+    pub inverted: bool,
+    /// Power ratio sent to Arduino never exceeds this value.
+    pub max_power: f32,
+}
+
+impl SideCalibration {
+    /// Convert a requested power ratio between -1.0 and 1.0 to the ratio
+    /// sent to Arduino. Zero is always kept so that stopping is exact.
+    pub fn apply(&self, ratio: f32) -> f32 {
+        if ratio == 0.0 {
+            return 0.0;
+        }
+
+        let magnitude = (ratio.abs() * self.gain).min(1.0);
+        let power = self.deadband + (self.max_power - self.deadband) * magnitude;
+        if (ratio < 0.0) != self.inverted {
+            -power
+        } else {
+            power
+        }
+    }
+
+    fn validate(&self, side: &str) -> Result<(), String> {
+        if !(self.gain.is_finite() && self.gain > 0.0 && self.gain <= MAX_GAIN) {
+            return Err(format!(
+                "Gain of {} motors must be a positive number not larger than {}.",
+                side, MAX_GAIN
+            ));
+        }
+        if !(self.max_power.is_finite() && self.max_power > 0.0 && self.max_power <= 1.0) {
+            return Err(format!(
+                "Max power of {} motors must be a number between 0 and 1.",
+                side
+            ));
+        }
+        if !(self.deadband.is_finite() && self.deadband >= 0.0 && self.deadband < self.max_power) {
+            return Err(format!(
+                "Deadband of {} motors must be a non-negative number smaller than \
+                 max power.",
+                side
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SideCalibration {
+    fn default() -> Self {
+        SideCalibration {
+            gain: 1.0,
+            deadband: 0.0,
+            inverted: false,
+            max_power: 1.0,
+        }
+    }
+}
+
+/// Calibration of left and right motors. The default calibration doesn't
+/// change power ratios.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub left: SideCalibration,
+    pub right: SideCalibration,
+}
+
+impl Calibration {
+    /// Create calibrated motor command from requested power ratios.
+    ///
+    /// # Arguments
+    ///
+    /// * `left` - requested left power ratio.
+    ///
+    /// * `right` - requested right power ratio.
+    ///
+    /// * `power_cap` - calibrated power ratios never exceed this magnitude,
+    ///   see `crate::supervisor::SafetyWatch::power_cap()`.
+    pub fn command(&self, left: f32, right: f32, power_cap: f32) -> MotorPowerRatio {
+        let cap = |power: f32| power.max(-power_cap).min(power_cap);
+        MotorPowerRatio::from_floats(cap(self.left.apply(left)), cap(self.right.apply(right)))
+    }
+
+    /// Return an error describing the problem if any of the values is out of
+    /// its range.
+    pub fn validate(&self) -> Result<(), String> {
+        self.left.validate("left")?;
+        self.right.validate("right")
+    }
+}
+
+/// Calibration is written as one name=value pair per line, for example
+/// `left.gain=0.95`.
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(name, side) in &[("left", &self.left), ("right", &self.right)] {
+            writeln!(f, "{}.gain={}", name, side.gain)?;
+            writeln!(f, "{}.deadband={}", name, side.deadband)?;
+            writeln!(f, "{}.inverted={}", name, side.inverted)?;
+            writeln!(f, "{}.max_power={}", name, side.max_power)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse name=value pairs separated by commas or new lines. Values which are
+/// not given are kept at their defaults.
+impl FromStr for Calibration {
+    type Err = io::Error;
+
+    fn from_str(calibration: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| io::Error::new(ErrorKind::InvalidInput, reason);
+
+        let mut parsed = Calibration::default();
+        let pairs = calibration
+            .split(&[',', '\n'][..])
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty());
+        for pair in pairs {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(invalid(format!("Invalid calibration \"{}\".", pair))),
+            };
+
+            let mut name_parts = name.splitn(2, '.');
+            let side = match name_parts.next() {
+                Some("left") => &mut parsed.left,
+                Some("right") => &mut parsed.right,
+                _ => return Err(invalid(format!("Unknown calibration \"{}\".", name))),
+            };
+            let field = match name_parts.next() {
+                Some("inverted") => {
+                    side.inverted = value
+                        .parse()
+                        .map_err(|_| invalid(format!("Invalid calibration \"{}\".", pair)))?;
+                    continue;
+                }
+                Some("gain") => &mut side.gain,
+                Some("deadband") => &mut side.deadband,
+                Some("max_power") => &mut side.max_power,
+                _ => return Err(invalid(format!("Unknown calibration \"{}\".", name))),
+            };
+            *field = value
+                .parse()
+                .map_err(|_| invalid(format!("Invalid calibration \"{}\".", pair)))?;
+        }
+
+        parsed.validate().map_err(invalid)?;
+        Ok(parsed)
+    }
+}
+
+/// Shared motor calibration, optionally persisted to a file.
+#[derive(Clone)]
+pub struct CalibrationStore {
+    calibration: Arc<Mutex<Calibration>>,
+    /// Held by `set()` while the calibration is replaced and stored, the file
+    /// is written outside of the calibration lock, which is taken with every
+    /// motor command.
+    write: Arc<Mutex<()>>,
+    path: Option<PathBuf>,
+}
+
+impl CalibrationStore {
+    /// Load calibration from a file. Default calibration is used if the path
+    /// is `None` or the file doesn't exist yet, it is created with the first
+    /// call to `set()`.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned if the file couldn't be read or if it doesn't
+    /// contain a valid calibration.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let calibration = match path {
+            Some(path) if path.exists() => {
+                let calibration: Calibration = fs::read_to_string(path)?.parse()?;
+                info!("Motor calibration loaded from {}.", path.display());
+                calibration
+            }
+            _ => Calibration::default(),
+        };
+
+        Ok(CalibrationStore {
+            calibration: Arc::new(Mutex::new(calibration)),
+            write: Arc::new(Mutex::new(())),
+            path: path.map(Path::to_owned),
+        })
+    }
+
+    /// Return current calibration.
+    pub fn get(&self) -> Calibration {
+        *self.calibration.lock().unwrap()
+    }
+
+    /// Replace current calibration and store it to the file.
+    ///
+    /// # Errors
+    ///
+    /// An error of kind `InvalidInput` is returned if the calibration is not
+    /// valid, in which case the current calibration is kept. Other errors
+    /// mean that the new calibration is in use but it couldn't be stored.
+    pub fn set(&self, calibration: Calibration) -> io::Result<()> {
+        calibration
+            .validate()
+            .map_err(|reason| io::Error::new(ErrorKind::InvalidInput, reason))?;
+        // Concurrent calls would otherwise share the temporary file and the
+        // file could end up with other calibration than the one in use.
+        let _write = self.write.lock().unwrap();
+        *self.calibration.lock().unwrap() = calibration;
+
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        // Write to a temporary file first so that a crash never leaves a
+        // truncated calibration behind.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, calibration.to_string())?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Create calibrated motor command from requested power ratios, see
+    /// `Calibration::command()`.
+    pub fn command(&self, left: f32, right: f32, power_cap: f32) -> MotorPowerRatio {
+        self.get().command(left, right, power_cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduino::cmd::ArduinoCommand;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn test_apply() {
+        let side = SideCalibration::default();
+        assert_eq!(side.apply(0.5), 0.5);
+        assert_eq!(side.apply(-1.0), -1.0);
+
+        let side = SideCalibration {
+            gain: 0.9,
+            deadband: 0.1,
+            inverted: true,
+            max_power: 0.8,
+        };
+        assert_eq!(side.apply(0.0), 0.0);
+        assert!((side.apply(0.01) + 0.10630).abs() < 1e-5);
+        assert!((side.apply(-0.5) - 0.415).abs() < 1e-5);
+        assert!((side.apply(1.0) + 0.73).abs() < 1e-5);
+
+        // Gain trim is capped by max power.
+        let side = SideCalibration {
+            gain: 1.5,
+            max_power: 0.8,
+            ..SideCalibration::default()
+        };
+        assert_eq!(side.apply(1.0), 0.8);
+        assert_eq!(side.apply(-0.8), -0.8);
+    }
+
+    #[test]
+    fn test_power_cap() {
+        let payload = |left, right| MotorPowerRatio::from_floats(left, right).payload();
+        let mut calibration = Calibration::default();
+        calibration.left.deadband = 0.1;
+        calibration.right.gain = 1.5;
+
+        let command = calibration.command(0.5, 0.4, 1.0);
+        assert_eq!(command.payload(), payload(0.55, 0.6));
+        // Neither the deadband nor the gain trim exceed the cap.
+        let command = calibration.command(0.5, 0.4, 0.5);
+        assert_eq!(command.payload(), payload(0.5, 0.5));
+        let command = calibration.command(-0.5, 0.1, 0.5);
+        assert_eq!(command.payload(), payload(-0.5, 0.15));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Calibration::default().validate().is_ok());
+
+        let mut calibration = Calibration::default();
+        calibration.left.gain = 0.0;
+        assert!(calibration.validate().is_err());
+
+        let mut calibration = Calibration::default();
+        calibration.right.deadband = 0.5;
+        calibration.right.max_power = 0.5;
+        assert!(calibration.validate().is_err());
+
+        let mut calibration = Calibration::default();
+        calibration.right.max_power = 1.1;
+        assert!(calibration.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let calibration: Calibration = "left.gain=0.95, right.deadband=0.08,right.inverted=true"
+            .parse()
+            .unwrap();
+        assert_eq!(calibration.left.gain, 0.95);
+        assert_eq!(calibration.left.deadband, 0.0);
+        assert_eq!(calibration.right.deadband, 0.08);
+        assert!(calibration.right.inverted);
+        assert_eq!(
+            calibration.to_string().parse::<Calibration>().unwrap(),
+            calibration
+        );
+
+        assert!("left.gain".parse::<Calibration>().is_err());
+        assert!("middle.gain=1".parse::<Calibration>().is_err());
+        assert!("left.trim=1".parse::<Calibration>().is_err());
+        assert!("left.inverted=yes".parse::<Calibration>().is_err());
+        assert!("left.max_power=1.5".parse::<Calibration>().is_err());
+    }
+
+    #[test]
+    fn test_store() {
+        let path = env::temp_dir().join(format!("irro-calibration-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = CalibrationStore::load(Some(&path)).unwrap();
+        assert_eq!(store.get(), Calibration::default());
+
+        let mut calibration = Calibration::default();
+        calibration.left.gain = 0.95;
+        calibration.right.deadband = 0.08;
+        calibration.right.inverted = true;
+        store.set(calibration).unwrap();
+
+        let mut invalid = calibration;
+        invalid.left.max_power = 0.0;
+        assert_eq!(
+            store.set(invalid).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(store.get(), calibration);
+
+        let loaded = CalibrationStore::load(Some(&path)).unwrap();
+        assert_eq!(loaded.get(), calibration);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_store_concurrent() {
+        let path = env::temp_dir().join(format!(
+            "irro-calibration-concurrent-{}.txt",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let store = CalibrationStore::load(Some(&path)).unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for j in 0..20 {
+                        let mut calibration = Calibration::default();
+                        calibration.left.gain = 0.5 + (i * 20 + j) as f32 / 1000.0;
+                        store.set(calibration).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let loaded = CalibrationStore::load(Some(&path)).unwrap();
+        assert_eq!(loaded.get(), store.get());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! Differential drive kinematics of Irro's tracked chassis.
+//!
+//! Linear velocity and angular (yaw) rate of the robot are converted into
+//! left and right motor power ratios. Power ratio is assumed to be
+//! proportional to track speed.
+
+/// Geometry and speed of the chassis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chassis {
+    /// Distance between centers of left and right tracks in meters.
+    pub track_width: f32,
+    /// Track speed at full motor power in meters per second.
+    pub max_speed: f32,
+}
+
+/// Motor power ratios corresponding to a velocity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ratios {
+    /// Left motors power ratio between -1.0 and 1.0.
+    pub left: f32,
+    /// Right motors power ratio between -1.0 and 1.0.
+    pub right: f32,
+    /// True if the velocity couldn't be reached and was scaled down.
+    pub saturated: bool,
+}
+
+impl Chassis {
+    /// Create new chassis description.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if track width or max speed is not a positive
+    /// number.
+    pub fn new(track_width: f32, max_speed: f32) -> Self {
+        if !(track_width.is_finite() && track_width > 0.0) {
+            panic!("Track width must be a positive number.");
+        }
+        if !(max_speed.is_finite() && max_speed > 0.0) {
+            panic!("Max speed must be a positive number.");
+        }
+
+        Chassis {
+            track_width,
+            max_speed,
+        }
+    }
+
+    /// Convert a velocity to motor power ratios. Velocities which would
+    /// require more than full power are scaled down so that turning curvature
+    /// (linear velocity to angular rate ratio) is preserved.
+    ///
+    /// # Arguments
+    ///
+    /// * `linear` - forward velocity in meters per second, negative for
+    ///   backward.
+    ///
+    /// * `angular` - yaw rate in radians per second, positive for turning
+    ///   left (counter-clockwise).
+    pub fn ratios(&self, linear: f32, angular: f32) -> Ratios {
+        let turn = angular * self.track_width / 2.0;
+        let left = (linear - turn) / self.max_speed;
+        let right = (linear + turn) / self.max_speed;
+
+        let max = left.abs().max(right.abs());
+        if max > 1.0 {
+            Ratios {
+                left: left / max,
+                right: right / max,
+                saturated: true,
+            }
+        } else {
+            Ratios {
+                left,
+                right,
+                saturated: false,
+            }
+        }
+    }
+}
+
+impl Default for Chassis {
+    fn default() -> Self {
+        Chassis {
+            track_width: 0.25,
+            max_speed: 0.5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_ratios(ratios: Ratios, left: f32, right: f32, saturated: bool) {
+        assert!((ratios.left - left).abs() < 1e-5, "{:?}", ratios);
+        assert!((ratios.right - right).abs() < 1e-5, "{:?}", ratios);
+        assert_eq!(ratios.saturated, saturated);
+    }
+
+    #[test]
+    fn test_ratios() {
+        let chassis = Chassis::new(0.25, 0.5);
+        assert_ratios(chassis.ratios(0.0, 0.0), 0.0, 0.0, false);
+        assert_ratios(chassis.ratios(0.25, 0.0), 0.5, 0.5, false);
+        assert_ratios(chassis.ratios(-0.5, 0.0), -1.0, -1.0, false);
+        // Turning left on the spot.
+        assert_ratios(chassis.ratios(0.0, 2.0), -0.5, 0.5, false);
+        assert_ratios(chassis.ratios(0.25, 1.0), 0.25, 0.75, false);
+        assert_ratios(chassis.ratios(0.25, -1.0), 0.75, 0.25, false);
+    }
+
+    #[test]
+    fn test_saturation() {
+        let chassis = Chassis::new(0.25, 0.5);
+        assert_ratios(chassis.ratios(1.0, 0.0), 1.0, 1.0, true);
+        // Curvature is preserved: the right track is three times faster.
+        assert_ratios(chassis.ratios(0.5, 2.0), 1.0 / 3.0, 1.0, true);
+        assert_ratios(chassis.ratios(0.0, -10.0), 1.0, -1.0, true);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_invalid_chassis() {
+        Chassis::new(0.0, 0.5);
+    }
+}
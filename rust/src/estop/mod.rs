@@ -0,0 +1,416 @@
+//! Latched emergency stop.
+//!
+//! Engaging the emergency stop stops the motors before any other waiting
+//! command, latches the emergency stop of Arduino so that the firmware
+//! refuses motor power as well and makes the server reject all motor
+//! commands. Unlike a zero motor power ratio, no client can override it, the
+//! emergency stop has to be explicitly released.
+
+use crate::arduino::binary::{self, Handle, ResponseError};
+use crate::arduino::cmd::motor::{EmergencyStop, MotorPowerRatio};
+use crate::ramp::Ramp;
+use crate::supervisor::SafetyWatch;
+use crate::watchdog::MotorWatchdog;
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Period in which the latch of an engaged emergency stop is checked and
+/// latched again if it was not confirmed, e.g. after Arduino reconnected.
+const CONFIRM_PERIOD: Duration = Duration::from_millis(500);
+
+/// State of the emergency stop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub engaged: bool,
+    /// Time the emergency stop was engaged.
+    pub since: Option<SystemTime>,
+    /// True if Arduino confirmed that its emergency stop is latched.
+    pub confirmed: bool,
+}
+
+struct State {
+    status: Status,
+    /// Incremented with each engagement and release. A response to the
+    /// latch command updates the status only if no newer engagement or
+    /// release was made in the meantime.
+    generation: u64,
+}
+
+/// Handle of the emergency stop.
+#[derive(Clone)]
+pub struct Estop {
+    state: Arc<Mutex<State>>,
+    arduino: Handle,
+    safety: SafetyWatch,
+    ramp: Ramp,
+    watchdog: MotorWatchdog,
+}
+
+impl Estop {
+    /// Create released emergency stop. A thread is started which latches the
+    /// emergency stop of Arduino again whenever it wasn't confirmed, e.g.
+    /// after Arduino reconnected, while the emergency stop is engaged.
+    ///
+    /// # Arguments
+    ///
+    /// * `arduino` - Handle of the connection to Arduino.
+    ///
+    /// * `safety` - Safety supervisor, stopped motors are recorded there so
+    ///   that it doesn't resume them.
+    ///
+    /// * `ramp` - Motor ramp, it is reset so that it doesn't resume the
+    ///   motors.
+    ///
+    /// * `watchdog` - Motor watchdog, it is disarmed once the motors are
+    ///   stopped.
+    pub fn new(arduino: Handle, safety: SafetyWatch, ramp: Ramp, watchdog: MotorWatchdog) -> Self {
+        let estop = Estop {
+            state: Arc::new(Mutex::new(State {
+                status: Status {
+                    engaged: false,
+                    since: None,
+                    confirmed: false,
+                },
+                generation: 0,
+            })),
+            arduino: arduino.clone(),
+            safety,
+            ramp,
+            watchdog,
+        };
+
+        let state = Arc::downgrade(&estop.state);
+        thread::spawn(move || confirm_latch(&state, &arduino));
+        estop
+    }
+
+    /// Stop the motors and latch the emergency stop. Engaging an already
+    /// engaged emergency stop stops the motors again.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - who engaged the emergency stop, it is logged.
+    pub fn engage(&self, source: &str) -> Status {
+        let (pending, generation) = {
+            let mut state = self.state.lock().unwrap();
+            if !state.status.engaged {
+                error!("Emergency stop engaged by {}.", source);
+                state.status.engaged = true;
+                state.status.since = Some(SystemTime::now());
+            }
+            state.generation += 1;
+
+            self.safety.limit_motors(0.0, 0.0).unwrap_or((0.0, 0.0));
+            self.ramp.reset(0.0, 0.0);
+            // The stop replaces all waiting motor commands and it is sent
+            // first, it is not waited for so that the latch follows
+            // immediately.
+            if let Err(error) = self.arduino.send(&MotorPowerRatio::from_floats(0.0, 0.0)) {
+                error!(
+                    "Motors couldn't be stopped by the emergency stop: {}",
+                    error
+                );
+            }
+            (
+                self.arduino.send(&EmergencyStop::new(true)),
+                state.generation,
+            )
+        };
+
+        // Neither the watchdog nor Arduino are waited for with the lock held,
+        // motor commands are rejected in the meantime.
+        self.watchdog.feed(true, None);
+        let confirmed = match pending.and_then(binary::Pending::wait) {
+            Ok(latched) => latched,
+            Err(error) => {
+                warn!("Arduino emergency stop couldn't be latched: {}", error);
+                false
+            }
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            state.status.confirmed = confirmed;
+        }
+        state.status
+    }
+
+    /// Release the emergency stop so that motor commands are accepted again.
+    /// The emergency stop stays engaged if it was engaged again while being
+    /// released.
+    ///
+    /// # Errors
+    ///
+    /// An error is returned, and the emergency stop is kept engaged, if
+    /// Arduino didn't confirm that its emergency stop was released.
+    pub fn release(&self) -> Result<Status, ResponseError> {
+        let (pending, generation) = {
+            let state = self.state.lock().unwrap();
+            if !state.status.engaged {
+                return Ok(state.status);
+            }
+            (
+                self.arduino.send(&EmergencyStop::new(false)),
+                state.generation,
+            )
+        };
+
+        if pending?.wait()? {
+            return Err(ResponseError::ProtocolError(
+                "Arduino kept its emergency stop latched.".to_owned(),
+            ));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation == generation {
+            info!("Emergency stop released.");
+            state.generation += 1;
+            state.status = Status {
+                engaged: false,
+                since: None,
+                confirmed: false,
+            };
+        }
+        Ok(state.status)
+    }
+
+    /// Return current state of the emergency stop.
+    pub fn status(&self) -> Status {
+        self.state.lock().unwrap().status
+    }
+
+    /// Return an error describing the emergency stop if it is engaged.
+    pub fn check(&self) -> Result<(), String> {
+        self.guard(|| ())
+    }
+
+    /// Call `dispatch` unless the emergency stop is engaged, an error
+    /// describing the emergency stop is returned otherwise.
+    ///
+    /// The emergency stop is not engaged while `dispatch` runs so motor
+    /// commands sent by it are always sent before the stop of `engage()`.
+    /// `dispatch` should only send commands, not wait for their responses.
+    pub fn guard<F, R>(&self, dispatch: F) -> Result<R, String>
+    where
+        F: FnOnce() -> R,
+    {
+        self.dispatch(|engaged| {
+            if engaged {
+                Err(
+                    "Emergency stop is engaged, motor commands are rejected until it \
+                     is released."
+                        .to_owned(),
+                )
+            } else {
+                Ok(dispatch())
+            }
+        })
+    }
+
+    /// Call `dispatch` with the emergency stop locked, it is passed true if
+    /// the emergency stop is engaged. Unlike with `guard()`, `dispatch` is
+    /// called even then so that e.g. the safety supervisor can update its
+    /// state, it must not send motor commands in such a case.
+    pub(crate) fn dispatch<F, R>(&self, dispatch: F) -> R
+    where
+        F: FnOnce(bool) -> R,
+    {
+        let state = self.state.lock().unwrap();
+        dispatch(state.status.engaged)
+    }
+}
+
+/// Periodically latch the emergency stop of Arduino while it is engaged and
+/// not confirmed. The latch is unconfirmed whenever Arduino is not connected
+/// because it could have been reset in the meantime. Return once all handles
+/// of the emergency stop are dropped.
+fn confirm_latch(state: &Weak<Mutex<State>>, arduino: &Handle) {
+    loop {
+        thread::sleep(CONFIRM_PERIOD);
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let (pending, generation) = {
+            let mut state = state.lock().unwrap();
+            if !state.status.engaged {
+                continue;
+            }
+            if arduino.state() != binary::State::Connected {
+                state.status.confirmed = false;
+                continue;
+            }
+            if state.status.confirmed {
+                continue;
+            }
+            (arduino.send(&EmergencyStop::new(true)), state.generation)
+        };
+
+        match pending.and_then(binary::Pending::wait) {
+            Ok(true) => {
+                let mut state = state.lock().unwrap();
+                if state.generation == generation {
+                    info!("Arduino emergency stop latched again.");
+                    state.status.confirmed = true;
+                }
+            }
+            Ok(false) => warn!("Arduino didn't latch its emergency stop."),
+            Err(error) => warn!("Arduino emergency stop couldn't be latched: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduino::binary::StateWatch;
+    use crate::calibration::CalibrationStore;
+    use crate::heading::{Gains, HeadingHold};
+    use crate::imu::ImuWatch;
+    use crate::{ramp, supervisor, watchdog};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Start an emergency stop with a simulated Arduino which responds with
+    /// `respond(command, payload)`. Return the emergency stop, its ramp and
+    /// watchdog and a receiver of all commands sent to Arduino.
+    fn start<F>(respond: F) -> (Estop, Ramp, MotorWatchdog, mpsc::Receiver<(u16, Vec<u8>)>)
+    where
+        F: Fn(u16, &[u8]) -> Result<Vec<u8>, ResponseError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let arduino = Handle::new(sender, StateWatch::new());
+        let (commands_sender, commands) = mpsc::channel();
+        thread::spawn(move || {
+            for message in receiver {
+                let (command, payload, _, sender) = message.destructure();
+                sender.send(respond(command, &payload)).unwrap_or(());
+                commands_sender.send((command, payload)).unwrap();
+            }
+        });
+
+        let safety = SafetyWatch::new(0.5);
+        let heading = HeadingHold::new(ImuWatch::new(), Gains::default());
+        let ramp = ramp::start(
+            arduino.clone(),
+            None,
+            CalibrationStore::load(None).unwrap(),
+            heading,
+            safety.clone(),
+        );
+        let watchdog = watchdog::start(Duration::from_secs(1), || ());
+        let estop = Estop::new(arduino, safety, ramp.clone(), watchdog.clone());
+        (estop, ramp, watchdog, commands)
+    }
+
+    /// Arduino responds with the resulting latch state.
+    fn latch(command: u16, payload: &[u8]) -> Result<Vec<u8>, ResponseError> {
+        if command == 0x0101 {
+            Ok(payload.to_vec())
+        } else {
+            Ok(vec![])
+        }
+    }
+
+    #[test]
+    fn test_estop() {
+        let (estop, ramp, watchdog, commands) = start(latch);
+        assert!(estop.check().is_ok());
+
+        ramp.request(0.5, 0.5);
+        watchdog.feed(false, None);
+        let status = estop.engage("test");
+        assert!(status.engaged && status.confirmed);
+        assert!(status.since.is_some());
+        assert!(estop.check().is_err());
+        assert_eq!(commands.recv().unwrap(), (0x0100, vec![0, 0, 0, 0]));
+        assert_eq!(commands.recv().unwrap(), (0x0101, vec![1]));
+        assert_eq!(ramp.status().requested, (0.0, 0.0));
+        assert_eq!(watchdog.status().remaining, None);
+
+        let status = estop.release().unwrap();
+        assert!(!status.engaged);
+        assert_eq!(commands.recv().unwrap(), (0x0101, vec![0]));
+        assert!(estop.check().is_ok());
+    }
+
+    #[test]
+    fn test_guard() {
+        let (estop, _, _, commands) = start(|command, payload| {
+            if command == 0x0101 {
+                // Slow Arduino doesn't block checks of the emergency stop.
+                thread::sleep(Duration::from_millis(300));
+            }
+            latch(command, payload)
+        });
+
+        let (engaged_sender, engaged) = mpsc::channel();
+        let engaging = estop.clone();
+        let result = estop.guard(|| {
+            thread::spawn(move || {
+                engaging.engage("test");
+                engaged_sender.send(()).unwrap();
+            });
+            thread::sleep(Duration::from_millis(100));
+            // The emergency stop is engaged only after the dispatch.
+            assert!(commands.try_recv().is_err());
+        });
+        assert!(result.is_ok());
+
+        assert_eq!(commands.recv().unwrap(), (0x0100, vec![0, 0, 0, 0]));
+        assert!(estop.guard(|| ()).is_err());
+        assert!(!estop.status().confirmed);
+        engaged.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(estop.status().confirmed);
+    }
+
+    #[test]
+    fn test_confirm_latch() {
+        let failed = Mutex::new(false);
+        let (estop, _, _, commands) = start(move |command, payload| {
+            let mut failed = failed.lock().unwrap();
+            if command == 0x0101 && !*failed {
+                *failed = true;
+                return Err(ResponseError::Disconnected);
+            }
+            latch(command, payload)
+        });
+
+        let status = estop.engage("test");
+        assert!(status.engaged && !status.confirmed);
+        assert_eq!(commands.recv().unwrap(), (0x0100, vec![0, 0, 0, 0]));
+        assert_eq!(commands.recv().unwrap(), (0x0101, vec![1]));
+
+        // The latch is sent again and confirmed.
+        let command = commands.recv_timeout(CONFIRM_PERIOD * 3).unwrap();
+        assert_eq!(command, (0x0101, vec![1]));
+        thread::sleep(Duration::from_millis(50));
+        assert!(estop.status().confirmed);
+    }
+
+    #[test]
+    fn test_safety_dispatch() {
+        let (estop, ramp, _, commands) = start(latch);
+        let safety = SafetyWatch::new(0.5);
+        let calibration = CalibrationStore::load(None).unwrap();
+        let arduino = estop.arduino.clone();
+        let set_motors = |motors| {
+            supervisor::set_motors(&arduino, &estop, &safety, &ramp, &calibration, || motors)
+        };
+
+        set_motors(Some((-0.5, 0.0)));
+        assert_eq!(commands.recv().unwrap(), (0x0100, vec![0xc0, 0, 0, 0]));
+        assert_eq!(ramp.status().applied, (-0.5, 0.0));
+
+        // Nothing is sent after the emergency stop.
+        estop.engage("test");
+        while commands.try_recv().is_ok() {}
+        set_motors(Some((-0.5, 0.0)));
+        assert!(commands.try_recv().is_err());
+        assert_eq!(ramp.status().applied, (0.0, 0.0));
+    }
+}
@@ -0,0 +1,25 @@
+//! Geometry helpers shared by pose and orientation estimates.
+
+use std::f64::consts::PI;
+
+/// Return the angle (in radians) moved to interval from -PI to PI.
+pub fn normalize_angle(angle: f64) -> f64 {
+    let angle = (angle + PI) % (2.0 * PI);
+    if angle < 0.0 {
+        angle + PI
+    } else {
+        angle - PI
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_angle() {
+        assert!((normalize_angle(3.0 * PI / 2.0) + PI / 2.0).abs() < 1e-9);
+        assert!((normalize_angle(-3.0 * PI / 2.0) - PI / 2.0).abs() < 1e-9);
+        assert!((normalize_angle(0.5) - 0.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,383 @@
+//! Heading hold for straight driving.
+//!
+//! Left and right tracks don't move at exactly the same speed even with
+//! calibrated motors and the difference changes with the terrain. While
+//! heading hold is enabled and both motors are driven with the same power
+//! ratio, a PID loop corrects the left/right imbalance so that the yaw rate
+//! measured by the IMU (see `crate::imu`) stays zero. Integral of the yaw rate
+//! is the heading drift so the loop also returns the robot to its original
+//! heading. The corrected ratio is sent every tick of the motor ramp (see
+//! `crate::ramp`).
+
+use crate::imu::ImuWatch;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Maximum correction added to and subtracted from the power ratios.
+const MAX_CORRECTION: f32 = 0.3;
+/// Older yaw rate is not used, the correction is paused until a fresh one is
+/// available.
+const MAX_YAW_RATE_AGE: Duration = Duration::from_millis(100);
+/// The loop starts over if it was not updated for this long, e.g. after the
+/// robot was stopped.
+const MAX_GAP: Duration = Duration::from_millis(200);
+
+/// Gains of the PID loop. The error is yaw rate in rad/s and the output is a
+/// power ratio correction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gains {
+    pub proportional: f32,
+    pub integral: f32,
+    pub derivative: f32,
+}
+
+impl Default for Gains {
+    fn default() -> Self {
+        Gains {
+            proportional: 0.2,
+            integral: 0.5,
+            derivative: 0.0,
+        }
+    }
+}
+
+/// Parse the gains from three comma separated numbers: proportional, integral
+/// and derivative gain.
+impl FromStr for Gains {
+    type Err = io::Error;
+
+    fn from_str(gains: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Heading hold gains must be three comma separated non-negative numbers, \
+                     got \"{}\".",
+                    gains
+                ),
+            )
+        };
+
+        let values = gains
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|_| invalid())?;
+        if values.len() != 3
+            || values
+                .iter()
+                .any(|value| !(value.is_finite() && *value >= 0.0))
+        {
+            return Err(invalid());
+        }
+
+        Ok(Gains {
+            proportional: values[0],
+            integral: values[1],
+            derivative: values[2],
+        })
+    }
+}
+
+struct Pid {
+    gains: Gains,
+    integral: f32,
+    last_error: Option<f32>,
+}
+
+impl Pid {
+    fn new(gains: Gains) -> Self {
+        Pid {
+            gains,
+            integral: 0.0,
+            last_error: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last_error = None;
+    }
+
+    /// Return the output for an error and time in seconds since the previous
+    /// update.
+    fn update(&mut self, error: f32, seconds: f32) -> f32 {
+        let gains = self.gains;
+        // The integral alone never saturates the output so that it doesn't
+        // wind up.
+        if gains.integral > 0.0 {
+            let limit = MAX_CORRECTION / gains.integral;
+            self.integral = (self.integral + error * seconds).max(-limit).min(limit);
+        }
+        let derivative = match self.last_error {
+            Some(last_error) if seconds > 0.0 => (error - last_error) / seconds,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+
+        let output = gains.proportional * error
+            + gains.integral * self.integral
+            + gains.derivative * derivative;
+        output.max(-MAX_CORRECTION).min(MAX_CORRECTION)
+    }
+}
+
+/// State of the heading hold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    pub enabled: bool,
+    /// True if the motor power ratios are being corrected at the moment.
+    pub active: bool,
+    /// The last yaw rate in rad/s used by the loop.
+    pub yaw_rate: Option<f32>,
+    /// Correction added to the right and subtracted from the left power
+    /// ratio.
+    pub correction: f32,
+}
+
+struct State {
+    enabled: bool,
+    pid: Pid,
+    yaw_rate: Option<f32>,
+    correction: f32,
+    last_update: Option<Instant>,
+}
+
+impl State {
+    fn stop(&mut self) {
+        self.pid.reset();
+        self.correction = 0.0;
+        self.last_update = None;
+    }
+}
+
+/// Handle of the heading hold.
+#[derive(Clone)]
+pub struct HeadingHold {
+    state: Arc<Mutex<State>>,
+    imu: ImuWatch,
+}
+
+impl HeadingHold {
+    /// Create disabled heading hold.
+    ///
+    /// # Arguments
+    ///
+    /// * `imu` - IMU providing the yaw rate.
+    ///
+    /// * `gains` - Gains of the PID loop.
+    pub fn new(imu: ImuWatch, gains: Gains) -> Self {
+        HeadingHold {
+            state: Arc::new(Mutex::new(State {
+                enabled: false,
+                pid: Pid::new(gains),
+                yaw_rate: None,
+                correction: 0.0,
+                last_update: None,
+            })),
+            imu,
+        }
+    }
+
+    /// Enable or disable the heading hold.
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.enabled = enabled;
+        if !enabled {
+            state.stop();
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.state.lock().unwrap().enabled
+    }
+
+    /// Return true if a power ratio would be corrected, id est the heading
+    /// hold is enabled and the robot is driven straight.
+    pub fn applies(&self, (left, right): (f32, f32)) -> bool {
+        self.enabled() && left == right && left != 0.0
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.state.lock().unwrap();
+        Status {
+            enabled: state.enabled,
+            active: state.last_update.is_some(),
+            yaw_rate: state.yaw_rate,
+            correction: state.correction,
+        }
+    }
+
+    /// Return a power ratio corrected with the latest yaw rate. The ratio is
+    /// returned unchanged if the heading hold doesn't apply to it or if
+    /// there is no fresh yaw rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `ratio` - left and right power ratio, already limited by the safety
+    ///   supervisor.
+    ///
+    /// * `power_cap` - maximum magnitude of the corrected ratios, see
+    ///   `crate::supervisor::SafetyWatch::power_cap()`.
+    pub fn correct(&self, ratio: (f32, f32), power_cap: f32) -> (f32, f32) {
+        let yaw_rate = self.imu.yaw_rate(MAX_YAW_RATE_AGE);
+        self.correct_at(ratio, power_cap, yaw_rate, Instant::now())
+    }
+
+    fn correct_at(
+        &self,
+        ratio: (f32, f32),
+        power_cap: f32,
+        yaw_rate: Option<f32>,
+        now: Instant,
+    ) -> (f32, f32) {
+        let applies = self.applies(ratio);
+        let mut state = self.state.lock().unwrap();
+        state.yaw_rate = yaw_rate;
+
+        let yaw_rate = match yaw_rate {
+            Some(yaw_rate) if applies => yaw_rate,
+            _ => {
+                state.stop();
+                return ratio;
+            }
+        };
+
+        let elapsed = match state.last_update {
+            Some(last_update) if now - last_update <= MAX_GAP => now - last_update,
+            _ => {
+                state.pid.reset();
+                Duration::from_secs(0)
+            }
+        };
+        state.last_update = Some(now);
+        let seconds = elapsed.as_secs() as f32 + elapsed.subsec_micros() as f32 / 1e6;
+
+        // Positive correction turns the robot counter-clockwise, against a
+        // negative yaw rate.
+        let correction = state.pid.update(-yaw_rate, seconds);
+        state.correction = correction;
+        (
+            correct_ratio(ratio.0, -correction, power_cap),
+            correct_ratio(ratio.1, correction, power_cap),
+        )
+    }
+}
+
+/// Add a correction to a power ratio without changing its direction nor
+/// exceeding the power cap in magnitude.
+fn correct_ratio(ratio: f32, correction: f32, power_cap: f32) -> f32 {
+    let corrected = ratio + correction;
+    if ratio > 0.0 {
+        corrected.max(0.0).min(power_cap)
+    } else {
+        corrected.min(0.0).max(-power_cap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hold() -> HeadingHold {
+        HeadingHold::new(ImuWatch::new(), Gains::default())
+    }
+
+    #[test]
+    fn test_parse_gains() {
+        assert_eq!(
+            "0.3, 1,0".parse::<Gains>().unwrap(),
+            Gains {
+                proportional: 0.3,
+                integral: 1.0,
+                derivative: 0.0,
+            }
+        );
+        assert!("0.3,1".parse::<Gains>().is_err());
+        assert!("0.3,-1,0".parse::<Gains>().is_err());
+        assert!("a,b,c".parse::<Gains>().is_err());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let hold = hold();
+        let now = Instant::now();
+        assert_eq!(
+            hold.correct_at((0.5, 0.5), 1.0, Some(-1.0), now),
+            (0.5, 0.5)
+        );
+        assert!(!hold.status().active);
+
+        hold.set_enabled(true);
+        // Turning and stopping is not corrected.
+        assert_eq!(
+            hold.correct_at((0.5, 0.2), 1.0, Some(-1.0), now),
+            (0.5, 0.2)
+        );
+        assert_eq!(
+            hold.correct_at((0.0, 0.0), 1.0, Some(-1.0), now),
+            (0.0, 0.0)
+        );
+        // Neither without yaw rate.
+        assert_eq!(hold.correct_at((0.5, 0.5), 1.0, None, now), (0.5, 0.5));
+        assert!(!hold.status().active);
+    }
+
+    #[test]
+    fn test_correction() {
+        let hold = hold();
+        hold.set_enabled(true);
+        let mut now = Instant::now();
+        let tick = Duration::from_millis(50);
+
+        // The robot drifts clockwise, the right track is sped up.
+        let (left, right) = hold.correct_at((0.5, 0.5), 1.0, Some(-0.5), now);
+        assert!((left - 0.4).abs() < 1e-6 && (right - 0.6).abs() < 1e-6);
+        let status = hold.status();
+        assert!(status.active);
+        assert_eq!(status.yaw_rate, Some(-0.5));
+
+        // Persisting drift increases the correction.
+        now += tick;
+        let (left, right) = hold.correct_at((0.5, 0.5), 1.0, Some(-0.5), now);
+        assert!(left < 0.4 && right > 0.6, "{} {}", left, right);
+
+        // Integral keeps part of the correction once the drift is gone.
+        now += tick;
+        let (left, right) = hold.correct_at((0.5, 0.5), 1.0, Some(0.0), now);
+        assert!(left < 0.5 && right > 0.5, "{} {}", left, right);
+
+        // Reversing is corrected the same way.
+        now += tick;
+        let (left, right) = hold.correct_at((-0.5, -0.5), 1.0, Some(-0.5), now);
+        assert!(left < -0.5 && right > -0.5, "{} {}", left, right);
+
+        // The correction never changes direction nor exceeds full power.
+        for _ in 0..100 {
+            now += tick;
+            hold.correct_at((0.1, 0.1), 1.0, Some(-5.0), now);
+        }
+        assert_eq!(hold.correct_at((0.1, 0.1), 1.0, Some(-5.0), now).0, 0.0);
+        assert_eq!(hold.correct_at((0.9, 0.9), 1.0, Some(-5.0), now).1, 1.0);
+        assert!((hold.status().correction - MAX_CORRECTION).abs() < 1e-6);
+
+        hold.set_enabled(false);
+        assert_eq!(hold.status().correction, 0.0);
+    }
+
+    #[test]
+    fn test_power_cap() {
+        let hold = hold();
+        hold.set_enabled(true);
+        let now = Instant::now();
+
+        // At Level::Limited with 0.5 power limit, the ratio limited by the
+        // supervisor is not pushed over the limit by the correction.
+        let (left, right) = hold.correct_at((0.5, 0.5), 0.5, Some(-5.0), now);
+        assert!(left < 0.5 && right == 0.5, "{} {}", left, right);
+        let (left, right) = hold.correct_at((-0.5, -0.5), 0.5, Some(-5.0), now);
+        assert!(left == -0.5 && right > -0.5, "{} {}", left, right);
+    }
+}
@@ -0,0 +1,305 @@
+//! Orientation of the robot estimated from the IMU.
+//!
+//! Accelerometer and gyroscope are periodically read from Arduino. Gyroscope
+//! bias is measured from the first readings, the robot has to stand still
+//! while the server starts. Roll and pitch are estimated with a complementary
+//! filter: integrated angular velocity is slowly pulled towards the direction
+//! of gravity measured by the accelerometer. Yaw is only integrated so it
+//! drifts over time.
+
+use crate::arduino::binary::{Handle, State};
+use crate::arduino::cmd::imu::{Imu, ReadImu};
+use crate::geometry;
+use log::{debug, info};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const SAMPLE_PERIOD: Duration = Duration::from_millis(20);
+/// Number of readings averaged into the gyroscope bias.
+const BIAS_SAMPLES: u32 = 50;
+/// Weight of integrated angular velocity in the complementary filter, the
+/// rest is the orientation measured by the accelerometer.
+const GYRO_WEIGHT: f32 = 0.98;
+/// Angular velocity is not integrated over longer gaps between readings,
+/// e.g. while Arduino is disconnected.
+const MAX_GAP: Duration = Duration::from_millis(500);
+
+/// Orientation in radians between -PI and PI. Roll is rotation around the X
+/// (forward) axis, pitch around the Y (left) axis and yaw, counter-clockwise
+/// from the starting heading, around the Z (up) axis.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Orientation {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+/// Complementary filter of IMU readings.
+pub struct Filter {
+    bias_sum: [f32; 3],
+    bias_samples: u32,
+    bias: Option<[f32; 3]>,
+    orientation: Orientation,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Filter {
+            bias_sum: [0.0; 3],
+            bias_samples: 0,
+            bias: None,
+            orientation: Orientation::default(),
+        }
+    }
+
+    /// Process a reading and return the reading with gyroscope bias removed
+    /// together with the new orientation. `None` is returned while the
+    /// gyroscope bias is being measured.
+    ///
+    /// # Arguments
+    ///
+    /// * `imu` - IMU reading.
+    ///
+    /// * `elapsed` - time since the previous reading.
+    pub fn update(&mut self, imu: Imu, elapsed: Duration) -> Option<(Imu, Orientation)> {
+        let (roll, pitch) = gravity_orientation(imu.acceleration);
+
+        let bias = match self.bias {
+            Some(bias) => bias,
+            None => {
+                for (sum, value) in self.bias_sum.iter_mut().zip(&imu.angular_velocity) {
+                    *sum += value;
+                }
+                self.bias_samples += 1;
+                if self.bias_samples < BIAS_SAMPLES {
+                    return None;
+                }
+
+                let samples = self.bias_samples as f32;
+                let bias = [
+                    self.bias_sum[0] / samples,
+                    self.bias_sum[1] / samples,
+                    self.bias_sum[2] / samples,
+                ];
+                self.bias = Some(bias);
+                self.orientation = Orientation {
+                    roll,
+                    pitch,
+                    yaw: 0.0,
+                };
+                return Some((unbias(imu, bias), self.orientation));
+            }
+        };
+
+        let imu = unbias(imu, bias);
+        let seconds = if elapsed <= MAX_GAP {
+            elapsed.as_secs() as f32 + elapsed.subsec_micros() as f32 / 1e6
+        } else {
+            0.0
+        };
+        let rotation = imu.angular_velocity;
+        let orientation = &mut self.orientation;
+        orientation.roll = normalize_angle(
+            GYRO_WEIGHT * (orientation.roll + rotation[0] * seconds) + (1.0 - GYRO_WEIGHT) * roll,
+        );
+        orientation.pitch = normalize_angle(
+            GYRO_WEIGHT * (orientation.pitch + rotation[1] * seconds) + (1.0 - GYRO_WEIGHT) * pitch,
+        );
+        orientation.yaw = normalize_angle(orientation.yaw + rotation[2] * seconds);
+        Some((imu, *orientation))
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Return roll and pitch of the robot given by the direction of gravity.
+fn gravity_orientation(acceleration: [f32; 3]) -> (f32, f32) {
+    let [x, y, z] = acceleration;
+    let roll = y.atan2(z);
+    let pitch = (-x).atan2((y * y + z * z).sqrt());
+    (roll, pitch)
+}
+
+fn unbias(imu: Imu, bias: [f32; 3]) -> Imu {
+    let rotation = imu.angular_velocity;
+    Imu {
+        acceleration: imu.acceleration,
+        angular_velocity: [
+            rotation[0] - bias[0],
+            rotation[1] - bias[1],
+            rotation[2] - bias[2],
+        ],
+    }
+}
+
+fn normalize_angle(angle: f32) -> f32 {
+    geometry::normalize_angle(f64::from(angle)) as f32
+}
+
+/// The latest IMU reading and orientation estimate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImuState {
+    /// Reading with gyroscope bias removed.
+    pub imu: Imu,
+    pub orientation: Orientation,
+    /// Time of the reading.
+    pub time: SystemTime,
+    updated: Instant,
+}
+
+/// Shared latest IMU state, it is updated by the thread started with
+/// `start_monitoring()`.
+#[derive(Clone)]
+pub struct ImuWatch(Arc<Mutex<Option<ImuState>>>);
+
+impl ImuWatch {
+    pub(crate) fn new() -> Self {
+        ImuWatch(Arc::new(Mutex::new(None)))
+    }
+
+    /// Return the latest state, `None` is returned until the gyroscope bias
+    /// is measured.
+    pub fn get(&self) -> Option<ImuState> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Return the latest yaw rate in rad/s if it is not older than `max_age`.
+    pub fn yaw_rate(&self, max_age: Duration) -> Option<f32> {
+        match *self.0.lock().unwrap() {
+            Some(state) if state.updated.elapsed() <= max_age => {
+                Some(state.imu.angular_velocity[2])
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Start a new thread periodically reading the IMU from Arduino and updating
+/// the orientation estimate.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino.
+pub fn start_monitoring(arduino: Handle) -> ImuWatch {
+    info!("Starting IMU monitoring, keep the robot still...");
+
+    let watch = ImuWatch::new();
+    let shared = watch.clone();
+
+    thread::spawn(move || {
+        let mut filter = Filter::new();
+        let mut last_reading = Instant::now();
+
+        loop {
+            thread::sleep(SAMPLE_PERIOD);
+            if arduino.state() != State::Connected {
+                continue;
+            }
+
+            let imu = match arduino.execute(&ReadImu) {
+                Ok(raw) => raw.convert(),
+                Err(error) => {
+                    debug!("IMU couldn't be read: {}", error);
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            let elapsed = now - last_reading;
+            last_reading = now;
+
+            let calibrated = shared.0.lock().unwrap().is_some();
+            if let Some((imu, orientation)) = filter.update(imu, elapsed) {
+                if !calibrated {
+                    info!(
+                        "Gyroscope bias measured, IMU orientation is {:?}.",
+                        orientation
+                    );
+                }
+                *shared.0.lock().unwrap() = Some(ImuState {
+                    imu,
+                    orientation,
+                    time: SystemTime::now(),
+                    updated: now,
+                });
+            }
+        }
+    });
+
+    watch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    fn imu(acceleration: [f32; 3], angular_velocity: [f32; 3]) -> Imu {
+        Imu {
+            acceleration,
+            angular_velocity,
+        }
+    }
+
+    #[test]
+    fn test_bias() {
+        let mut filter = Filter::new();
+        let tick = Duration::from_millis(20);
+        let still = imu([0.0, 0.0, 9.8], [0.01, -0.02, 0.03]);
+        for _ in 1..BIAS_SAMPLES {
+            assert!(filter.update(still, tick).is_none());
+        }
+        let (unbiased, orientation) = filter.update(still, tick).unwrap();
+        assert_eq!(orientation, Orientation::default());
+        assert!(unbiased.angular_velocity.iter().all(|v| v.abs() < 1e-6));
+
+        // Standing still doesn't move the orientation.
+        for _ in 0..100 {
+            filter.update(still, tick);
+        }
+        let (_, orientation) = filter.update(still, tick).unwrap();
+        assert!(orientation.yaw.abs() < 1e-4, "{:?}", orientation);
+    }
+
+    #[test]
+    fn test_orientation() {
+        let mut filter = Filter::new();
+        let tick = Duration::from_millis(20);
+        for _ in 0..BIAS_SAMPLES {
+            filter.update(imu([0.0, 0.0, 9.8], [0.0; 3]), tick);
+        }
+
+        // Turning left at 1 rad/s for a second.
+        for _ in 0..50 {
+            filter.update(imu([0.0, 0.0, 9.8], [0.0, 0.0, 1.0]), tick);
+        }
+        let (imu_state, orientation) = filter.update(imu([0.0, 0.0, 9.8], [0.0; 3]), tick).unwrap();
+        assert!((orientation.yaw - 1.0).abs() < 1e-3, "{:?}", orientation);
+        assert_eq!(imu_state.angular_velocity[2], 0.0);
+
+        // Long gaps are not integrated.
+        filter.update(
+            imu([0.0, 0.0, 9.8], [0.0, 0.0, 1.0]),
+            Duration::from_secs(2),
+        );
+        let (_, orientation) = filter.update(imu([0.0, 0.0, 9.8], [0.0; 3]), tick).unwrap();
+        assert!((orientation.yaw - 1.0).abs() < 1e-3, "{:?}", orientation);
+
+        // Pitched nose down, the filter converges to the accelerometer.
+        let pitched = imu([-9.8 * 0.5, 0.0, 9.8 * 0.866], [0.0; 3]);
+        for _ in 0..500 {
+            filter.update(pitched, tick);
+        }
+        let (_, orientation) = filter.update(pitched, tick).unwrap();
+        assert!(
+            (orientation.pitch - PI / 6.0).abs() < 1e-2,
+            "{:?}",
+            orientation
+        );
+        assert!(orientation.roll.abs() < 1e-3, "{:?}", orientation);
+    }
+}
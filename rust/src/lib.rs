@@ -1,5 +1,18 @@
 pub mod api;
 pub mod arduino;
+pub mod battery;
+pub mod calibration;
+pub mod drive;
+pub mod estop;
+pub mod geometry;
+pub mod heading;
+pub mod imu;
 pub mod logging;
 pub mod network;
+pub mod odometry;
+pub mod ramp;
+pub mod range;
+pub mod replay;
+pub mod supervisor;
 pub mod update;
+pub mod watchdog;
@@ -1,9 +1,24 @@
 use clap::{App, AppSettings, Arg, SubCommand};
-use irro::arduino::binary::Connection;
-use irro::{api, logging::IrroLogger, network, update};
-use log::{error, info};
+use irro::arduino::binary::{Connection, Handle, ResponseError};
+use irro::arduino::capture::Capture;
+use irro::arduino::cmd::motor::MotorPowerRatio;
+use irro::arduino::cmd::sensor::BatteryCalibration;
+use irro::arduino::cmd::system::{ReadFirmwareInfo, MIN_FIRMWARE_VERSION};
+use irro::arduino::sim;
+use irro::calibration::CalibrationStore;
+use irro::drive::Chassis;
+use irro::estop::Estop;
+use irro::heading::{Gains, HeadingHold};
+use irro::imu;
+use irro::odometry::{self, Odometry};
+use irro::ramp::{self, Ramp};
+use irro::range;
+use irro::supervisor::{self, SafetyWatch, Thresholds};
+use irro::{api, battery, logging::IrroLogger, network, replay, update, watchdog};
+use log::{error, info, warn};
 use std::panic;
 use std::path::Path;
+use std::time::Duration;
 
 macro_rules! irro_version {
     () => {
@@ -30,9 +45,157 @@ fn main() {
         .arg(
             Arg::with_name("device")
                 .long("device")
-                .help("Arduino serial port device, for example /dev/ttyACM0")
+                .help(
+                    "Arduino serial port device, for example /dev/ttyACM0, or auto \
+                     to find the USB serial port with Irro Arduino. Other \
+                     transports are given as tcp://host:port, unix:///path/to/socket \
+                     or pty:///dev/pts/N. Use sim:// for a simulated Arduino.",
+                )
                 .takes_value(true)
+                .required_unless("simulate"),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .help("Use a simulated Arduino instead of a serial port device.")
+                .conflicts_with("device"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .help("Record all serial communication with Arduino to this file.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow-incompatible-firmware")
+                .long("allow-incompatible-firmware")
+                .help("Start even if Arduino firmware is not compatible, only warn."),
+        )
+        .arg(
+            Arg::with_name("battery-calibration")
+                .long("battery-calibration")
+                .help(
+                    "Conversion of raw battery readings given as voltage scale, \
+                     voltage offset, current scale and current offset, for example \
+                     0.0293,0,0.0489,-25.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("safety-thresholds")
+                .long("safety-thresholds")
+                .help(
+                    "Battery thresholds of the safety supervisor given as comma \
+                     separated name=value pairs, for example \
+                     stop_voltage=18.9,power_limit=0.4. Known names are \
+                     limit_voltage, stop_voltage, shutdown_voltage, limit_current, \
+                     stop_current and power_limit.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("motor-timeout")
+                .long("motor-timeout")
+                .help(
+                    "Seconds after which motors are stopped unless a fresh motor \
+                     command arrives. Used for commands which don't specify their \
+                     validity.",
+                )
+                .takes_value(true)
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("motor-ramp")
+                .long("motor-ramp")
+                .help(
+                    "Apply motor power ratios gradually, changing them by at most \
+                     this much per second, e.g. 2 changes full backward power to \
+                     full forward power in one second. Ratios are applied \
+                     immediately if not given.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("track-width")
+                .long("track-width")
+                .help("Distance between centers of left and right tracks in meters.")
+                .takes_value(true)
+                .default_value("0.25"),
+        )
+        .arg(
+            Arg::with_name("max-speed")
+                .long("max-speed")
+                .help("Track speed at full motor power in meters per second.")
+                .takes_value(true)
+                .default_value("0.5"),
+        )
+        .arg(
+            Arg::with_name("obstacle-distance")
+                .long("obstacle-distance")
+                .help(
+                    "Cut off forward motion when range sensors see an obstacle \
+                     closer than this many meters, 0 disables the cutoff.",
+                )
+                .takes_value(true)
+                .default_value("0.2"),
+        )
+        .arg(Arg::with_name("heading-hold").long("heading-hold").help(
+            "Enable heading hold at start, it corrects straight driving \
+                     with the yaw rate measured by the IMU.",
+        ))
+        .arg(
+            Arg::with_name("heading-hold-gains")
+                .long("heading-hold-gains")
+                .help(
+                    "Proportional, integral and derivative gain of the heading \
+                     hold, for example 0.2,0.5,0.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ticks-per-meter")
+                .long("ticks-per-meter")
+                .help("Wheel encoder ticks per meter of track movement.")
+                .takes_value(true)
+                .default_value("2000"),
+        )
+        .arg(
+            Arg::with_name("motor-calibration")
+                .long("motor-calibration")
+                .help(
+                    "File with per-side motor gain trim, deadband, inversion and \
+                     max power given as name=value pairs, one per line, for \
+                     example left.gain=0.95 or right.inverted=true. The file is \
+                     created when the calibration is changed via the API. Motor \
+                     power ratios are not corrected and calibration changes are \
+                     not persisted if not given.",
+                )
+                .takes_value(true),
+        );
+
+    let replay_cmd = SubCommand::with_name("replay")
+        .about("Decodes and replays serial communication capture.")
+        .long_about(
+            "This sub-command prints commands and responses recorded with \
+             `start --capture`. The captured commands are sent again, with the \
+             original timing, if an Arduino device is given.",
+        )
+        .arg(
+            Arg::with_name("capture")
+                .help("Capture file.")
                 .required(true),
+        )
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .help("Arduino serial port device the commands are sent to.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .help("Send the commands to a simulated Arduino.")
+                .conflicts_with("device"),
         );
 
     let update_cmd = SubCommand::with_name("update")
@@ -57,23 +220,138 @@ fn main() {
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .subcommand(start_cmd)
         .subcommand(update_cmd)
+        .subcommand(replay_cmd)
         .get_matches();
 
     match matches.subcommand() {
         ("start", Some(matches)) => {
-            let device = matches.value_of("device").unwrap();
-            start_server(device);
+            let device = if matches.is_present("simulate") {
+                sim::DEVICE
+            } else {
+                matches.value_of("device").unwrap()
+            };
+            let capture = matches.value_of("capture").map(Path::new);
+            let allow_incompatible = matches.is_present("allow-incompatible-firmware");
+            let calibration = match matches.value_of("battery-calibration") {
+                Some(calibration) => match calibration.parse() {
+                    Ok(calibration) => calibration,
+                    Err(error) => panic!("Invalid battery calibration: {}", error),
+                },
+                None => BatteryCalibration::default(),
+            };
+            let thresholds = match matches.value_of("safety-thresholds") {
+                Some(thresholds) => match thresholds.parse() {
+                    Ok(thresholds) => thresholds,
+                    Err(error) => panic!("Invalid safety thresholds: {}", error),
+                },
+                None => Thresholds::default(),
+            };
+            let motor_timeout = match matches.value_of("motor-timeout").unwrap().parse::<f32>() {
+                Ok(timeout)
+                    if timeout > 0.0 && timeout <= watchdog::MAX_VALIDITY.as_secs() as f32 =>
+                {
+                    Duration::from_millis((timeout * 1000.0) as u64)
+                }
+                _ => panic!(
+                    "Motor timeout must be a positive number of seconds not larger than {}.",
+                    watchdog::MAX_VALIDITY.as_secs()
+                ),
+            };
+            let motor_ramp = matches
+                .value_of("motor-ramp")
+                .map(|rate| match rate.parse::<f32>() {
+                    Ok(rate) if rate.is_finite() && rate > 0.0 => rate,
+                    _ => panic!("Motor ramp must be a positive number."),
+                });
+            let positive = |name: &str| match matches.value_of(name).unwrap().parse::<f32>() {
+                Ok(value) if value.is_finite() && value > 0.0 => value,
+                _ => panic!("Option --{} must be a positive number.", name),
+            };
+            let chassis = Chassis::new(positive("track-width"), positive("max-speed"));
+            let obstacle_distance = match matches
+                .value_of("obstacle-distance")
+                .unwrap()
+                .parse::<f32>()
+            {
+                Ok(distance) if distance.is_finite() && distance >= 0.0 => distance,
+                _ => panic!("Obstacle distance must be a non-negative number."),
+            };
+            let heading_gains = match matches.value_of("heading-hold-gains") {
+                Some(gains) => match gains.parse() {
+                    Ok(gains) => gains,
+                    Err(error) => panic!("Invalid heading hold gains: {}", error),
+                },
+                None => Gains::default(),
+            };
+            let odometry = Odometry::new(positive("ticks-per-meter"), chassis.track_width);
+            let motor_calibration = matches.value_of("motor-calibration").map(Path::new);
+            let motor_calibration = match CalibrationStore::load(motor_calibration) {
+                Ok(calibration) => calibration,
+                Err(error) => panic!("Invalid motor calibration: {}", error),
+            };
+            let motors = MotorConfig {
+                timeout: motor_timeout,
+                ramp: motor_ramp,
+                thresholds,
+                chassis,
+                calibration: motor_calibration,
+                obstacle_distance,
+                heading_hold: matches.is_present("heading-hold"),
+                heading_gains,
+            };
+            start_server(
+                device,
+                capture,
+                allow_incompatible,
+                calibration,
+                motors,
+                odometry,
+            );
         }
         ("update", Some(matches)) => {
             let path_str = matches.value_of("path").unwrap();
             let path = Path::new(path_str);
             update::update(path);
         }
+        ("replay", Some(matches)) => {
+            let path = Path::new(matches.value_of("capture").unwrap());
+            let device = if matches.is_present("simulate") {
+                Some(sim::DEVICE)
+            } else {
+                matches.value_of("device")
+            };
+            replay::replay(path, device);
+        }
         _ => panic!("Unrecognized command"),
     }
 }
 
-fn start_server(device: &str) {
+/// Configuration of motor control.
+struct MotorConfig {
+    /// Default validity of motor commands.
+    timeout: Duration,
+    /// Maximum change of motor power ratio per second.
+    ramp: Option<f32>,
+    /// Safety supervisor thresholds.
+    thresholds: Thresholds,
+    chassis: Chassis,
+    /// Per-side correction of motor power ratios.
+    calibration: CalibrationStore,
+    /// Distance of an obstacle at which forward motion is cut off.
+    obstacle_distance: f32,
+    /// Enable heading hold at start.
+    heading_hold: bool,
+    heading_gains: Gains,
+}
+
+fn start_server(
+    device: &str,
+    capture: Option<&Path>,
+    allow_incompatible: bool,
+    calibration: BatteryCalibration,
+    motors: MotorConfig,
+    odometry: Odometry,
+) {
     info!("Starting Irro {}...", irro_long_version!());
 
     match network::start_broadcasting() {
@@ -81,12 +359,139 @@ fn start_server(device: &str) {
         Err(error) => panic!("Error while starting broadcast loop: {}", error),
     }
 
-    let sender = match Connection::init_from_device(device) {
-        Ok(sender) => sender,
+    let connection = match capture {
+        Some(path) => match Capture::create(path) {
+            Ok(capture) => Connection::init_with_capture(device, capture),
+            Err(error) => panic!("Error while creating {}: {}", path.display(), error),
+        },
+        None => Connection::init_from_device(device),
+    };
+    let arduino = match connection {
+        Ok(arduino) => arduino,
         Err(error) => panic!("Error while connecting to Arduino: {}", error),
     };
+    check_firmware(&arduino, allow_incompatible);
+
+    let battery = battery::start_monitoring(arduino.clone(), calibration);
+    let odometry = odometry::start_tracking(arduino.clone(), odometry);
+    let imu = imu::start_monitoring(arduino.clone());
+    let heading = HeadingHold::new(imu.clone(), motors.heading_gains);
+    heading.set_enabled(motors.heading_hold);
+    let safety = SafetyWatch::new(motors.thresholds.power_limit);
+    let ramp = ramp::start(
+        arduino.clone(),
+        motors.ramp,
+        motors.calibration.clone(),
+        heading.clone(),
+        safety.clone(),
+    );
+    let motor_watchdog = start_motor_watchdog(&arduino, &safety, &ramp, motors.timeout);
+    let estop = Estop::new(
+        arduino.clone(),
+        safety.clone(),
+        ramp.clone(),
+        motor_watchdog.clone(),
+    );
+    supervisor::start_supervising(
+        arduino.clone(),
+        battery.clone(),
+        safety.clone(),
+        motors.thresholds,
+        estop.clone(),
+        ramp.clone(),
+        motors.calibration.clone(),
+    );
+    let range = range::start_monitoring(
+        arduino.clone(),
+        motors.obstacle_distance,
+        safety.clone(),
+        estop.clone(),
+        ramp.clone(),
+        motors.calibration.clone(),
+    );
 
-    if let Err(error) = api::run_http_server(sender) {
+    let motors = api::Motors {
+        safety,
+        watchdog: motor_watchdog,
+        ramp,
+        calibration: motors.calibration,
+        chassis: motors.chassis,
+        estop,
+        heading,
+    };
+    let sensors = api::Sensors {
+        odometry,
+        range,
+        imu,
+    };
+    let result = api::run_http_server(arduino, calibration, battery, motors, sensors);
+    if let Err(error) = result {
         panic!("Error while starting HTTP server: {}", error);
     }
 }
+
+/// Start the motor watchdog which stops the motors when a motor command
+/// expires.
+fn start_motor_watchdog(
+    arduino: &Handle,
+    safety: &SafetyWatch,
+    ramp: &Ramp,
+    timeout: Duration,
+) -> watchdog::MotorWatchdog {
+    let arduino = arduino.clone();
+    let safety = safety.clone();
+    let ramp = ramp.clone();
+    watchdog::start(timeout, move || {
+        // Stopping is always allowed, this only records the stop so that
+        // neither the supervisor nor the ramp resume the motors.
+        safety.limit_motors(0.0, 0.0).unwrap_or((0.0, 0.0));
+        ramp.reset(0.0, 0.0);
+        if let Err(error) = arduino.execute(&MotorPowerRatio::from_floats(0.0, 0.0)) {
+            error!("Motors couldn't be stopped by the watchdog: {}", error);
+        }
+    })
+}
+
+/// Check that Arduino firmware is compatible with this server.
+///
+/// # Panics
+///
+/// This function panics if the firmware is incompatible or too old to
+/// identify itself, unless `allow_incompatible` is true, in which case only a
+/// warning is logged. Firmware which couldn't be queried (e.g. Arduino is
+/// disconnected at the moment) is not considered incompatible.
+fn check_firmware(arduino: &Handle, allow_incompatible: bool) {
+    let (major, minor, patch) = MIN_FIRMWARE_VERSION;
+    let problem = match arduino.execute(&ReadFirmwareInfo) {
+        Ok(ref info) if info.is_compatible() => {
+            info!("Arduino runs {}.", info);
+            return;
+        }
+        Ok(info) => format!(
+            "Arduino firmware {} is not compatible, {}.{}.{} or newer with the same \
+             major version is required.",
+            info.version_string(),
+            major,
+            minor,
+            patch
+        ),
+        Err(ResponseError::ProtocolError(reason)) => format!(
+            "Arduino firmware couldn't be identified, {}.{}.{} or newer is required: {}",
+            major, minor, patch, reason
+        ),
+        Err(error @ ResponseError::Unsupported(_)) => format!(
+            "Arduino firmware couldn't be identified, {}.{}.{} or newer is required: {}",
+            major, minor, patch, error
+        ),
+        Err(error) => {
+            warn!("Arduino firmware couldn't be checked: {}", error);
+            return;
+        }
+    };
+
+    if allow_incompatible {
+        warn!("{}", problem);
+    } else {
+        panic!("{}", problem);
+    }
+}
@@ -0,0 +1,267 @@
+//! Odometry of Irro's tracked chassis.
+//!
+//! Encoder tick counters of left and right tracks are periodically read from
+//! Arduino and integrated into a 2D pose relative to the place where the
+//! server started (or where the odometry was reset). The X axis points
+//! forward from the starting pose and the Y axis to the left. Track slip,
+//! which is substantial when a tracked robot turns, is not modelled so the
+//! pose drifts over time.
+
+use crate::arduino::binary::{Handle, State};
+use crate::arduino::cmd::encoder::{EncoderTicks, ReadEncoders};
+use crate::geometry::normalize_angle;
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const SAMPLE_PERIOD: Duration = Duration::from_millis(100);
+/// Track movement between two readings larger than this (in meters) is
+/// considered a reset of the tick counters and it is not integrated.
+const MAX_STEP_DISTANCE: f64 = 1.0;
+
+/// Position in meters and heading in radians, counter-clockwise from the X
+/// axis between -PI and PI.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pose {
+    pub x: f64,
+    pub y: f64,
+    pub heading: f64,
+}
+
+/// Integrator of encoder ticks.
+#[derive(Clone, Copy, Debug)]
+pub struct Odometry {
+    ticks_per_meter: f64,
+    track_width: f64,
+    last_ticks: Option<EncoderTicks>,
+    pose: Pose,
+    distance: f64,
+}
+
+impl Odometry {
+    /// Create odometry at the origin.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticks_per_meter` - encoder ticks per meter of track movement.
+    ///
+    /// * `track_width` - distance between centers of left and right tracks in
+    ///   meters.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if ticks per meter or track width is not a positive
+    /// number.
+    pub fn new(ticks_per_meter: f32, track_width: f32) -> Self {
+        if !(ticks_per_meter.is_finite() && ticks_per_meter > 0.0) {
+            panic!("Ticks per meter must be a positive number.");
+        }
+        if !(track_width.is_finite() && track_width > 0.0) {
+            panic!("Track width must be a positive number.");
+        }
+
+        Odometry {
+            ticks_per_meter: f64::from(ticks_per_meter),
+            track_width: f64::from(track_width),
+            last_ticks: None,
+            pose: Pose::default(),
+            distance: 0.0,
+        }
+    }
+
+    /// Current pose.
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    /// Distance travelled by the center of the robot in meters.
+    pub fn distance(&self) -> f64 {
+        self.distance
+    }
+
+    /// The last integrated tick counters, `None` if the encoders were not
+    /// read yet.
+    pub fn ticks(&self) -> Option<EncoderTicks> {
+        self.last_ticks
+    }
+
+    /// Move the pose to the origin and reset travelled distance.
+    pub fn reset(&mut self) {
+        self.pose = Pose::default();
+        self.distance = 0.0;
+    }
+
+    /// Make the next reading a new baseline, e.g. because the tick counters
+    /// may have been reset.
+    pub fn forget_ticks(&mut self) {
+        self.last_ticks = None;
+    }
+
+    /// Integrate movement since the previous reading.
+    ///
+    /// Return false if the movement was implausibly large and it was not
+    /// integrated. The reading is used as a new baseline in such a case.
+    pub fn update(&mut self, ticks: EncoderTicks) -> bool {
+        let last = match self.last_ticks.replace(ticks) {
+            Some(last) => last,
+            None => return true,
+        };
+
+        // The counters may wrap around.
+        let left = f64::from(ticks.left.wrapping_sub(last.left)) / self.ticks_per_meter;
+        let right = f64::from(ticks.right.wrapping_sub(last.right)) / self.ticks_per_meter;
+        if left.abs() > MAX_STEP_DISTANCE || right.abs() > MAX_STEP_DISTANCE {
+            return false;
+        }
+
+        let distance = (left + right) / 2.0;
+        let rotation = (right - left) / self.track_width;
+        // Movement is approximated by a straight line in the mean direction.
+        let direction = self.pose.heading + rotation / 2.0;
+        self.pose.x += distance * direction.cos();
+        self.pose.y += distance * direction.sin();
+        self.pose.heading = normalize_angle(self.pose.heading + rotation);
+        self.distance += distance.abs();
+        true
+    }
+}
+
+/// Shared odometry updated by the thread started with `start_tracking()`.
+#[derive(Clone)]
+pub struct OdometryWatch(Arc<Mutex<Odometry>>);
+
+impl OdometryWatch {
+    /// Return current state of the odometry.
+    pub fn get(&self) -> Odometry {
+        *self.0.lock().unwrap()
+    }
+
+    /// Move the pose to the origin and reset travelled distance.
+    pub fn reset(&self) {
+        self.0.lock().unwrap().reset();
+    }
+}
+
+/// Start a new thread periodically reading encoders from Arduino and
+/// integrating them.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino.
+///
+/// * `odometry` - Odometry to be updated.
+pub fn start_tracking(arduino: Handle, odometry: Odometry) -> OdometryWatch {
+    info!("Starting odometry...");
+
+    let watch = OdometryWatch(Arc::new(Mutex::new(odometry)));
+    let shared = watch.clone();
+
+    thread::spawn(move || loop {
+        thread::sleep(SAMPLE_PERIOD);
+        if arduino.state() != State::Connected {
+            // Arduino may reset while disconnected, which resets the counters.
+            shared.0.lock().unwrap().forget_ticks();
+            continue;
+        }
+
+        let ticks = match arduino.execute(&ReadEncoders) {
+            Ok(ticks) => ticks,
+            Err(error) => {
+                debug!("Encoders couldn't be read: {}", error);
+                continue;
+            }
+        };
+
+        if !shared.0.lock().unwrap().update(ticks) {
+            warn!(
+                "Encoder ticks jumped to {:?}, the movement is not integrated.",
+                ticks
+            );
+        }
+    });
+
+    watch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn ticks(left: i32, right: i32) -> EncoderTicks {
+        EncoderTicks { left, right }
+    }
+
+    fn assert_pose(odometry: &Odometry, x: f64, y: f64, heading: f64) {
+        let pose = odometry.pose();
+        assert!((pose.x - x).abs() < 1e-6, "{:?}", pose);
+        assert!((pose.y - y).abs() < 1e-6, "{:?}", pose);
+        assert!((pose.heading - heading).abs() < 1e-6, "{:?}", pose);
+    }
+
+    #[test]
+    fn test_straight() {
+        let mut odometry = Odometry::new(1000.0, 0.25);
+        assert!(odometry.update(ticks(5000, -300)));
+        assert_pose(&odometry, 0.0, 0.0, 0.0);
+
+        assert!(odometry.update(ticks(5500, 200)));
+        assert_pose(&odometry, 0.5, 0.0, 0.0);
+        assert!(odometry.update(ticks(5300, 0)));
+        assert_pose(&odometry, 0.3, 0.0, 0.0);
+        assert!((odometry.distance() - 0.7).abs() < 1e-6);
+
+        odometry.reset();
+        assert_pose(&odometry, 0.0, 0.0, 0.0);
+        assert_eq!(odometry.distance(), 0.0);
+        assert_eq!(odometry.ticks(), Some(ticks(5300, 0)));
+    }
+
+    #[test]
+    fn test_turn() {
+        let mut odometry = Odometry::new(1000.0, 0.25);
+        odometry.update(ticks(0, 0));
+
+        // Quarter turn to the left on the spot, rounded to whole ticks.
+        let quarter = (PI / 2.0 * 0.125 * 1000.0).round() as i32;
+        odometry.update(ticks(-quarter, quarter));
+        let pose = odometry.pose();
+        assert!(pose.x.abs() < 1e-6 && pose.y.abs() < 1e-6);
+        assert!((pose.heading - PI / 2.0).abs() < 1e-2, "{:?}", pose);
+
+        // Drive forward, which is along the Y axis now.
+        odometry.update(ticks(1000 - quarter, 1000 + quarter));
+        let pose = odometry.pose();
+        assert!(
+            pose.x.abs() < 1e-2 && (pose.y - 1.0).abs() < 1e-2,
+            "{:?}",
+            pose
+        );
+
+        // Half turn to the left wraps heading around.
+        let half = (PI * 0.125 * 1000.0).round() as i32;
+        odometry.update(ticks(1000 - quarter - half, 1000 + quarter + half));
+        let pose = odometry.pose();
+        assert!((pose.heading + PI / 2.0).abs() < 1e-2, "{:?}", pose);
+    }
+
+    #[test]
+    fn test_counter_jumps() {
+        let mut odometry = Odometry::new(1000.0, 0.25);
+        odometry.update(ticks(i32::max_value() - 100, 0));
+        // Counters wrap around.
+        assert!(odometry.update(ticks(i32::min_value() + 99, 200)));
+        assert_pose(&odometry, 0.2, 0.0, 0.0);
+
+        // Counters were reset.
+        assert!(!odometry.update(ticks(0, 0)));
+        assert_pose(&odometry, 0.2, 0.0, 0.0);
+        assert!(odometry.update(ticks(100, 100)));
+        assert_pose(&odometry, 0.3, 0.0, 0.0);
+
+        odometry.forget_ticks();
+        assert!(odometry.update(ticks(0, 0)));
+        assert_pose(&odometry, 0.3, 0.0, 0.0);
+    }
+}
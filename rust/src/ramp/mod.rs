@@ -0,0 +1,277 @@
+//! Motor acceleration ramping.
+//!
+//! Requested motor power ratio is not applied instantly, the ratio sent to
+//! Arduino is moved towards the requested one at a limited rate with a
+//! command sent every tick. This avoids jerks of the tracks and current
+//! spikes on the motor power supply. Both motors are ramped so that they
+//! reach the requested ratio at the same time, which keeps the shape of the
+//! robot's path.
+//!
+//! The ramp also applies corrections of the heading hold (see
+//! `crate::heading`), it keeps sending the corrected ratio every tick while
+//! the heading hold applies to it, even with ramping disabled.
+
+use crate::arduino::binary::Handle;
+use crate::calibration::CalibrationStore;
+use crate::heading::HeadingHold;
+use crate::supervisor::SafetyWatch;
+use log::{debug, info};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Period of intermediate motor commands.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Requested and applied motor power ratios.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Status {
+    /// Left and right power ratio requested last.
+    pub requested: (f32, f32),
+    /// Left and right power ratio last sent to Arduino.
+    pub applied: (f32, f32),
+}
+
+/// Handle of the ramp started with `start()`.
+#[derive(Clone)]
+pub struct Ramp {
+    status: Arc<Mutex<Status>>,
+    rate: Option<f32>,
+    heading: HeadingHold,
+}
+
+impl Ramp {
+    /// Register a newly requested motor power ratio.
+    ///
+    /// Return true if the ratio is sent by the ramp, e.g. gradually. False
+    /// is returned if ramping is disabled and the heading hold doesn't apply
+    /// to the ratio, the caller has to send the ratio to Arduino itself in
+    /// such a case and errors of the command are reported to it.
+    pub fn request(&self, left: f32, right: f32) -> bool {
+        let mut status = self.status.lock().unwrap();
+        status.requested = (left, right);
+        let sent = self.rate.is_some() || self.heading.applies((left, right));
+        if !sent {
+            status.applied = (left, right);
+        }
+        sent
+    }
+
+    /// Set both requested and applied ratio without ramping. This is used
+    /// when the caller sends the ratio to Arduino itself, e.g. to stop the
+    /// motors immediately.
+    pub fn reset(&self, left: f32, right: f32) {
+        let mut status = self.status.lock().unwrap();
+        status.requested = (left, right);
+        status.applied = (left, right);
+    }
+
+    /// Return requested and applied ratios.
+    pub fn status(&self) -> Status {
+        *self.status.lock().unwrap()
+    }
+}
+
+/// Start ramping motor power ratio.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino.
+///
+/// * `rate` - maximum change of the power ratio per second, ramping is
+///   disabled if `None`.
+///
+/// * `calibration` - Motor calibration applied to ramped ratios.
+///
+/// * `heading` - Heading hold correcting ramped ratios.
+///
+/// * `safety` - Safety supervisor capping power of corrected and calibrated
+///   ratios.
+pub fn start(
+    arduino: Handle,
+    rate: Option<f32>,
+    calibration: CalibrationStore,
+    heading: HeadingHold,
+    safety: SafetyWatch,
+) -> Ramp {
+    let ramp = Ramp {
+        status: Arc::new(Mutex::new(Status {
+            requested: (0.0, 0.0),
+            applied: (0.0, 0.0),
+        })),
+        rate,
+        heading: heading.clone(),
+    };
+
+    if let Some(rate) = rate {
+        info!("Starting motor ramping at {} per second...", rate);
+    }
+
+    let shared = ramp.status.clone();
+    thread::spawn(move || {
+        let mut last_tick = Instant::now();
+
+        loop {
+            thread::sleep(TICK);
+            let now = Instant::now();
+            let elapsed = now - last_tick;
+            last_tick = now;
+
+            // The lock is held while the command is being sent so that a
+            // concurrent reset is never overwritten by an older ratio.
+            let mut status = shared.lock().unwrap();
+            if status.applied == status.requested && !heading.applies(status.applied) {
+                continue;
+            }
+
+            status.applied = match rate {
+                Some(rate) => {
+                    let seconds = elapsed.as_secs() as f32 + elapsed.subsec_micros() as f32 / 1e6;
+                    step(status.applied, status.requested, rate * seconds)
+                }
+                None => status.requested,
+            };
+            let (left, right) = heading.correct(status.applied, safety.power_cap());
+            // Intermediate commands are not waited for, a newer command
+            // replaces a waiting one anyway.
+            let command = calibration.command(left, right, safety.power_cap());
+            if let Err(error) = arduino.send(&command) {
+                debug!("Ramped motor power ratio couldn't be sent: {}", error);
+            }
+        }
+    });
+
+    ramp
+}
+
+/// Move both ratios from `applied` towards `requested`, the larger change is
+/// at most `max_step`.
+fn step(applied: (f32, f32), requested: (f32, f32), max_step: f32) -> (f32, f32) {
+    let left_diff = requested.0 - applied.0;
+    let right_diff = requested.1 - applied.1;
+    let max_diff = left_diff.abs().max(right_diff.abs());
+    if max_diff <= max_step {
+        return requested;
+    }
+
+    let ratio = max_step / max_diff;
+    (
+        applied.0 + left_diff * ratio,
+        applied.1 + right_diff * ratio,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arduino::binary::StateWatch;
+    use crate::heading::Gains;
+    use crate::imu::ImuWatch;
+    use std::sync::mpsc;
+
+    fn calibration() -> CalibrationStore {
+        CalibrationStore::load(None).unwrap()
+    }
+
+    fn heading() -> HeadingHold {
+        HeadingHold::new(ImuWatch::new(), Gains::default())
+    }
+
+    #[test]
+    fn test_step() {
+        let (left, right) = step((0.0, 0.0), (1.0, 0.5), 0.1);
+        assert!((left - 0.1).abs() < 1e-6 && (right - 0.05).abs() < 1e-6);
+        assert_eq!(step((-1.0, 1.0), (1.0, 1.0), 0.5), (-0.5, 1.0));
+        assert_eq!(step((0.9, 0.9), (1.0, 0.95), 0.5), (1.0, 0.95));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let (sender, receiver) = mpsc::channel();
+        let ramp = start(
+            Handle::new(sender, StateWatch::new()),
+            None,
+            calibration(),
+            heading(),
+            SafetyWatch::new(0.5),
+        );
+        assert!(!ramp.request(0.5, -0.5));
+        assert_eq!(ramp.status().applied, (0.5, -0.5));
+        thread::sleep(TICK * 2);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_ramp() {
+        let (sender, receiver) = mpsc::channel();
+        let ramp = start(
+            Handle::new(sender, StateWatch::new()),
+            Some(4.0),
+            calibration(),
+            heading(),
+            SafetyWatch::new(0.5),
+        );
+        assert!(ramp.request(1.0, 0.5));
+        assert_eq!(ramp.status().applied, (0.0, 0.0));
+
+        let mut lefts = Vec::new();
+        while let Ok(message) = receiver.recv_timeout(Duration::from_millis(500)) {
+            let (command, payload, _, _) = message.destructure();
+            assert_eq!(command, 0x0100);
+            lefts.push(i16::from_be_bytes([payload[0], payload[1]]));
+            if lefts.last() == Some(&i16::max_value()) {
+                break;
+            }
+        }
+
+        // It takes 250 ms to reach the requested ratio.
+        assert!(lefts.len() >= 3, "{:?}", lefts);
+        assert!(lefts.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(*lefts.last().unwrap(), i16::max_value());
+        let status = ramp.status();
+        assert_eq!(status.applied, status.requested);
+
+        ramp.reset(0.0, 0.0);
+        thread::sleep(TICK * 2);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(ramp.status().applied, (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_heading_hold() {
+        let (sender, receiver) = mpsc::channel();
+        let heading = heading();
+        let ramp = start(
+            Handle::new(sender, StateWatch::new()),
+            None,
+            calibration(),
+            heading.clone(),
+            SafetyWatch::new(0.5),
+        );
+        heading.set_enabled(true);
+        assert!(ramp.request(0.5, 0.5));
+
+        // Straight driving is sent every tick, uncorrected without the IMU.
+        for _ in 0..3 {
+            let message = receiver.recv_timeout(Duration::from_millis(500)).unwrap();
+            let (command, payload, _, _) = message.destructure();
+            assert_eq!(command, 0x0100);
+            assert_eq!(payload, vec![0x3f, 0xff, 0x3f, 0xff]);
+        }
+        assert_eq!(ramp.status().applied, (0.5, 0.5));
+
+        // Turning is not corrected, it is sent by the caller.
+        assert!(!ramp.request(0.5, -0.5));
+        assert_eq!(ramp.status().applied, (0.5, -0.5));
+        thread::sleep(TICK * 2);
+        while receiver.try_recv().is_ok() {}
+        thread::sleep(TICK * 2);
+        assert!(receiver.try_recv().is_err());
+
+        ramp.reset(0.0, 0.0);
+        thread::sleep(TICK * 2);
+        while receiver.try_recv().is_ok() {}
+        thread::sleep(TICK * 2);
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Obstacle detection with range sensors.
+//!
+//! All range sensors face forward. Their distances are periodically read from
+//! Arduino and forward motor power is cut off by the safety supervisor (see
+//! `crate::supervisor::SafetyWatch`) while an obstacle is closer than a
+//! configured distance. Reversing and turning away stay allowed so that the
+//! robot can be driven out of the situation. Forward motion is cut off as well
+//! when the range sensors can't be read, e.g. while Arduino is disconnected,
+//! or when a sensor fails.
+
+use crate::arduino::binary::{Handle, State};
+use crate::arduino::cmd::range::{Range, ReadRanges};
+use crate::calibration::CalibrationStore;
+use crate::estop::Estop;
+use crate::ramp::Ramp;
+use crate::supervisor::{self, SafetyWatch};
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const SAMPLE_PERIOD: Duration = Duration::from_millis(100);
+/// An obstacle is cleared only once it is this much (in meters) further than
+/// the minimum distance.
+const DISTANCE_HYSTERESIS: f32 = 0.05;
+/// Readings older than this (three sample periods) are not trusted and
+/// forward motion is cut off until the range sensors are read again.
+const MAX_AGE: Duration = Duration::from_millis(300);
+
+/// Distances measured by range sensors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ranges {
+    /// Distance in meters measured by each sensor, `None` if there is nothing
+    /// in range of the sensor or if the sensor failed.
+    pub distances: Vec<Option<f32>>,
+    /// True if any sensor failed, forward motion is cut off in such a case.
+    pub failed: bool,
+    /// Time of the reading.
+    pub time: SystemTime,
+    updated: Instant,
+}
+
+impl Ranges {
+    /// Return true if the reading is too old to be trusted, forward motion
+    /// is cut off in such a case.
+    pub fn is_stale(&self) -> bool {
+        self.updated.elapsed() > MAX_AGE
+    }
+
+    /// Return distance to the closest obstacle seen by any sensor.
+    pub fn closest(&self) -> Option<f32> {
+        self.distances
+            .iter()
+            .filter_map(|&distance| distance)
+            .fold(None, |closest, distance| match closest {
+                Some(closest) if closest <= distance => Some(closest),
+                _ => Some(distance),
+            })
+    }
+}
+
+/// Return the reason of the forward motion cutoff if there is an obstacle
+/// closer than the minimum distance.
+///
+/// # Arguments
+///
+/// * `closest` - distance to the closest obstacle.
+///
+/// * `min_distance` - the minimum distance, 0 disables the cutoff.
+///
+/// * `obstacle` - true if an obstacle has been detected by the previous
+///   reading, it is cleared only with hysteresis.
+fn detect(closest: Option<f32>, min_distance: f32, obstacle: bool) -> Option<String> {
+    let margin = if obstacle { DISTANCE_HYSTERESIS } else { 0.0 };
+    match closest {
+        Some(distance) if min_distance > 0.0 && distance < min_distance + margin => Some(format!(
+            "obstacle {:.2} m ahead is closer than {:.2} m",
+            distance, min_distance
+        )),
+        _ => None,
+    }
+}
+
+/// Return the reason of the forward motion cutoff if the range sensors have
+/// not been read for too long.
+///
+/// # Arguments
+///
+/// * `age` - time since the last successful reading.
+///
+/// * `min_distance` - the minimum distance, 0 disables the cutoff.
+fn detect_stale(age: Duration, min_distance: f32) -> Option<String> {
+    if min_distance > 0.0 && age > MAX_AGE {
+        Some(format!(
+            "range sensors have not been read for {:.1} s",
+            age.as_millis() as f32 / 1000.0
+        ))
+    } else {
+        None
+    }
+}
+
+/// Return the reason of the forward motion cutoff if a range sensor failed,
+/// its readings are treated like stale readings.
+///
+/// # Arguments
+///
+/// * `failed` - true if any sensor failed.
+///
+/// * `min_distance` - the minimum distance, 0 disables the cutoff.
+fn detect_failed(failed: bool, min_distance: f32) -> Option<String> {
+    if min_distance > 0.0 && failed {
+        Some("a range sensor is not responding".to_owned())
+    } else {
+        None
+    }
+}
+
+/// Shared latest range readings, they are updated by the thread started with
+/// `start_monitoring()`.
+#[derive(Clone)]
+pub struct RangeWatch {
+    ranges: Arc<Mutex<Option<Ranges>>>,
+    min_distance: f32,
+}
+
+impl RangeWatch {
+    /// Return the latest readings, `None` is returned if the range sensors
+    /// have not been read yet.
+    pub fn get(&self) -> Option<Ranges> {
+        self.ranges.lock().unwrap().clone()
+    }
+
+    /// Distance in meters below which forward motion is cut off, 0 if the
+    /// cutoff is disabled.
+    pub fn min_distance(&self) -> f32 {
+        self.min_distance
+    }
+}
+
+/// Start a new thread periodically reading range sensors from Arduino and
+/// cutting off forward motion when there is an obstacle.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino, it is used to stop
+///   forward motion.
+///
+/// * `min_distance` - Forward motion is cut off when an obstacle is closer
+///   than this distance in meters, 0 disables the cutoff.
+///
+/// * `safety` - Safety supervisor which cuts off forward motor power.
+///
+/// * `estop` - Emergency stop, forward motion is not stopped while it is
+///   engaged.
+///
+/// * `ramp` - Motor ramp, forward motion is stopped without ramping.
+///
+/// * `calibration` - Motor calibration applied to the stop command.
+pub fn start_monitoring(
+    arduino: Handle,
+    min_distance: f32,
+    safety: SafetyWatch,
+    estop: Estop,
+    ramp: Ramp,
+    calibration: CalibrationStore,
+) -> RangeWatch {
+    info!(
+        "Starting range monitoring with {:.2} m minimum distance...",
+        min_distance
+    );
+
+    let watch = RangeWatch {
+        ranges: Arc::new(Mutex::new(None)),
+        min_distance,
+    };
+    let shared = watch.clone();
+
+    thread::spawn(move || {
+        let mut last_reading = Instant::now();
+
+        loop {
+            thread::sleep(SAMPLE_PERIOD);
+
+            let ranges = if arduino.state() == State::Connected {
+                match arduino.execute(&ReadRanges) {
+                    Ok(ranges) => Some(Ranges {
+                        distances: ranges
+                            .iter()
+                            .map(|range| match range {
+                                Range::Distance(millimeters) => {
+                                    Some(f32::from(*millimeters) / 1000.0)
+                                }
+                                Range::NoEcho | Range::Failed => None,
+                            })
+                            .collect(),
+                        failed: ranges.contains(&Range::Failed),
+                        time: SystemTime::now(),
+                        updated: Instant::now(),
+                    }),
+                    Err(error) => {
+                        debug!("Range sensors couldn't be read: {}", error);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+
+            let was_obstacle = safety.obstacle().is_some();
+            let obstacle = match ranges {
+                Some(ranges) => {
+                    last_reading = ranges.updated;
+                    let obstacle = detect_failed(ranges.failed, min_distance)
+                        .or_else(|| detect(ranges.closest(), min_distance, was_obstacle));
+                    *shared.ranges.lock().unwrap() = Some(ranges);
+                    obstacle
+                }
+                // The previous state is kept until the readings get stale.
+                None => match detect_stale(last_reading.elapsed(), min_distance) {
+                    Some(reason) => Some(reason),
+                    None => continue,
+                },
+            };
+
+            match obstacle {
+                Some(ref reason) if !was_obstacle => {
+                    warn!("Cutting off forward motion: {}.", reason)
+                }
+                None if was_obstacle => info!("Obstacle cleared, forward motion is allowed."),
+                _ => (),
+            }
+            // The obstacle is set with the emergency stop locked so that a
+            // motor command limited before it is never sent after the cutoff.
+            supervisor::set_motors(&arduino, &estop, &safety, &ramp, &calibration, || {
+                safety.set_obstacle(obstacle)
+            });
+        }
+    });
+
+    watch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_closest() {
+        let ranges = |distances| Ranges {
+            distances,
+            failed: false,
+            time: SystemTime::now(),
+            updated: Instant::now(),
+        };
+        assert_eq!(ranges(vec![]).closest(), None);
+        assert_eq!(ranges(vec![None, None]).closest(), None);
+        assert_eq!(
+            ranges(vec![Some(1.5), None, Some(0.3)]).closest(),
+            Some(0.3)
+        );
+
+        let old = Ranges {
+            distances: vec![],
+            failed: false,
+            time: SystemTime::now(),
+            updated: Instant::now() - MAX_AGE * 2,
+        };
+        assert!(old.is_stale());
+        assert!(!ranges(vec![]).is_stale());
+    }
+
+    #[test]
+    fn test_detect() {
+        assert!(detect(None, 0.2, false).is_none());
+        assert!(detect(None, 0.2, true).is_none());
+        assert!(detect(Some(0.5), 0.2, false).is_none());
+        assert!(detect(Some(0.15), 0.2, false)
+            .unwrap()
+            .contains("obstacle 0.15 m"));
+
+        // The obstacle is cleared with hysteresis.
+        assert!(detect(Some(0.22), 0.2, false).is_none());
+        assert!(detect(Some(0.22), 0.2, true).is_some());
+        assert!(detect(Some(0.26), 0.2, true).is_none());
+
+        // Zero minimum distance disables the cutoff.
+        assert!(detect(Some(0.0), 0.0, false).is_none());
+    }
+
+    #[test]
+    fn test_detect_stale() {
+        assert!(detect_stale(Duration::from_millis(100), 0.2).is_none());
+        assert!(detect_stale(MAX_AGE, 0.2).is_none());
+        assert!(detect_stale(Duration::from_millis(1500), 0.2)
+            .unwrap()
+            .contains("not been read for 1.5 s"));
+
+        // Zero minimum distance disables the cutoff.
+        assert!(detect_stale(Duration::from_secs(10), 0.0).is_none());
+    }
+
+    #[test]
+    fn test_detect_failed() {
+        assert!(detect_failed(false, 0.2).is_none());
+        assert!(detect_failed(true, 0.2).unwrap().contains("not responding"));
+
+        // Zero minimum distance disables the cutoff.
+        assert!(detect_failed(true, 0.0).is_none());
+    }
+}
@@ -0,0 +1,83 @@
+//! Decoding and replaying of serial communication captures, see
+//! `crate::arduino::capture`.
+
+use crate::arduino::binary::{self, Connection, Message};
+use crate::arduino::capture::{self, Decoder, Event};
+use crate::arduino::frame::{self, ResponseFrame};
+use log::info;
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+/// Print commands and responses from a capture file and optionally send the
+/// captured commands again.
+///
+/// # Arguments
+///
+/// * `path` - path of the capture file.
+///
+/// * `device` - Arduino device the captured commands are sent to, see
+///   `Connection::init_from_device()`. The commands are sent with the same
+///   timing as they were captured, each response is awaited before the next
+///   command is sent. Protocol negotiation is not replayed.
+pub fn replay(path: &Path, device: Option<&str>) {
+    let records = match capture::read(path) {
+        Ok(records) => records,
+        Err(error) => panic!("Error while reading {}: {}", path.display(), error),
+    };
+
+    let mut decoder = Decoder::new();
+    let events: Vec<Event> = records
+        .iter()
+        .flat_map(|record| decoder.push(record))
+        .collect();
+    for event in &events {
+        println!("{}", event);
+    }
+
+    if let Some(device) = device {
+        send_commands(&events, device);
+    }
+}
+
+fn send_commands(events: &[Event], device: &str) {
+    let arduino = match Connection::init_from_device(device) {
+        Ok(arduino) => arduino,
+        Err(error) => panic!("Error while connecting to Arduino: {}", error),
+    };
+
+    info!("Replaying captured commands to {}...", device);
+    let start = Instant::now();
+
+    for event in events {
+        let (time, command) = match event {
+            Event::Command(time, command) if command.command != frame::NEGOTIATE_COMMAND => {
+                (*time, command)
+            }
+            _ => continue,
+        };
+
+        let elapsed = start.elapsed();
+        if time > elapsed {
+            thread::sleep(time - elapsed);
+        }
+
+        let (message, receiver) = Message::new(command.command, command.payload.clone());
+        if arduino.send_message(message).is_err() {
+            panic!("Connection to Arduino closed.");
+        }
+        println!("{}", Event::Command(start.elapsed(), command.clone()));
+
+        match binary::receive(&receiver) {
+            Ok(payload) => {
+                let response = ResponseFrame {
+                    seq: None,
+                    consumed: None,
+                    payload,
+                };
+                println!("{}", Event::Response(start.elapsed(), response));
+            }
+            Err(error) => println!("{}", Event::Error(start.elapsed(), error.to_string())),
+        }
+    }
+}
@@ -0,0 +1,704 @@
+//! Safety supervisor protecting the battery and the onboard computer.
+//!
+//! The supervisor watches battery readings (see `crate::battery`) and
+//! escalates through safety levels: motor power is limited first, then the
+//! motors are stopped and finally the onboard computer is cleanly shut down
+//! before the BMS cuts the power off. Low voltage and over-current have to
+//! persist for a few readings before the level is raised so that short
+//! voltage drops and inrush currents are tolerated.
+
+use crate::arduino::binary::{Handle, Pending};
+use crate::arduino::cmd::sensor::Battery;
+use crate::battery::{self, BatteryWatch};
+use crate::calibration::CalibrationStore;
+use crate::estop::Estop;
+use crate::ramp::Ramp;
+use log::{error, info, warn};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive readings calling for a higher level before the
+/// level is raised.
+const ESCALATE_READINGS: u32 = 3;
+/// Number of consecutive readings calling for a lower level before the level
+/// is lowered.
+const RECOVER_READINGS: u32 = 30;
+/// A threshold which has been crossed is cleared only once the resting
+/// voltage is this much (in volts) above it.
+const VOLTAGE_HYSTERESIS: f32 = 0.5;
+/// A current threshold which has been crossed is cleared only once the
+/// current is this much (in amps) below it.
+const CURRENT_HYSTERESIS: f32 = 0.5;
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+/// Command cleanly shutting down the onboard computer. The server runs as an
+/// unprivileged user so a sudoers rule permitting the command is needed.
+const SHUTDOWN_COMMAND: [&str; 5] = ["sudo", "--non-interactive", "/sbin/shutdown", "-h", "now"];
+
+/// Safety level, levels are ordered from the least to the most restrictive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// No restrictions.
+    Normal,
+    /// Motor power ratio magnitude is limited.
+    Limited,
+    /// Motors are stopped and only stop commands are accepted.
+    Stopped,
+    /// The onboard computer is being shut down.
+    Shutdown,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Level::Normal => "normal",
+            Level::Limited => "limited",
+            Level::Stopped => "stopped",
+            Level::Shutdown => "shutdown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Battery thresholds at which safety levels are raised. Voltages are
+/// compared to the resting voltage, see `crate::battery::resting_voltage()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Motor power is limited below this voltage.
+    pub limit_voltage: f32,
+    /// Motors are stopped below this voltage.
+    pub stop_voltage: f32,
+    /// The onboard computer is shut down below this voltage.
+    pub shutdown_voltage: f32,
+    /// Motor power is limited above this current.
+    pub limit_current: f32,
+    /// Motors are stopped above this current.
+    pub stop_current: f32,
+    /// Maximum motor power ratio magnitude at `Level::Limited`.
+    pub power_limit: f32,
+}
+
+impl Thresholds {
+    /// Return the level called for by a battery reading and the reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `battery` - battery reading.
+    ///
+    /// * `level` - current level, thresholds of this and lower levels are
+    ///   cleared only with hysteresis.
+    fn evaluate(&self, battery: Battery, level: Level) -> (Level, Option<String>) {
+        let voltage = battery::resting_voltage(battery);
+        let below = |threshold_level: Level, threshold: f32| {
+            let margin = if threshold_level <= level {
+                VOLTAGE_HYSTERESIS
+            } else {
+                0.0
+            };
+            if voltage < threshold + margin {
+                Some(format!(
+                    "battery voltage {:.2} V is below {:.2} V",
+                    voltage, threshold
+                ))
+            } else {
+                None
+            }
+        };
+        let above = |threshold_level: Level, threshold: f32| {
+            let margin = if threshold_level <= level {
+                CURRENT_HYSTERESIS
+            } else {
+                0.0
+            };
+            if battery.current > threshold - margin {
+                Some(format!(
+                    "current {:.2} A is above {:.2} A",
+                    battery.current, threshold
+                ))
+            } else {
+                None
+            }
+        };
+
+        let checks = [
+            (
+                Level::Shutdown,
+                below(Level::Shutdown, self.shutdown_voltage),
+            ),
+            (Level::Stopped, below(Level::Stopped, self.stop_voltage)),
+            (Level::Stopped, above(Level::Stopped, self.stop_current)),
+            (Level::Limited, below(Level::Limited, self.limit_voltage)),
+            (Level::Limited, above(Level::Limited, self.limit_current)),
+        ];
+        for (threshold_level, reason) in checks.iter() {
+            if reason.is_some() {
+                return (*threshold_level, reason.clone());
+            }
+        }
+        (Level::Normal, None)
+    }
+}
+
+impl Default for Thresholds {
+    /// Thresholds for the 6 cell battery pack, see
+    /// [hardware documentation](https://irro.cz/hw.html).
+    fn default() -> Self {
+        Thresholds {
+            limit_voltage: 19.8,
+            stop_voltage: 18.6,
+            shutdown_voltage: 17.4,
+            limit_current: 6.0,
+            stop_current: 8.0,
+            power_limit: 0.5,
+        }
+    }
+}
+
+/// Parse thresholds from comma separated `name=value` pairs, for example
+/// `stop_voltage=18.9,power_limit=0.4`. Thresholds which are not given keep
+/// their default values.
+impl FromStr for Thresholds {
+    type Err = io::Error;
+
+    fn from_str(thresholds: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| io::Error::new(ErrorKind::InvalidInput, reason);
+
+        let mut parsed = Thresholds::default();
+        for pair in thresholds.split(',') {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let value: f32 = match parts.next().map(|value| value.trim().parse()) {
+                Some(Ok(value)) if f32::is_finite(value) && value >= 0.0 => value,
+                _ => return Err(invalid(format!("Invalid threshold \"{}\".", pair))),
+            };
+
+            let field = match name {
+                "limit_voltage" => &mut parsed.limit_voltage,
+                "stop_voltage" => &mut parsed.stop_voltage,
+                "shutdown_voltage" => &mut parsed.shutdown_voltage,
+                "limit_current" => &mut parsed.limit_current,
+                "stop_current" => &mut parsed.stop_current,
+                "power_limit" => &mut parsed.power_limit,
+                _ => return Err(invalid(format!("Unknown threshold \"{}\".", name))),
+            };
+            *field = value;
+        }
+
+        if parsed.power_limit > 1.0 {
+            return Err(invalid("Power limit must be between 0 and 1.".to_owned()));
+        }
+        // Misordered thresholds would skip levels, e.g. shut the computer
+        // down before the motors are stopped.
+        if !(parsed.limit_voltage > parsed.stop_voltage
+            && parsed.stop_voltage > parsed.shutdown_voltage)
+        {
+            return Err(invalid(format!(
+                "Voltage thresholds must decrease from limit to stop to shutdown, got \
+                 {:.2} V, {:.2} V and {:.2} V.",
+                parsed.limit_voltage, parsed.stop_voltage, parsed.shutdown_voltage
+            )));
+        }
+        if parsed.limit_current >= parsed.stop_current {
+            return Err(invalid(format!(
+                "Limit current {:.2} A must be below stop current {:.2} A.",
+                parsed.limit_current, parsed.stop_current
+            )));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Safety level state machine.
+pub struct Supervisor {
+    thresholds: Thresholds,
+    level: Level,
+    reason: Option<String>,
+    /// Number of consecutive readings calling for a change of the level, the
+    /// level the change is made to and the reason.
+    pending: Option<(u32, Level, Option<String>)>,
+}
+
+impl Supervisor {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Supervisor {
+            thresholds,
+            level: Level::Normal,
+            reason: None,
+            pending: None,
+        }
+    }
+
+    /// Return the current level.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Return the reason of the current level, it is `None` at
+    /// `Level::Normal`.
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_ref().map(String::as_str)
+    }
+
+    /// Process a battery reading and return the new level if it changed.
+    /// `Level::Shutdown` is never left.
+    pub fn update(&mut self, battery: Battery) -> Option<Level> {
+        if self.level == Level::Shutdown {
+            return None;
+        }
+
+        let (target, reason) = self.thresholds.evaluate(battery, self.level);
+        if target == self.level {
+            self.pending = None;
+            return None;
+        }
+
+        let escalating = target > self.level;
+        let (count, pending_target, pending_reason) = match self.pending.take() {
+            // Consecutive readings may call for different levels, the
+            // change is made to the least different one.
+            Some((count, pending_target, pending_reason))
+                if (pending_target > self.level) == escalating =>
+            {
+                if (target < pending_target) == escalating {
+                    (count + 1, target, reason)
+                } else {
+                    (count + 1, pending_target, pending_reason)
+                }
+            }
+            _ => (1, target, reason),
+        };
+
+        let required = if escalating {
+            ESCALATE_READINGS
+        } else {
+            RECOVER_READINGS
+        };
+        if count < required {
+            self.pending = Some((count, pending_target, pending_reason));
+            return None;
+        }
+
+        self.level = pending_target;
+        self.reason = pending_reason;
+        Some(self.level)
+    }
+}
+
+struct Status {
+    level: Level,
+    reason: Option<String>,
+    /// Last motor power ratio sent via `SafetyWatch::limit_motors()`.
+    motors: (f32, f32),
+    /// Obstacle in front of the robot, see `crate::range`.
+    obstacle: Option<String>,
+}
+
+/// Shared safety level, it is updated by the thread started with
+/// `start_supervising()`.
+#[derive(Clone)]
+pub struct SafetyWatch {
+    status: Arc<Mutex<Status>>,
+    power_limit: f32,
+}
+
+impl SafetyWatch {
+    /// Create a watch at `Level::Normal`, it is updated once it is passed to
+    /// `start_supervising()`.
+    ///
+    /// # Arguments
+    ///
+    /// * `power_limit` - Maximum motor power ratio magnitude at
+    ///   `Level::Limited`, see `Thresholds::power_limit`.
+    pub fn new(power_limit: f32) -> Self {
+        SafetyWatch {
+            status: Arc::new(Mutex::new(Status {
+                level: Level::Normal,
+                reason: None,
+                motors: (0.0, 0.0),
+                obstacle: None,
+            })),
+            power_limit,
+        }
+    }
+
+    /// Return the current level and its reason.
+    pub fn get(&self) -> (Level, Option<String>) {
+        let status = self.status.lock().unwrap();
+        (status.level, status.reason.clone())
+    }
+
+    /// Return the maximum magnitude of power ratios sent to Arduino at the
+    /// current level, after motor calibration (see
+    /// `crate::calibration::Calibration::command()`).
+    pub fn power_cap(&self) -> f32 {
+        match self.status.lock().unwrap().level {
+            Level::Normal => 1.0,
+            Level::Limited => self.power_limit,
+            Level::Stopped | Level::Shutdown => 0.0,
+        }
+    }
+
+    /// Return the obstacle in front of the robot if there is any.
+    pub fn obstacle(&self) -> Option<String> {
+        self.status.lock().unwrap().obstacle.clone()
+    }
+
+    /// Limit a motor power ratio according to the current level. Both ratios
+    /// are scaled down by the same factor so that the direction of the robot
+    /// is kept. Forward power is cut off while there is an obstacle in front
+    /// of the robot, reversing and turning away are allowed.
+    ///
+    /// # Errors
+    ///
+    /// An error with the reason is returned if motors may not be powered at
+    /// the current level. Stopping the motors is always allowed.
+    pub fn limit_motors(&self, left: f32, right: f32) -> Result<(f32, f32), String> {
+        let mut status = self.status.lock().unwrap();
+
+        let limited = match status.level {
+            Level::Normal => (left, right),
+            Level::Limited => limit_ratio(left, right, self.power_limit),
+            Level::Stopped | Level::Shutdown if left == 0.0 && right == 0.0 => (0.0, 0.0),
+            Level::Stopped | Level::Shutdown => {
+                return Err(format!(
+                    "Motors are stopped by the safety supervisor: {}.",
+                    status
+                        .reason
+                        .as_ref()
+                        .map_or("unknown reason", String::as_str)
+                ));
+            }
+        };
+
+        let limited = if status.obstacle.is_some() {
+            cut_forward(limited)
+        } else {
+            limited
+        };
+        status.motors = limited;
+        Ok(limited)
+    }
+
+    /// Set or clear the obstacle in front of the robot. Return the motor
+    /// power ratio to be sent if the motors have to stop moving forward.
+    pub(crate) fn set_obstacle(&self, obstacle: Option<String>) -> Option<(f32, f32)> {
+        let mut status = self.status.lock().unwrap();
+        status.obstacle = obstacle;
+        if status.obstacle.is_none() {
+            return None;
+        }
+
+        let cut = cut_forward(status.motors);
+        if cut == status.motors {
+            None
+        } else {
+            status.motors = cut;
+            Some(cut)
+        }
+    }
+
+    fn set(&self, level: Level, reason: Option<String>) {
+        let mut status = self.status.lock().unwrap();
+        status.level = level;
+        status.reason = reason;
+    }
+
+    fn motors(&self) -> (f32, f32) {
+        self.status.lock().unwrap().motors
+    }
+}
+
+/// Scale both ratios so that neither of them exceeds the limit in magnitude.
+fn limit_ratio(left: f32, right: f32, limit: f32) -> (f32, f32) {
+    let max = left.abs().max(right.abs());
+    if max <= limit {
+        (left, right)
+    } else {
+        (left * limit / max, right * limit / max)
+    }
+}
+
+/// Replace forward power ratios with zero.
+fn cut_forward((left, right): (f32, f32)) -> (f32, f32) {
+    (left.min(0.0), right.min(0.0))
+}
+
+/// Start a new thread supervising battery readings and updating the shared
+/// safety level.
+///
+/// # Arguments
+///
+/// * `arduino` - Handle of the connection to Arduino, it is used to limit
+///   and stop the motors.
+///
+/// * `battery` - Battery estimate updated with new readings.
+///
+/// * `safety` - Safety level to be updated, its power limit is used at
+///   `Level::Limited`.
+///
+/// * `thresholds` - Thresholds of the safety levels.
+///
+/// * `estop` - Emergency stop, motors are not limited nor stopped while it
+///   is engaged.
+///
+/// * `ramp` - Motor ramp, motors are limited and stopped without ramping.
+///
+/// * `calibration` - Motor calibration applied to limited ratios.
+pub fn start_supervising(
+    arduino: Handle,
+    battery: BatteryWatch,
+    safety: SafetyWatch,
+    thresholds: Thresholds,
+    estop: Estop,
+    ramp: Ramp,
+    calibration: CalibrationStore,
+) {
+    info!("Starting safety supervisor with {:?}...", thresholds);
+
+    thread::spawn(move || {
+        let mut supervisor = Supervisor::new(thresholds);
+        let mut last_reading: Option<Instant> = None;
+
+        loop {
+            thread::sleep(CHECK_PERIOD);
+
+            let previous = supervisor.level();
+            let changed = match battery.get() {
+                Some(estimate) if Some(estimate.time) != last_reading => {
+                    last_reading = Some(estimate.time);
+                    supervisor.update(estimate.battery).is_some()
+                }
+                _ => false,
+            };
+            let level = supervisor.level();
+            let reason = supervisor.reason().unwrap_or("battery recovered");
+
+            if changed {
+                match level {
+                    _ if level < previous => {
+                        info!("Safety level lowered to {}: {}.", level, reason)
+                    }
+                    Level::Normal => (),
+                    Level::Limited => warn!(
+                        "Limiting motor power to {:.0}%: {}.",
+                        safety.power_limit * 100.0,
+                        reason
+                    ),
+                    Level::Stopped => error!("Stopping motors: {}.", reason),
+                    Level::Shutdown => error!("Shutting down: {}.", reason),
+                }
+            }
+
+            // The level is set and the motors are limited with the emergency
+            // stop locked so that a motor command limited at the previous
+            // level is never sent afterwards. The stop is sent on every check
+            // so that nothing keeps the motors running at the stopped levels.
+            set_motors(&arduino, &estop, &safety, &ramp, &calibration, || {
+                if changed {
+                    safety.set(level, supervisor.reason().map(str::to_owned));
+                }
+                match level {
+                    Level::Normal => None,
+                    Level::Limited => {
+                        let (left, right) = safety.motors();
+                        let limited = safety.limit_motors(left, right).ok()?;
+                        if limited == (left, right) {
+                            None
+                        } else {
+                            Some(limited)
+                        }
+                    }
+                    Level::Stopped | Level::Shutdown => safety.limit_motors(0.0, 0.0).ok(),
+                }
+            });
+
+            if changed && level == Level::Shutdown {
+                shutdown();
+            }
+        }
+    });
+}
+
+/// Update the safety state with `update` and send the motor power ratio it
+/// returns immediately, bypassing the ramp. Both are done with the emergency
+/// stop locked so that no other motor command is dispatched in between.
+/// Nothing is sent while the emergency stop is engaged, it keeps the motors
+/// stopped anyway.
+pub(crate) fn set_motors<F>(
+    arduino: &Handle,
+    estop: &Estop,
+    safety: &SafetyWatch,
+    ramp: &Ramp,
+    calibration: &CalibrationStore,
+    update: F,
+) where
+    F: FnOnce() -> Option<(f32, f32)>,
+{
+    let pending = estop.dispatch(|engaged| {
+        let (left, right) = update()?;
+        if engaged {
+            return None;
+        }
+        ramp.reset(left, right);
+        let command = calibration.command(left, right, safety.power_cap());
+        Some(arduino.send(&command))
+    });
+
+    // The response is waited for with the emergency stop unlocked.
+    if let Some(Err(error)) = pending.map(|pending| pending.and_then(Pending::wait)) {
+        error!("Motor power ratio couldn't be set: {}", error);
+    }
+}
+
+fn shutdown() {
+    let result = Command::new(SHUTDOWN_COMMAND[0])
+        .args(&SHUTDOWN_COMMAND[1..])
+        .status();
+    match result {
+        Ok(status) if status.success() => info!("Shutdown initiated."),
+        Ok(status) => error!("Shutdown command failed: {}", status),
+        Err(error) => error!("Shutdown command couldn't be run: {}", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery(voltage: f32, current: f32) -> Battery {
+        Battery { voltage, current }
+    }
+
+    fn feed(supervisor: &mut Supervisor, reading: Battery, count: u32) -> Vec<Level> {
+        (0..count)
+            .filter_map(|_| supervisor.update(reading))
+            .collect()
+    }
+
+    #[test]
+    fn test_escalation() {
+        let mut supervisor = Supervisor::new(Thresholds::default());
+        assert_eq!(feed(&mut supervisor, battery(22.0, 1.0), 10), vec![]);
+
+        // A short voltage drop is tolerated.
+        assert_eq!(feed(&mut supervisor, battery(19.0, 0.0), 2), vec![]);
+        assert_eq!(feed(&mut supervisor, battery(22.0, 0.0), 1), vec![]);
+
+        assert_eq!(
+            feed(&mut supervisor, battery(19.0, 0.0), 3),
+            vec![Level::Limited]
+        );
+        assert!(supervisor.reason().unwrap().contains("19.00 V"));
+        assert_eq!(
+            feed(&mut supervisor, battery(18.0, 0.0), 3),
+            vec![Level::Stopped]
+        );
+        assert_eq!(
+            feed(&mut supervisor, battery(17.0, 0.0), 3),
+            vec![Level::Shutdown]
+        );
+        assert_eq!(feed(&mut supervisor, battery(25.0, 0.0), 100), vec![]);
+    }
+
+    #[test]
+    fn test_recovery() {
+        let mut supervisor = Supervisor::new(Thresholds::default());
+        assert_eq!(
+            feed(&mut supervisor, battery(20.0, 9.0), 3),
+            vec![Level::Stopped]
+        );
+        assert!(supervisor.reason().unwrap().contains("9.00 A"));
+
+        // Current between the limits keeps motor power limited and resting
+        // voltage 20.0 + 7.0 * 0.3 is above the hysteresis.
+        assert_eq!(
+            feed(&mut supervisor, battery(20.0, 7.0), 30),
+            vec![Level::Limited]
+        );
+        // Within the hysteresis.
+        assert_eq!(feed(&mut supervisor, battery(20.0, 5.8), 100), vec![]);
+        assert_eq!(
+            feed(&mut supervisor, battery(21.0, 1.0), 30),
+            vec![Level::Normal]
+        );
+        assert_eq!(supervisor.reason(), None);
+    }
+
+    #[test]
+    fn test_parse_thresholds() {
+        let thresholds: Thresholds = "stop_voltage=18.9, power_limit=0.4".parse().unwrap();
+        assert_eq!(thresholds.stop_voltage, 18.9);
+        assert_eq!(thresholds.power_limit, 0.4);
+        assert_eq!(
+            thresholds.limit_voltage,
+            Thresholds::default().limit_voltage
+        );
+
+        assert!("stop_voltage".parse::<Thresholds>().is_err());
+        assert!("stop_voltage=x".parse::<Thresholds>().is_err());
+        assert!("speed=1".parse::<Thresholds>().is_err());
+        assert!("power_limit=1.5".parse::<Thresholds>().is_err());
+    }
+
+    #[test]
+    fn test_parse_misordered_thresholds() {
+        let error = "stop_voltage=20".parse::<Thresholds>().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!("shutdown_voltage=18.6".parse::<Thresholds>().is_err());
+        assert!("limit_voltage=17,stop_voltage=18,shutdown_voltage=19"
+            .parse::<Thresholds>()
+            .is_err());
+        assert!("limit_current=8".parse::<Thresholds>().is_err());
+        assert!("stop_voltage=17,shutdown_voltage=16"
+            .parse::<Thresholds>()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_limit_motors() {
+        let watch = SafetyWatch::new(0.5);
+        assert_eq!(watch.limit_motors(1.0, -0.5), Ok((1.0, -0.5)));
+        assert_eq!(watch.power_cap(), 1.0);
+
+        watch.set(Level::Limited, Some("low battery".to_owned()));
+        assert_eq!(watch.limit_motors(1.0, -0.5), Ok((0.5, -0.25)));
+        assert_eq!(watch.limit_motors(0.2, 0.3), Ok((0.2, 0.3)));
+        assert_eq!(watch.motors(), (0.2, 0.3));
+
+        assert_eq!(watch.power_cap(), 0.5);
+
+        watch.set(Level::Stopped, Some("low battery".to_owned()));
+        assert_eq!(watch.power_cap(), 0.0);
+        assert!(watch
+            .limit_motors(0.2, 0.3)
+            .unwrap_err()
+            .contains("low battery"));
+        assert_eq!(watch.limit_motors(0.0, 0.0), Ok((0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_obstacle() {
+        let watch = SafetyWatch::new(0.5);
+        assert_eq!(watch.limit_motors(0.6, 0.4), Ok((0.6, 0.4)));
+        let obstacle = Some("obstacle 0.12 m ahead".to_owned());
+        assert_eq!(watch.set_obstacle(obstacle.clone()), Some((0.0, 0.0)));
+        assert_eq!(watch.set_obstacle(obstacle.clone()), None);
+        assert_eq!(watch.obstacle(), obstacle);
+
+        // Reversing and turning away are allowed.
+        assert_eq!(watch.limit_motors(0.5, 0.5), Ok((0.0, 0.0)));
+        assert_eq!(watch.limit_motors(-0.5, -0.3), Ok((-0.5, -0.3)));
+        assert_eq!(watch.limit_motors(0.5, -0.5), Ok((0.0, -0.5)));
+
+        watch.set(Level::Limited, Some("low battery".to_owned()));
+        assert_eq!(watch.limit_motors(1.0, -1.0), Ok((0.0, -0.5)));
+
+        assert_eq!(watch.set_obstacle(None), None);
+        assert_eq!(watch.obstacle(), None);
+        assert_eq!(watch.limit_motors(0.4, 0.4), Ok((0.4, 0.4)));
+    }
+}
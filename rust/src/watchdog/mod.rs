@@ -0,0 +1,201 @@
+//! Motor command watchdog (dead-man switch).
+//!
+//! Every motor command which doesn't stop the motors is valid only for a
+//! limited time. The motors are stopped by the server itself unless a fresh
+//! command arrives in time, e.g. when the client crashed or the network
+//! connection was lost.
+
+use log::{info, warn};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longest validity a motor command may have.
+pub const MAX_VALIDITY: Duration = Duration::from_secs(60);
+
+/// State of the watchdog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    /// Validity of motor commands which don't specify it.
+    pub default_validity: Duration,
+    /// Time remaining until the motors are stopped, `None` if the motors are
+    /// not expected to run.
+    pub remaining: Option<Duration>,
+    /// Number of times the motors were stopped by the watchdog.
+    pub expired: u64,
+    /// Time the motors were last stopped by the watchdog.
+    pub last_expired: Option<SystemTime>,
+}
+
+struct State {
+    deadline: Option<Instant>,
+    expired: u64,
+    last_expired: Option<SystemTime>,
+}
+
+/// Handle of the watchdog started with `start()`.
+#[derive(Clone)]
+pub struct MotorWatchdog {
+    state: Arc<(Mutex<State>, Condvar)>,
+    default_validity: Duration,
+}
+
+impl MotorWatchdog {
+    /// Register a motor command which is about to be sent. This blocks while
+    /// the watchdog is stopping the motors.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_stop` - true if the command stops the motors, the watchdog is
+    ///   disarmed in such a case.
+    ///
+    /// * `validity` - how long the command is valid, the default validity is
+    ///   used if `None`.
+    pub fn feed(&self, is_stop: bool, validity: Option<Duration>) {
+        let (ref lock, ref condvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+        state.deadline = if is_stop {
+            None
+        } else {
+            Some(Instant::now() + validity.unwrap_or(self.default_validity))
+        };
+        condvar.notify_one();
+    }
+
+    /// Return current state of the watchdog.
+    pub fn status(&self) -> Status {
+        let state = self.state.0.lock().unwrap();
+        let now = Instant::now();
+        Status {
+            default_validity: self.default_validity,
+            remaining: state.deadline.map(|deadline| {
+                if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            }),
+            expired: state.expired,
+            last_expired: state.last_expired,
+        }
+    }
+}
+
+/// Start a new thread stopping the motors when a motor command expires.
+///
+/// # Arguments
+///
+/// * `default_validity` - validity of motor commands which don't specify it.
+///
+/// * `stop` - called when the motors should be stopped.
+pub fn start<F>(default_validity: Duration, stop: F) -> MotorWatchdog
+where
+    F: Fn() + Send + 'static,
+{
+    info!(
+        "Starting motor watchdog with {} ms default validity...",
+        default_validity.as_millis()
+    );
+
+    let watchdog = MotorWatchdog {
+        state: Arc::new((
+            Mutex::new(State {
+                deadline: None,
+                expired: 0,
+                last_expired: None,
+            }),
+            Condvar::new(),
+        )),
+        default_validity,
+    };
+    let state = watchdog.state.clone();
+
+    thread::spawn(move || {
+        let (ref lock, ref condvar) = *state;
+        let mut state = lock.lock().unwrap();
+
+        loop {
+            let deadline = match state.deadline {
+                Some(deadline) => deadline,
+                None => {
+                    state = condvar.wait(state).unwrap();
+                    continue;
+                }
+            };
+
+            let now = Instant::now();
+            if now < deadline {
+                state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+                continue;
+            }
+
+            state.deadline = None;
+            state.expired += 1;
+            state.last_expired = Some(SystemTime::now());
+            warn!("No fresh motor command arrived in time, stopping motors.");
+            // The lock is held so that a command fed in the meantime is sent
+            // only after the stop and it is not overridden by it.
+            stop();
+        }
+    });
+
+    watchdog
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_watchdog() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let watchdog = start(Duration::from_millis(50), move || {
+            sender.lock().unwrap().send(()).unwrap()
+        });
+        assert_eq!(watchdog.status().remaining, None);
+
+        watchdog.feed(false, None);
+        assert!(watchdog.status().remaining.unwrap() <= Duration::from_millis(50));
+        receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        let status = watchdog.status();
+        assert_eq!(status.remaining, None);
+        assert_eq!(status.expired, 1);
+        assert!(status.last_expired.is_some());
+
+        // Fresh commands keep the motors running.
+        watchdog.feed(false, Some(Duration::from_millis(200)));
+        for _ in 0..5 {
+            thread::sleep(Duration::from_millis(100));
+            watchdog.feed(false, Some(Duration::from_millis(200)));
+        }
+        assert!(receiver.try_recv().is_err());
+
+        // Stop commands disarm the watchdog.
+        watchdog.feed(true, None);
+        assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err());
+        assert_eq!(watchdog.status().expired, 1);
+    }
+
+    #[test]
+    fn test_feed_during_stop() {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let watchdog = start(Duration::from_millis(20), move || {
+            thread::sleep(Duration::from_millis(100));
+            sender.lock().unwrap().send(Instant::now()).unwrap()
+        });
+
+        watchdog.feed(false, None);
+        thread::sleep(Duration::from_millis(50));
+        // The motors are being stopped, the fresh command is registered (and
+        // sent) only afterwards so it stays valid.
+        watchdog.feed(false, Some(Duration::from_secs(1)));
+        let fed = Instant::now();
+        let stopped = receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(stopped <= fed);
+        assert!(watchdog.status().remaining.is_some());
+        assert_eq!(watchdog.status().expired, 1);
+    }
+}